@@ -2,61 +2,220 @@
 use goscript_parser::ast::*;
 use goscript_parser::token::Token;
 use goscript_vm::instruction::*;
+use goscript_vm::objects::FunctionKey;
 use goscript_vm::value::*;
+use std::collections::HashMap;
+
+/// the kind of the statement that break and continue apply to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockKind {
+    For,
+    // the container and the cursor of a range loop are on the stack
+    Range,
+    // switch and select
+    Other,
+}
 
 /// branch points of break and continue
 pub struct BranchPoints {
-    data: Vec<(usize, Token)>,
+    label: Option<String>,
+    kind: BlockKind,
+    data: Vec<(usize, Token, Option<String>)>,
 }
 
 impl BranchPoints {
-    pub fn new() -> BranchPoints {
-        BranchPoints { data: vec![] }
+    pub fn new(label: Option<String>, kind: BlockKind) -> BranchPoints {
+        BranchPoints {
+            label: label,
+            kind: kind,
+            data: vec![],
+        }
     }
 }
 
 /// helper for break & continue
 pub struct BreakContinue {
     points_vec: Vec<BranchPoints>,
+    next_label: Option<String>,
 }
 
 impl BreakContinue {
     pub fn new() -> BreakContinue {
-        BreakContinue { points_vec: vec![] }
+        BreakContinue {
+            points_vec: vec![],
+            next_label: None,
+        }
     }
 
-    pub fn add_point(&mut self, func: &mut FunctionVal, token: Token, pos: usize) {
+    pub fn add_point(
+        &mut self,
+        func: &mut FunctionVal,
+        token: Token,
+        label: Option<String>,
+        pos: usize,
+    ) {
+        let slots = self.range_slots(&token, &label);
+        if slots > 0 {
+            func.emit_code_with_flag_imm(Opcode::POP, true, slots, Some(pos));
+        }
         let index = func.code().len();
         func.emit_code_with_imm(Opcode::JUMP, 0, Some(pos));
         self.points_vec
             .last_mut()
             .unwrap()
             .data
-            .push((index, token));
+            .push((index, token, label));
     }
 
-    pub fn enter_block(&mut self) {
-        self.points_vec.push(BranchPoints::new())
+    /// set_next_label gives a label to the next block entered, which is the
+    /// "for", "switch" or "select" statement of a labeled statement
+    pub fn set_next_label(&mut self, label: String) {
+        self.next_label = Some(label);
     }
 
+    pub fn enter_block(&mut self, kind: BlockKind) {
+        let label = self.next_label.take();
+        self.points_vec.push(BranchPoints::new(label, kind))
+    }
+
+    /// range_slots returns the number of stack slots held by the range loops
+    /// that the branch jumps out of, a "continue" stays in its own loop
+    fn range_slots(&self, token: &Token, label: &Option<String>) -> OpIndex {
+        let mut slots = 0;
+        for points in self.points_vec.iter().rev() {
+            let target = match label {
+                Some(_) => points.label == *label,
+                None => *token == Token::BREAK || points.kind != BlockKind::Other,
+            };
+            if points.kind == BlockKind::Range && (!target || *token == Token::BREAK) {
+                slots += 2;
+            }
+            if target {
+                break;
+            }
+        }
+        slots
+    }
+
+    /// range_slots_held returns the number of stack slots held by the range
+    /// loops entered, a goto leaves the ones its label is not in
+    pub fn range_slots_held(&self) -> OpIndex {
+        self.points_vec
+            .iter()
+            .filter(|x| x.kind == BlockKind::Range)
+            .count() as OpIndex
+            * 2
+    }
+
+    /// leave_block patches the jumps of the block, 'begin' is None for switch and
+    /// select, in which case the "continue"s belong to the enclosing loop.
+    /// Jumps with a label of another block are also passed to the enclosing block.
     pub fn leave_block(&mut self, func: &mut FunctionVal, begin: Option<usize>, end: usize) {
         let points = self.points_vec.pop().unwrap();
-        for (index, token) in points.data.iter() {
-            let offset = if *token == Token::BREAK {
-                (end - index) as OpIndex - 1
+        for (index, token, label) in points.data.into_iter() {
+            let other_label = label.is_some() && label != points.label;
+            if other_label || (token == Token::CONTINUE && begin.is_none()) {
+                self.points_vec
+                    .last_mut()
+                    .unwrap()
+                    .data
+                    .push((index, token, label));
+                continue;
+            }
+            let target = if token == Token::BREAK {
+                end
             } else {
-                let begin = begin.unwrap();
-                if begin >= *index {
-                    (begin - index) as OpIndex - 1
-                } else {
-                    (index - begin) as OpIndex - 1
-                }
+                begin.unwrap()
             };
-            func.instruction_mut(*index).set_imm(offset);
+            patch_jump(func, index, target);
+        }
+    }
+}
+
+/// helper for goto, labels are scoped to the function. The range slots held
+/// where the labels and the gotos are tell the range loops a goto leaves, it
+/// can't jump into a block
+pub struct GotoHelper {
+    labels: HashMap<(FunctionKey, String), (usize, OpIndex)>,
+    // forward gotos waiting for their labels
+    gotos: Vec<(FunctionKey, usize, String, OpIndex)>,
+}
+
+impl GotoHelper {
+    pub fn new() -> GotoHelper {
+        GotoHelper {
+            labels: HashMap::new(),
+            gotos: vec![],
+        }
+    }
+
+    pub fn add_label(
+        &mut self,
+        fkey: FunctionKey,
+        func: &mut FunctionVal,
+        label: String,
+        range_slots: OpIndex,
+    ) {
+        let loc = func.next_code_index();
+        let gotos = std::mem::replace(&mut self.gotos, vec![]);
+        let (matched, rest): (Vec<_>, Vec<_>) = gotos
+            .into_iter()
+            .partition(|(f, _, l, _)| *f == fkey && *l == label);
+        self.gotos = rest;
+        for (_, index, _, slots) in matched.iter() {
+            if *slots > 0 {
+                // the POP before the jump, it may pop nothing in the end
+                func.instruction_mut(*index - 1)
+                    .set_imm(*slots - range_slots);
+            }
+            patch_jump(func, *index, loc);
+        }
+        self.labels.insert((fkey, label), (loc, range_slots));
+    }
+
+    pub fn add_goto(
+        &mut self,
+        fkey: FunctionKey,
+        func: &mut FunctionVal,
+        label: String,
+        range_slots: OpIndex,
+        pos: usize,
+    ) {
+        match self.labels.get(&(fkey, label.clone())) {
+            Some((loc, label_slots)) => {
+                let slots = range_slots - label_slots;
+                if slots > 0 {
+                    func.emit_code_with_flag_imm(Opcode::POP, true, slots, Some(pos));
+                }
+                let index = func.next_code_index();
+                func.emit_code_with_imm(Opcode::JUMP, 0, Some(pos));
+                patch_jump(func, index, *loc);
+            }
+            None => {
+                // the label is not known yet, the POP is patched by add_label
+                if range_slots > 0 {
+                    func.emit_code_with_flag_imm(Opcode::POP, true, range_slots, Some(pos));
+                }
+                let index = func.next_code_index();
+                func.emit_code_with_imm(Opcode::JUMP, 0, Some(pos));
+                self.gotos.push((fkey, index, label, range_slots));
+            }
         }
     }
 }
 
+/// patch_jump sets the target of the JUMP at 'index', backward jumps are turned
+/// into LOOPs so that the scheduler gets a chance to switch fibers
+fn patch_jump(func: &mut FunctionVal, index: usize, target: usize) {
+    let offset = target as OpIndex - index as OpIndex - 1;
+    let inst = func.instruction_mut(index);
+    if offset < 0 {
+        *inst = Instruction::new(Opcode::LOOP, None, None, None, Some(offset));
+    } else {
+        inst.set_imm(offset);
+    }
+}
+
 pub struct SwitchJumpPoints {
     cases: Vec<Vec<usize>>,
     default: Option<usize>,
@@ -137,3 +296,16 @@ impl SwitchHelper {
         })
     }
 }
+
+pub struct SelectHelper {}
+
+impl SelectHelper {
+    /// to_recv returns the receive expression of a select case
+    pub fn to_recv(e: &Expr) -> &UnaryExpr {
+        match e {
+            Expr::Unary(ue) => ue,
+            Expr::Paren(pe) => Self::to_recv(&pe.expr),
+            _ => unreachable!(),
+        }
+    }
+}
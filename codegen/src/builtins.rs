@@ -38,6 +38,16 @@ impl Builtins {
             BuiltInFunc::new("len", Opcode::LEN, 1, false),
             BuiltInFunc::new("cap", Opcode::CAP, 1, false),
             BuiltInFunc::new("append", Opcode::APPEND, 2, true),
+            BuiltInFunc::new("close", Opcode::CLOSE, 1, false),
+            BuiltInFunc::new("panic", Opcode::PANIC, 1, false),
+            BuiltInFunc::new("recover", Opcode::RECOVER, 0, false),
+            BuiltInFunc::new("copy", Opcode::COPY, 2, false),
+            BuiltInFunc::new("delete", Opcode::DELETE, 2, false),
+            BuiltInFunc::new("complex", Opcode::COMPLEX, 2, false),
+            BuiltInFunc::new("real", Opcode::REAL, 1, false),
+            BuiltInFunc::new("imag", Opcode::IMAG, 1, false),
+            BuiltInFunc::new("print", Opcode::PRINT, 0, false),
+            BuiltInFunc::new("println", Opcode::PRINTLN, 0, false),
             BuiltInFunc::new("assert", Opcode::ASSERT, 1, false),
             BuiltInFunc::new("ffi", Opcode::FFI, 2, false),
         ];
@@ -1,5 +1,6 @@
 //#![allow(dead_code)]
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::rc::Rc;
@@ -11,6 +12,7 @@ use super::interface::IfaceMapping;
 use super::package::PkgUtil;
 use super::types::{TypeCache, TypeLookup};
 
+use goscript_vm::ffi::Ffi;
 use goscript_vm::instruction::*;
 use goscript_vm::metadata::*;
 use goscript_vm::objects::EntIndex;
@@ -53,6 +55,7 @@ pub struct CodeGen<'a> {
     iface_mapping: &'a mut IfaceMapping,
     pkg_util: PkgUtil<'a>,
     break_cont: BreakContinue,
+    goto_labels: GotoHelper,
     pkg_key: PackageKey,
     func_stack: Vec<FunctionKey>,
     func_t_stack: Vec<TCTypeKey>, // for casting return values to interfaces
@@ -82,6 +85,7 @@ impl<'a> CodeGen<'a> {
             iface_mapping: mapping,
             pkg_util: PkgUtil::new(asto, tco, pkg_indices, pkgs, pkg),
             break_cont: BreakContinue::new(),
+            goto_labels: GotoHelper::new(),
             pkg_key: pkg,
             func_stack: Vec::new(),
             func_t_stack: Vec::new(),
@@ -108,30 +112,35 @@ impl<'a> CodeGen<'a> {
             return local.unwrap();
         }
         // 2. try upvalue
-        let upvalue = self
-            .func_stack
-            .clone()
+        let func_stack = self.func_stack.clone();
+        let upvalue = func_stack
             .iter()
+            .enumerate()
             .skip(1) // skip package constructor
             .rev()
             .skip(1) // skip itself
-            .find_map(|ifunc| {
+            .find_map(|(i, ifunc)| {
                 let f = &mut self.objects.functions[*ifunc];
                 let index = f.entity_index(&entity_key).map(|x| *x);
                 if let Some(ind) = index {
-                    let desc = ValueDesc {
-                        func: *ifunc,
-                        frame: 0,
-                        index: ind.into(),
-                        typ: self.tlookup.get_use_value_type(*ident),
-                        is_up_value: true,
-                    };
-                    Some(desc)
+                    let desc = ValueDesc::new(
+                        *ifunc,
+                        ind.into(),
+                        self.tlookup.get_use_value_type(*ident),
+                        true,
+                    );
+                    Some((i, desc))
                 } else {
                     None
                 }
             });
-        if let Some(uv) = upvalue {
+        if let Some((owner, uv)) = upvalue {
+            // the functions in between capture it too, so that a closure
+            // created where the owner's frame is out of reach, e.g. in another
+            // goroutine, can get it from the enclosing closure
+            for ifunc in func_stack[owner + 1..func_stack.len() - 1].iter() {
+                self.objects.functions[*ifunc].try_add_upvalue(&entity_key, uv.clone());
+            }
             let func = current_func_mut!(self);
             let index = func.try_add_upvalue(&entity_key, uv);
             return index;
@@ -203,12 +212,12 @@ impl<'a> CodeGen<'a> {
     /// x += 1
     /// x++
     /// for x := range xxx
+    /// case x := <-ch
     fn gen_assign(
         &mut self,
         token: &Token,
         lhs_exprs: &Vec<&Expr>,
-        rhs_exprs: &Vec<Expr>,
-        range: Option<&Expr>,
+        rhs: RightHandSide,
     ) -> Option<usize> {
         let lhs = lhs_exprs
             .iter()
@@ -236,10 +245,14 @@ impl<'a> CodeGen<'a> {
 
                         let mut index_const = None;
                         let mut index_typ = None;
-                        if let Some(const_val) = self.tlookup.get_tc_const_value(ind.id()) {
-                            let (ival, _) = const_val.to_int().int_as_i64();
-                            if let Ok(i) = OpIndex::try_from(ival) {
-                                index_const = Some(i);
+                        // the IMM instructions take the index as an int, which is wrong
+                        // for map keys of other types
+                        if self.tlookup.get_expr_value_type(ind) == ValueType::Int {
+                            if let Some(const_val) = self.tlookup.get_tc_const_value(ind.id()) {
+                                let (ival, _) = const_val.to_int().int_as_i64();
+                                if let Ok(i) = OpIndex::try_from(ival) {
+                                    index_const = Some(i);
+                                }
                             }
                         }
                         if index_const.is_none() {
@@ -331,6 +344,10 @@ impl<'a> CodeGen<'a> {
                 let typ = self.tlookup.get_expr_value_type(&lhs_exprs[0]);
                 self.gen_op_assign(&lhs[0].0, (code, None), None, typ, lhs[0].2);
             } else {
+                let rhs_exprs = match rhs {
+                    RightHandSide::Values(v) => v,
+                    _ => unreachable!(),
+                };
                 assert_eq!(lhs_exprs.len(), 1);
                 assert_eq!(rhs_exprs.len(), 1);
                 let ltyp = self.tlookup.get_expr_value_type(&lhs_exprs[0]);
@@ -344,10 +361,6 @@ impl<'a> CodeGen<'a> {
             }
             None
         } else {
-            let rhs = match range {
-                Some(r) => RightHandSide::Range(r),
-                None => RightHandSide::Values(rhs_exprs),
-            };
             self.gen_assign_def_var(&lhs, &None, &rhs)
         }
     }
@@ -382,21 +395,16 @@ impl<'a> CodeGen<'a> {
                     let comma_ok = lhs.len() == 2;
                     match val0 {
                         Expr::TypeAssert(tae) => {
-                            self.visit_expr(&tae.expr);
-                            let t = self.tlookup.get_expr_tc_type(tae.typ.as_ref().unwrap());
-                            let meta = self.tlookup.meta_from_tc(t, self.objects);
-                            let func = current_func_mut!(self);
-                            let index = func.add_const(None, GosValue::Metadata(meta));
-                            func.emit_code_with_flag_imm(
-                                Opcode::TYPE_ASSERT,
-                                comma_ok,
-                                index.into(),
-                                Some(tae.l_paren),
-                            );
+                            self.gen_type_assert(&tae.expr, tae.typ.as_ref().unwrap(), comma_ok);
                         }
                         Expr::Index(ie) => {
                             self.gen_map_index(&ie.expr, &ie.index, comma_ok);
                         }
+                        Expr::Unary(ue) => {
+                            // receive from a channel
+                            self.visit_expr(&ue.expr);
+                            current_func_emitter!(self).emit_recv(comma_ok, Some(ue.op_pos));
+                        }
                         _ => unreachable!(),
                     }
                     if comma_ok {
@@ -447,6 +455,14 @@ impl<'a> CodeGen<'a> {
                 );
                 tkv[1..].to_vec()
             }
+            RightHandSide::SelectRecv(rhs, comma_ok) => {
+                // the values are pushed by Opcode::SELECT already
+                if *comma_ok {
+                    self.tlookup.get_return_tc_types(rhs)
+                } else {
+                    vec![self.tlookup.get_expr_tc_type(rhs)]
+                }
+            }
         };
 
         // now the values should be on stack, generate code to set them to the lhs
@@ -458,9 +474,13 @@ impl<'a> CodeGen<'a> {
         assert_eq!(lhs.len(), types.len());
         let total_rhs_val = types.len() as OpIndex;
         let total_val = (total_lhs_val + total_rhs_val) as OpIndex;
-        let mut current_indexing_index = -total_val;
+        // the values received by select are pushed before the lhs
+        let (mut current_indexing_index, rhs_begin) = match rhs {
+            RightHandSide::SelectRecv(_, _) => (-total_lhs_val, -total_val),
+            _ => (-total_val, -total_rhs_val),
+        };
         for (i, (l, _, p)) in lhs.iter().enumerate() {
-            let val_index = i as OpIndex - total_rhs_val;
+            let val_index = i as OpIndex + rhs_begin;
             let typ = self.try_cast_to_iface(lhs[i].1, Some(types[i]), val_index, *p);
             let pos = Some(*p);
             match l {
@@ -660,16 +680,29 @@ impl<'a> CodeGen<'a> {
         fkey
     }
 
+    /// gen_type_assert generates x.(T), a failed assertion panics unless it's comma-ok
+    fn gen_type_assert(&mut self, expr: &Expr, typ: &Expr, comma_ok: bool) {
+        self.visit_expr(expr);
+        let t = self.tlookup.get_expr_tc_type(typ);
+        let meta = self.tlookup.meta_from_tc(t, self.objects);
+        let pos = Some(typ.pos(&self.ast_objs));
+        let func = current_func_mut!(self);
+        let index = func.add_const(None, GosValue::Metadata(meta));
+        func.emit_code_with_flag_imm(Opcode::TYPE_ASSERT, comma_ok, index.into(), pos);
+    }
+
     fn gen_map_index(&mut self, expr: &Expr, index: &Expr, comma_ok: bool) {
         let t0 = self.tlookup.get_expr_value_type(expr);
         let t1 = self.tlookup.get_expr_value_type(index);
         self.visit_expr(expr);
         let pos = Some(expr.pos(&self.ast_objs));
-        if let Some(const_val) = self.tlookup.get_tc_const_value(index.id()) {
-            let (ival, _) = const_val.to_int().int_as_i64();
-            if let Ok(i) = OpIndex::try_from(ival) {
-                current_func_emitter!(self).emit_load_index_imm(i, t0, comma_ok, pos);
-                return;
+        if t1 == ValueType::Int {
+            if let Some(const_val) = self.tlookup.get_tc_const_value(index.id()) {
+                let (ival, _) = const_val.to_int().int_as_i64();
+                if let Ok(i) = OpIndex::try_from(ival) {
+                    current_func_emitter!(self).emit_load_index_imm(i, t0, comma_ok, pos);
+                    return;
+                }
             }
         }
         self.visit_expr(index);
@@ -688,8 +721,11 @@ impl<'a> CodeGen<'a> {
             if self.tlookup.underlying_value_type_from_tc(t1) == ValueType::Interface {
                 let (cast, typ) = match rhs {
                     Some(t2) => {
+                        // values of named types are wrapped, so the type on the
+                        // stack is not the underlying one
                         let vt2 = self.tlookup.underlying_value_type_from_tc(t2);
-                        (vt2 != ValueType::Interface && vt2 != ValueType::Nil, vt2)
+                        let cast = vt2 != ValueType::Interface && vt2 != ValueType::Nil;
+                        (cast, self.tlookup.value_type_from_tc(t2))
                     }
                     None => (true, ValueType::Slice), // it must be a variadic parameter
                 };
@@ -737,6 +773,65 @@ impl<'a> CodeGen<'a> {
         }
     }
 
+    /// is_builtin_call returns if 'func' is one of the built in functions
+    fn is_builtin_call(&self, func: &Expr) -> bool {
+        match func {
+            Expr::Ident(ikey) => {
+                let ident = &self.ast_objs.idents[*ikey];
+                ident.entity.clone().into_key().is_none()
+                    && self.builtins.func_index(&ident.name).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// gen_conversion generates a type conversion, constant conversions are
+    /// already evaluated by the type checker
+    fn gen_conversion(&mut self, this: &Expr, typ: &Expr, param: &Expr) {
+        let pos = Some(typ.pos(&self.ast_objs));
+        let tc_to = self.tlookup.get_expr_tc_type(this);
+        let meta = self.tlookup.meta_from_tc(tc_to, self.objects);
+        if self.tlookup.get_tc_const_value(this.id()).is_some() {
+            let val = self.tlookup.get_const_value(this.id());
+            let (val, t) = match self.tlookup.value_type_from_tc(tc_to) {
+                ValueType::Named => (GosValue::Named(Box::new((val, meta))), ValueType::Named),
+                _ => {
+                    let t = val.get_type();
+                    (val, t)
+                }
+            };
+            let mut emitter = current_func_emitter!(self);
+            let i = emitter.add_const(None, val);
+            emitter.emit_load(i, None, t, pos);
+            return;
+        }
+
+        self.visit_expr(param);
+        let tc_from = self.tlookup.get_expr_tc_type(param);
+        if self.tlookup.underlying_value_type_from_tc(tc_to) == ValueType::Interface {
+            self.try_cast_to_iface(Some(tc_to), Some(tc_from), -1, pos.unwrap());
+        } else {
+            let t_from = self.tlookup.get_expr_value_type(param);
+            let func = current_func_mut!(self);
+            let index = func.add_const(None, GosValue::Metadata(meta));
+            func.emit_code_with_type_imm(Opcode::CONVERT, t_from, index.into(), pos);
+        }
+    }
+
+    /// gen_call generates the call of a goscript function, the call runs in
+    /// a new fiber if it's CallStyle::Async, or when the current function
+    /// returns if it's CallStyle::Defer
+    fn gen_call(&mut self, func_expr: &Expr, params: &Vec<Expr>, ellipsis: bool, style: CallStyle) {
+        let pos = Some(func_expr.pos(&self.ast_objs));
+        self.visit_expr(func_expr);
+        current_func_emitter!(self).emit_pre_call(pos);
+        let _ = params.iter().map(|e| self.visit_expr(e)).count();
+        let t = self.tlookup.get_expr_tc_type(func_expr);
+        self.try_cast_params_to_iface(t, params, ellipsis);
+        // do not pack params if there is ellipsis
+        current_func_emitter!(self).emit_call(style, ellipsis, pos);
+    }
+
     fn get_type_default(&mut self, expr: &Expr) -> (GosValue, TCTypeKey) {
         let t = self.tlookup.get_expr_tc_type(expr);
         let meta = self.tlookup.meta_from_tc(t, self.objects);
@@ -881,6 +976,29 @@ impl<'a> CodeGen<'a> {
         emitter.emit_return_init_pkg(index, None);
         self.func_stack.pop();
     }
+
+    /// add_native_funcs adds the functions implemented by the host to the
+    /// members of the package, they are declared in its scope by the type
+    /// checker, each one is called through its own Ffi object
+    pub fn add_native_funcs(
+        &mut self,
+        tcpkg: TCPackageKey,
+        funcs: &[(String, Rc<RefCell<dyn Ffi>>)],
+    ) {
+        let scope = &self.tc_objs.scopes[*self.tc_objs.pkgs[tcpkg].scope()];
+        for (name, ffi) in funcs.iter() {
+            let okey = scope.lookup(name).unwrap();
+            let typ = self.tc_objs.lobjs[*okey].typ().unwrap();
+            let meta = self.tlookup.meta_from_tc(typ, self.objects);
+            let cls = ClosureObj::new_ffi(FfiClosureObj {
+                ffi: ffi.clone(),
+                func_name: name.clone(),
+                meta: meta,
+            });
+            let val = GosValue::Closure(Rc::new((RefCell::new(cls), Cell::new(0))));
+            self.objects.packages[self.pkg_key].add_member(name.clone(), val);
+        }
+    }
 }
 
 impl<'a> ExprVisitor for CodeGen<'a> {
@@ -1019,49 +1137,81 @@ impl<'a> ExprVisitor for CodeGen<'a> {
         }
     }
 
-    fn visit_expr_type_assert(&mut self, _: &Expr, _expr: &Expr, _typ: &Option<Expr>) {
-        unimplemented!();
+    fn visit_expr_type_assert(&mut self, _: &Expr, expr: &Expr, typ: &Option<Expr>) {
+        self.gen_type_assert(expr, typ.as_ref().unwrap(), false);
     }
 
-    fn visit_expr_call(&mut self, _: &Expr, func_expr: &Expr, params: &Vec<Expr>, ellipsis: bool) {
+    fn visit_expr_call(
+        &mut self,
+        this: &Expr,
+        func_expr: &Expr,
+        params: &Vec<Expr>,
+        ellipsis: bool,
+    ) {
+        // calling a type is a type conversion
+        if self.tlookup.get_expr_mode(func_expr) == &OperandMode::TypeExpr {
+            return self.gen_conversion(this, func_expr, &params[0]);
+        }
+
         // check if this is a built in function first
         let pos = Some(func_expr.pos(&self.ast_objs));
         if let Expr::Ident(ikey) = func_expr {
             let ident = self.ast_objs.idents[*ikey].clone();
             if ident.entity.into_key().is_none() {
                 return if let Some(i) = self.builtins.func_index(&ident.name) {
-                    let t = self.tlookup.get_expr_value_type(&params[0]);
-                    let t_last = self.tlookup.get_expr_value_type(params.last().unwrap());
+                    // calls like len("abc") or real(1i) are constants
+                    if self.tlookup.get_tc_const_value(this.id()).is_some() {
+                        let val = self.tlookup.get_const_value(this.id());
+                        let mut emitter = current_func_emitter!(self);
+                        let t = val.get_type();
+                        let i = emitter.add_const(None, val);
+                        emitter.emit_load(i, None, t, pos);
+                        return;
+                    }
+
+                    let opcode = self.builtins.get_func_by_index(i as usize).opcode;
+                    let t = params.first().map(|e| self.tlookup.get_expr_value_type(e));
+                    let mut t_last = params.last().map(|e| self.tlookup.get_expr_value_type(e));
                     for e in params.iter() {
                         self.visit_expr(e);
                     }
                     // some of the built in funcs are not recorded
                     if let Some(t) = self.tlookup.try_get_expr_tc_type(func_expr) {
                         self.try_cast_params_to_iface(t, params, ellipsis);
-                        if self.builtins.get_func_by_index(i as usize).opcode == Opcode::FFI {
-                            // FFI needs the signature of the call
-                            let meta = self.tlookup.meta_from_tc(t, self.objects);
-                            let mut emitter = current_func_emitter!(self);
-                            let i = emitter.add_const(None, GosValue::Metadata(meta));
-                            emitter.emit_load(i, None, ValueType::Metadata, pos);
+                        // the last param could have been casted, e.g. the key of delete
+                        let (sig_params, variadic) = self.tlookup.get_sig_params_tc_types(t);
+                        if let (Some(p), None) = (sig_params.last(), variadic) {
+                            if self.tlookup.underlying_value_type_from_tc(*p)
+                                == ValueType::Interface
+                            {
+                                t_last = Some(ValueType::Interface);
+                            }
+                        }
+                        match opcode {
+                            // they need the signature of the call
+                            Opcode::FFI | Opcode::PRINT | Opcode::PRINTLN => {
+                                let meta = self.tlookup.meta_from_tc(t, self.objects);
+                                let mut emitter = current_func_emitter!(self);
+                                let i = emitter.add_const(None, GosValue::Metadata(meta));
+                                emitter.emit_load(i, None, ValueType::Metadata, pos);
+                            }
+                            _ => {}
                         }
                     }
                     let bf = &self.builtins.get_func_by_index(i as usize);
                     let count = params.len();
-                    let (t_variadic, count) = if bf.variadic {
-                        if ellipsis {
-                            (None, Some(0)) // do not pack params if there is ellipsis
-                        } else {
-                            (
-                                Some(t_last),
-                                Some((bf.params_count - 1 - count as isize) as OpIndex),
-                            )
-                        }
+                    if bf.variadic && ellipsis {
+                        // do not pack params if there is ellipsis
+                        current_func_mut!(self).emit_code_with_flag_imm(opcode, true, 0, pos);
+                        return;
+                    }
+                    let count = if bf.variadic {
+                        bf.params_count - 1 - count as isize
                     } else {
-                        (None, Some(count as OpIndex))
+                        count as isize
                     };
                     let func = current_func_mut!(self);
-                    func.emit_inst(bf.opcode, [Some(t), t_variadic, None], count, pos);
+                    func.emit_inst(opcode, [t, t_last, None], Some(count as OpIndex), pos);
                 } else {
                     unreachable!()
                 };
@@ -1069,13 +1219,7 @@ impl<'a> ExprVisitor for CodeGen<'a> {
         }
 
         // normal goscript function
-        self.visit_expr(func_expr);
-        current_func_emitter!(self).emit_pre_call(pos);
-        let _ = params.iter().map(|e| self.visit_expr(e)).count();
-        let t = self.tlookup.get_expr_tc_type(func_expr);
-        self.try_cast_params_to_iface(t, params, ellipsis);
-        // do not pack params if there is ellipsis
-        current_func_emitter!(self).emit_call(ellipsis, pos);
+        self.gen_call(func_expr, params, ellipsis, CallStyle::Default);
     }
 
     fn visit_expr_star(&mut self, _: &Expr, expr: &Expr) {
@@ -1129,13 +1273,12 @@ impl<'a> ExprVisitor for CodeGen<'a> {
                                 let entity_key = ident.entity_key().unwrap();
                                 let func = current_func_mut!(self);
                                 let ind = *func.entity_index(&entity_key).unwrap();
-                                let desc = ValueDesc {
-                                    func: *self.func_stack.last().unwrap(),
-                                    frame: 0,
-                                    index: ind.into(),
-                                    typ: t,
-                                    is_up_value: false,
-                                };
+                                let desc = ValueDesc::new(
+                                    *self.func_stack.last().unwrap(),
+                                    ind.into(),
+                                    t,
+                                    false,
+                                );
                                 let index = func.try_add_upvalue(&entity_key, desc);
                                 func.emit_inst(
                                     Opcode::REF_UPVALUE,
@@ -1233,6 +1376,7 @@ impl<'a> ExprVisitor for CodeGen<'a> {
             Token::SUB => Opcode::UNARY_SUB,
             Token::XOR => Opcode::UNARY_XOR,
             Token::NOT => Opcode::NOT,
+            Token::ARROW => Opcode::ARROW,
             _ => {
                 dbg!(op);
                 unreachable!()
@@ -1331,8 +1475,8 @@ impl<'a> ExprVisitor for CodeGen<'a> {
         self.gen_type_meta(this)
     }
 
-    fn visit_chan_type(&mut self, _: &Expr, _chan: &Expr, _dir: &ChanDir) {
-        unimplemented!();
+    fn visit_chan_type(&mut self, this: &Expr, _chan: &Expr, _dir: &ChanDir) {
+        self.gen_type_meta(this)
     }
 
     fn visit_bad_expr(&mut self, _: &Expr, _e: &BadExpr) {
@@ -1344,7 +1488,22 @@ impl<'a> StmtVisitor for CodeGen<'a> {
     type Result = ();
 
     fn visit_stmt(&mut self, stmt: &Stmt) {
-        walk_stmt(self, stmt)
+        match stmt {
+            Stmt::Expr(e) => {
+                self.visit_expr(e);
+                // the results of a function called as a statement are unused
+                if let Expr::Call(call) = &**e {
+                    if !self.is_builtin_call(&call.func) {
+                        let count = self.tlookup.get_expr_value_count(e);
+                        if count > 0 {
+                            let pos = Some(e.pos(&self.ast_objs));
+                            current_func_emitter!(self).emit_pop(count as OpIndex, pos);
+                        }
+                    }
+                }
+            }
+            _ => walk_stmt(self, stmt),
+        }
     }
 
     fn visit_decl(&mut self, decl: &Decl) {
@@ -1401,16 +1560,41 @@ impl<'a> StmtVisitor for CodeGen<'a> {
         }
     }
 
-    fn visit_stmt_labeled(&mut self, _lstmt: &LabeledStmtKey) {
-        unimplemented!();
-    }
-
-    fn visit_stmt_send(&mut self, _sstmt: &SendStmt) {
-        unimplemented!();
+    fn visit_stmt_labeled(&mut self, lstmt: &LabeledStmtKey) {
+        let stmt = &self.ast_objs.l_stmts[*lstmt];
+        let name = self.ast_objs.idents[stmt.label].name.clone();
+        let fkey = *self.func_stack.last().unwrap();
+        let slots = self.break_cont.range_slots_held();
+        self.goto_labels
+            .add_label(fkey, current_func_mut!(self), name.clone(), slots);
+        match &stmt.stmt {
+            Stmt::For(_)
+            | Stmt::Range(_)
+            | Stmt::Switch(_)
+            | Stmt::TypeSwitch(_)
+            | Stmt::Select(_) => self.break_cont.set_next_label(name),
+            _ => {}
+        }
+        self.visit_stmt(&stmt.stmt);
+    }
+
+    fn visit_stmt_send(&mut self, sstmt: &SendStmt) {
+        self.visit_expr(&sstmt.chan);
+        self.visit_expr(&sstmt.val);
+        let chan_type = self.tlookup.get_expr_tc_type(&sstmt.chan);
+        let elem_type = self.tlookup.get_chan_elem_tc_type(chan_type);
+        let val_type = self.tlookup.get_expr_tc_type(&sstmt.val);
+        let typ = self.try_cast_to_iface(Some(elem_type), Some(val_type), -1, sstmt.arrow);
+        current_func_mut!(self).emit_inst(
+            Opcode::SEND,
+            [Some(ValueType::Channel), Some(typ), None],
+            None,
+            Some(sstmt.arrow),
+        );
     }
 
     fn visit_stmt_incdec(&mut self, idcstmt: &IncDecStmt) {
-        self.gen_assign(&idcstmt.token, &vec![&idcstmt.expr], &vec![], None);
+        self.gen_assign(&idcstmt.token, &vec![&idcstmt.expr], RightHandSide::Nothing);
     }
 
     fn visit_stmt_assign(&mut self, astmt: &AssignStmtKey) {
@@ -1418,17 +1602,36 @@ impl<'a> StmtVisitor for CodeGen<'a> {
         self.gen_assign(
             &stmt.token,
             &stmt.lhs.iter().map(|x| x).collect(),
-            &stmt.rhs,
-            None,
+            RightHandSide::Values(&stmt.rhs),
         );
     }
 
-    fn visit_stmt_go(&mut self, _gostmt: &GoStmt) {
-        unimplemented!();
+    fn visit_stmt_go(&mut self, gostmt: &GoStmt) {
+        match &gostmt.call {
+            Expr::Call(call) => {
+                self.gen_call(
+                    &call.func,
+                    &call.args,
+                    call.ellipsis.is_some(),
+                    CallStyle::Async,
+                );
+            }
+            _ => unreachable!(),
+        }
     }
 
-    fn visit_stmt_defer(&mut self, _dstmt: &DeferStmt) {
-        unimplemented!();
+    fn visit_stmt_defer(&mut self, dstmt: &DeferStmt) {
+        match &dstmt.call {
+            Expr::Call(call) => {
+                self.gen_call(
+                    &call.func,
+                    &call.args,
+                    call.ellipsis.is_some(),
+                    CallStyle::Defer,
+                );
+            }
+            _ => unreachable!(),
+        }
     }
 
     fn visit_stmt_return(&mut self, rstmt: &ReturnStmt) {
@@ -1458,13 +1661,26 @@ impl<'a> StmtVisitor for CodeGen<'a> {
     fn visit_stmt_branch(&mut self, bstmt: &BranchStmt) {
         match bstmt.token {
             Token::BREAK | Token::CONTINUE => {
+                let label = bstmt.label.map(|l| self.ast_objs.idents[l].name.clone());
                 self.break_cont.add_point(
                     current_func_mut!(self),
                     bstmt.token.clone(),
+                    label,
+                    bstmt.token_pos,
+                );
+            }
+            Token::GOTO => {
+                let label = self.ast_objs.idents[bstmt.label.unwrap()].name.clone();
+                let fkey = *self.func_stack.last().unwrap();
+                let slots = self.break_cont.range_slots_held();
+                self.goto_labels.add_goto(
+                    fkey,
+                    current_func_mut!(self),
+                    label,
+                    slots,
                     bstmt.token_pos,
                 );
             }
-            Token::GOTO => {}
             Token::FALLTHROUGH => {}
             _ => unreachable!(),
         }
@@ -1516,7 +1732,7 @@ impl<'a> StmtVisitor for CodeGen<'a> {
     }
 
     fn visit_stmt_switch(&mut self, sstmt: &SwitchStmt) {
-        self.break_cont.enter_block();
+        self.break_cont.enter_block(BlockKind::Other);
 
         if let Some(init) = &sstmt.init {
             self.visit_stmt(init);
@@ -1540,6 +1756,8 @@ impl<'a> StmtVisitor for CodeGen<'a> {
     }
 
     fn visit_stmt_type_switch(&mut self, tstmt: &TypeSwitchStmt) {
+        self.break_cont.enter_block(BlockKind::Other);
+
         if let Some(init) = &tstmt.init {
             self.visit_stmt(init);
         }
@@ -1572,18 +1790,116 @@ impl<'a> StmtVisitor for CodeGen<'a> {
         }
 
         self.gen_switch_body(&*tstmt.body, ValueType::Metadata);
+
+        let end = current_func!(self).next_code_index();
+        self.break_cont
+            .leave_block(current_func_mut!(self), None, end);
     }
 
     fn visit_stmt_comm(&mut self, _cclause: &CommClause) {
-        unimplemented!();
+        unreachable!(); // handled at upper level of the tree
     }
 
-    fn visit_stmt_select(&mut self, _sstmt: &SelectStmt) {
-        unimplemented!();
+    /// The operands of all the cases are evaluated first, then Opcode::SELECT
+    /// picks a case and jumps to its body, each case is described by an
+    /// instruction after SELECT. The default case is placed right after them.
+    fn visit_stmt_select(&mut self, sstmt: &SelectStmt) {
+        self.break_cont.enter_block(BlockKind::Other);
+
+        let clauses: Vec<&CommClause> = sstmt
+            .body
+            .list
+            .iter()
+            .map(|s| match s {
+                Stmt::Comm(c) => c.as_ref(),
+                _ => unreachable!(),
+            })
+            .collect();
+        let mut cases = vec![];
+        let mut default = None;
+        for cc in clauses.iter() {
+            let pos = Some(cc.case);
+            match &cc.comm {
+                Some(Stmt::Send(send)) => {
+                    self.visit_expr(&send.chan);
+                    self.visit_expr(&send.val);
+                    let chan_type = self.tlookup.get_expr_tc_type(&send.chan);
+                    let elem_type = self.tlookup.get_chan_elem_tc_type(chan_type);
+                    let val_type = self.tlookup.get_expr_tc_type(&send.val);
+                    let typ =
+                        self.try_cast_to_iface(Some(elem_type), Some(val_type), -1, send.arrow);
+                    cases.push((cc, Opcode::SEND, typ, false, pos));
+                }
+                Some(Stmt::Expr(e)) => {
+                    let ue = SelectHelper::to_recv(e);
+                    self.visit_expr(&ue.expr);
+                    cases.push((cc, Opcode::ARROW, ValueType::Channel, false, pos));
+                }
+                Some(Stmt::Assign(akey)) => {
+                    let ass = &self.ast_objs.a_stmts[*akey];
+                    let ue = SelectHelper::to_recv(&ass.rhs[0]);
+                    self.visit_expr(&ue.expr);
+                    let comma_ok = ass.lhs.len() == 2;
+                    cases.push((cc, Opcode::ARROW, ValueType::Channel, comma_ok, pos));
+                }
+                None => default = Some(cc),
+                _ => unreachable!(),
+            }
+        }
+
+        let mut emitter = current_func_emitter!(self);
+        emitter.emit_select(
+            cases.len() as OpIndex,
+            default.is_some(),
+            Some(sstmt.select),
+        );
+        for (_, op, typ, comma_ok, pos) in cases.iter() {
+            emitter.emit_select_case(*op, *typ, *comma_ok, *pos);
+        }
+        let block_end = current_func!(self).next_code_index();
+
+        let mut ends = vec![];
+        if let Some(cc) = default {
+            for s in cc.body.iter() {
+                self.visit_stmt(s);
+            }
+            let func = current_func_mut!(self);
+            ends.push(func.next_code_index());
+            func.emit_code(Opcode::JUMP, None);
+        }
+        for (i, (cc, op, _, comma_ok, pos)) in cases.iter().enumerate() {
+            let func = current_func_mut!(self);
+            let offset = (func.next_code_index() - block_end) as OpIndex;
+            func.instruction_mut(block_end - cases.len() + i)
+                .set_imm(offset);
+            if *op == Opcode::ARROW {
+                match &cc.comm {
+                    Some(Stmt::Assign(akey)) => {
+                        let ass = &self.ast_objs.a_stmts[*akey];
+                        let rhs = RightHandSide::SelectRecv(&ass.rhs[0], *comma_ok);
+                        self.gen_assign(&ass.token, &ass.lhs.iter().collect(), rhs);
+                    }
+                    _ => current_func_emitter!(self).emit_pop(1, *pos),
+                }
+            }
+            for s in cc.body.iter() {
+                self.visit_stmt(s);
+            }
+            let func = current_func_mut!(self);
+            ends.push(func.next_code_index());
+            func.emit_code(Opcode::JUMP, None);
+        }
+        let end = current_func!(self).next_code_index();
+        let func = current_func_mut!(self);
+        for i in ends.into_iter() {
+            func.instruction_mut(i).set_imm((end - i) as OpIndex - 1);
+        }
+
+        self.break_cont.leave_block(func, None, end);
     }
 
     fn visit_stmt_for(&mut self, fstmt: &ForStmt) {
-        self.break_cont.enter_block();
+        self.break_cont.enter_block(BlockKind::For);
 
         if let Some(init) = &fstmt.init {
             self.visit_stmt(init);
@@ -1611,7 +1927,7 @@ impl<'a> StmtVisitor for CodeGen<'a> {
         // jump to the top
         let func = current_func_mut!(self);
         let offset = -func.offset(top_marker) - 1;
-        func.emit_code_with_imm(Opcode::JUMP, offset, Some(fstmt.for_pos));
+        func.emit_code_with_imm(Opcode::LOOP, offset, Some(fstmt.for_pos));
 
         // set the correct else jump out target
         if let Some(m) = out_marker {
@@ -1626,7 +1942,7 @@ impl<'a> StmtVisitor for CodeGen<'a> {
     }
 
     fn visit_stmt_range(&mut self, rstmt: &RangeStmt) {
-        self.break_cont.enter_block();
+        self.break_cont.enter_block(BlockKind::Range);
 
         let blank = Expr::Ident(self.blank_ident);
         let lhs = vec![
@@ -1634,7 +1950,7 @@ impl<'a> StmtVisitor for CodeGen<'a> {
             rstmt.val.as_ref().unwrap_or(&blank),
         ];
         let marker = self
-            .gen_assign(&rstmt.token, &lhs, &vec![], Some(&rstmt.expr))
+            .gen_assign(&rstmt.token, &lhs, RightHandSide::Range(&rstmt.expr))
             .unwrap();
 
         self.visit_stmt_block(&rstmt.body);
@@ -1644,7 +1960,7 @@ impl<'a> StmtVisitor for CodeGen<'a> {
         // tell Opcode::RANGE where to jump after it's done
         let end_offset = func.offset(marker);
         func.instruction_mut(marker).set_imm(end_offset);
-        func.emit_code_with_imm(Opcode::JUMP, offset, Some(rstmt.token_pos));
+        func.emit_code_with_imm(Opcode::LOOP, offset, Some(rstmt.token_pos));
 
         let end = current_func!(self).next_code_index();
         self.break_cont
@@ -8,6 +8,13 @@ use goscript_vm::objects::{key_to_u64, EntIndex, FunctionVal};
 use goscript_vm::value::*;
 use std::convert::TryFrom;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallStyle {
+    Default,
+    Async, // the go statement
+    Defer, // the defer statement
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum IndexSelType {
     Indexing,
@@ -70,6 +77,7 @@ pub enum RightHandSide<'a> {
     Nothing,
     Values(&'a Vec<Expr>),
     Range(&'a Expr),
+    SelectRecv(&'a Expr, bool), // the values are already on the stack
 }
 
 pub struct Emitter<'a> {
@@ -315,6 +323,32 @@ impl<'a> Emitter<'a> {
         self.f.push_inst_pos(inst, pos);
     }
 
+    pub fn emit_recv(&mut self, comma_ok: bool, pos: Option<usize>) {
+        let mut inst = Instruction::new(Opcode::ARROW, Some(ValueType::Channel), None, None, None);
+        inst.set_t2_with_index(if comma_ok { 1 } else { 0 });
+        self.f.push_inst_pos(inst, pos);
+    }
+
+    pub fn emit_select(&mut self, count: OpIndex, has_default: bool, pos: Option<usize>) {
+        let mut inst = Instruction::new(Opcode::SELECT, None, None, None, Some(count));
+        inst.set_t2_with_index(if has_default { 1 } else { 0 });
+        self.f.push_inst_pos(inst, pos);
+    }
+
+    /// emit_select_case emits the description of a case of the select statement,
+    /// the opcode tells if it's a send or a receive
+    pub fn emit_select_case(
+        &mut self,
+        op: Opcode,
+        typ: ValueType,
+        comma_ok: bool,
+        pos: Option<usize>,
+    ) {
+        let mut inst = Instruction::new(op, Some(typ), None, None, Some(0));
+        inst.set_t2_with_index(if comma_ok { 1 } else { 0 });
+        self.f.push_inst_pos(inst, pos);
+    }
+
     pub fn emit_return(&mut self, pos: Option<usize>) {
         self.f
             .emit_inst(Opcode::RETURN, [None, None, None], None, pos);
@@ -334,13 +368,27 @@ impl<'a> Emitter<'a> {
             .emit_inst(Opcode::PRE_CALL, [None, None, None], None, pos);
     }
 
-    pub fn emit_call(&mut self, has_ellipsis: bool, pos: Option<usize>) {
-        let op = if has_ellipsis {
-            Opcode::CALL_ELLIPSIS
-        } else {
-            Opcode::CALL
-        };
-        self.f.emit_inst(op, [None, None, None], None, pos);
+    pub fn emit_call(&mut self, style: CallStyle, has_ellipsis: bool, pos: Option<usize>) {
+        match style {
+            CallStyle::Default => {
+                let op = if has_ellipsis {
+                    Opcode::CALL_ELLIPSIS
+                } else {
+                    Opcode::CALL
+                };
+                self.f.emit_inst(op, [None, None, None], None, pos);
+            }
+            CallStyle::Async | CallStyle::Defer => {
+                // the imm tells if there is an ellipsis
+                let imm = if has_ellipsis { 1 } else { 0 };
+                let op = if style == CallStyle::Async {
+                    Opcode::GO
+                } else {
+                    Opcode::DEFER
+                };
+                self.f.emit_inst(op, [None, None, None], Some(imm), pos);
+            }
+        }
     }
 
     pub fn emit_literal(&mut self, typ: ValueType, index: OpIndex, pos: Option<usize>) {
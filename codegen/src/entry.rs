@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use super::codegen::CodeGen;
-use super::emit::Emitter;
+use super::emit::{CallStyle, Emitter};
 use super::interface::IfaceMapping;
 use super::package::PkgVarPairs;
 use super::types::TypeCache;
@@ -10,12 +10,19 @@ use goscript_parser::objects::Objects as AstObjects;
 use goscript_parser::objects::*;
 use goscript_parser::FileSet;
 use goscript_types::{Config, PackageKey as TCPackageKey, TCObjects, TypeInfo};
+use goscript_vm::ffi::Ffi;
 use goscript_vm::instruction::*;
 use goscript_vm::null_key;
 use goscript_vm::value::*;
 use goscript_vm::vm::ByteCode;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::pin::Pin;
+use std::rc::Rc;
+
+/// NativeFuncs are the implementations of the functions of Config::native_pkgs,
+/// by import path, and then by function name
+pub type NativeFuncs = HashMap<String, Vec<(String, Rc<RefCell<dyn Ffi>>)>>;
 
 pub struct EntryGen<'a> {
     objects: Pin<Box<VMObjects>>,
@@ -41,29 +48,31 @@ impl<'a> EntryGen<'a> {
         }
     }
 
-    // generate the entry function for ByteCode
+    // generate an entry function for ByteCode, it imports the main package, which
+    // initializes all the packages, and calls 'main_ident' of the package if given
     fn gen_entry_func(
         &mut self,
         pkg: PackageKey,
         index: OpIndex,
-        main_ident: IdentKey,
+        main_ident: Option<IdentKey>,
         pairs: &mut PkgVarPairs,
     ) -> FunctionKey {
-        // import the 0th pkg and call the main function of the pkg
         let fmeta = self.objects.metadata.default_sig;
         let f = GosValue::new_function(null_key!(), fmeta.clone(), &mut self.objects, false);
         let fkey = *f.as_function();
         let func = &mut self.objects.functions[fkey];
         let mut emitter = Emitter::new(func);
         emitter.emit_import(index, pkg, None);
-        emitter.emit_load(
-            EntIndex::PackageMember(pkg, main_ident),
-            Some((pairs, fkey)),
-            ValueType::Function,
-            None,
-        );
-        emitter.emit_pre_call(None);
-        emitter.emit_call(false, None);
+        if let Some(ident) = main_ident {
+            emitter.emit_load(
+                EntIndex::PackageMember(pkg, ident),
+                Some((pairs, fkey)),
+                ValueType::Function,
+                None,
+            );
+            emitter.emit_pre_call(None);
+            emitter.emit_call(CallStyle::Default, false, None);
+        }
         emitter.emit_return(None);
         *f.as_function()
     }
@@ -73,6 +82,7 @@ impl<'a> EntryGen<'a> {
         checker_result: &HashMap<TCPackageKey, TypeInfo>,
         main_pkg: TCPackageKey,
         main_ident: IdentKey,
+        natives: &NativeFuncs,
     ) -> ByteCode {
         let mut main_pkg_idx = None;
         for (&tcpkg, _) in checker_result.iter() {
@@ -86,7 +96,7 @@ impl<'a> EntryGen<'a> {
                 main_pkg_idx = Some(index);
             }
         }
-        let mut type_cache: TypeCache = HashMap::new();
+        let mut type_cache = TypeCache::new();
         let mut pairs = PkgVarPairs::new();
         for (i, (tcpkg, ti)) in checker_result.iter().enumerate() {
             let mut cgen = CodeGen::new(
@@ -102,16 +112,30 @@ impl<'a> EntryGen<'a> {
                 self.blank_ident,
             );
             cgen.gen_with_files(&ti.ast_files, *tcpkg, i as OpIndex);
+            if let Some(funcs) = natives.get(self.tc_objs.pkgs[*tcpkg].path()) {
+                cgen.add_native_funcs(*tcpkg, funcs);
+            }
             pairs.append_from_util(cgen.pkg_util());
         }
         let index = main_pkg_idx.unwrap();
-        let entry =
-            self.gen_entry_func(self.packages[index as usize], index, main_ident, &mut pairs);
+        let pkey = self.packages[index as usize];
+        let init = self.gen_entry_func(pkey, index, None, &mut pairs);
+        // a package without a main function can still be called into
+        let has_main = self.objects.packages[pkey]
+            .get_member_index(&self.ast_objs.idents[main_ident].name)
+            .is_some();
+        let entry = if has_main {
+            Some(self.gen_entry_func(pkey, index, Some(main_ident), &mut pairs))
+        } else {
+            None
+        };
         pairs.patch_index(self.ast_objs, &mut self.objects);
+        type_cache.patch_promoted(&mut self.objects);
         ByteCode {
             objects: self.objects,
             packages: self.packages,
             ifaces: self.iface_mapping.into_result(),
+            init: init,
             entry: entry,
         }
     }
@@ -120,6 +144,7 @@ impl<'a> EntryGen<'a> {
 pub fn parse_check_gen(
     path: &str,
     config: &Config,
+    natives: &NativeFuncs,
     fset: &mut FileSet,
     el: &ErrorList,
 ) -> Result<ByteCode, usize> {
@@ -139,6 +164,6 @@ pub fn parse_check_gen(
         let blank_ident = asto.idents.insert(Ident::blank(0));
         let main_ident = asto.idents.insert(Ident::with_str(0, "main"));
         let gen = EntryGen::new(asto, tco, blank_ident);
-        Ok(gen.gen(results, main_pkg.unwrap(), main_ident))
+        Ok(gen.gen(results, main_pkg.unwrap(), main_ident, natives))
     }
 }
@@ -61,7 +61,7 @@ impl IfaceMapping {
         }
         let s = lookup.meta_from_tc(i_s.1.unwrap(), objs);
         let ifields = match &objs.metas[i.as_non_ptr()] {
-            MetadataType::Named(_, iface) => match &objs.metas[iface.as_non_ptr()] {
+            MetadataType::Named(_, iface, _) => match &objs.metas[iface.as_non_ptr()] {
                 MetadataType::Interface(m) => m,
                 _ => unreachable!(),
             },
@@ -74,7 +74,7 @@ impl IfaceMapping {
             _ => unreachable!(),
         };
         let methods = match &objs.metas[named] {
-            MetadataType::Named(m, _) => Some(m),
+            MetadataType::Named(m, _, _) => Some(m),
             // primitive types
             _ => None,
         };
@@ -135,7 +135,9 @@ impl<'a> PkgUtil<'a> {
                                 match spec {
                                     Spec::Value(v) => {
                                         let name = &self.ast_objs.idents[v.names[0]].name;
-                                        let order = orders[name];
+                                        // vars without initializers are not in init_order,
+                                        // they don't depend on anything
+                                        let order = orders.get(name).map_or(0, |i| i + 1);
                                         decls.push((v.clone(), order));
                                     }
                                     _ => unimplemented!(),
@@ -1,17 +1,45 @@
 #![allow(dead_code)]
+use super::emit::{CallStyle, Emitter, LeftHandSide};
 use goscript_parser::ast::Node;
 use goscript_parser::ast::{Expr, NodeId};
 use goscript_parser::objects::IdentKey;
 use goscript_types::{
-    BasicType, ConstValue, EntityType, ObjKey, OperandMode, PackageKey as TCPackageKey, TCObjects,
-    Type, TypeInfo, TypeKey as TCTypeKey,
+    lookup_field_or_method, BasicType, ChanDir, ConstValue, EntityType, LookupResult, ObjKey,
+    OperandMode, PackageKey as TCPackageKey, TCObjects, Type, TypeInfo, TypeKey as TCTypeKey,
 };
-use goscript_vm::instruction::{OpIndex, ValueType};
+use goscript_vm::instruction::{OpIndex, Opcode, ValueType};
 use goscript_vm::metadata::*;
+use goscript_vm::null_key;
+use goscript_vm::objects::EntIndex;
 use goscript_vm::value::*;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// TypeCache holds the metadata of the checked types, it's shared by all the packages.
+/// The methods promoted from embedded fields are generated wrappers, the methods they
+/// call are patched in after all the packages are generated.
+pub struct TypeCache {
+    metas: HashMap<TCTypeKey, GosMetadata>,
+    // the wrapper, the index of the const for the method and the method
+    promoted: Vec<(FunctionKey, OpIndex, Rc<RefCell<MethodDesc>>)>,
+}
 
-pub type TypeCache = HashMap<TCTypeKey, GosMetadata>;
+impl TypeCache {
+    pub fn new() -> TypeCache {
+        TypeCache {
+            metas: HashMap::new(),
+            promoted: vec![],
+        }
+    }
+
+    pub fn patch_promoted(&self, objs: &mut VMObjects) {
+        for (wrapper, index, method) in self.promoted.iter() {
+            let func = method.borrow().func.unwrap();
+            objs.functions[*wrapper].consts[*index as usize] = GosValue::Function(func);
+        }
+    }
+}
 
 pub struct TypeLookup<'a> {
     tc_objs: &'a TCObjects,
@@ -139,6 +167,19 @@ impl<'a> TypeLookup<'a> {
         self.range_tc_types(typ)
     }
 
+    /// get_expr_value_count returns the number of values the expression 'e'
+    /// leaves on the stack, which is 0 for a call without results
+    pub fn get_expr_value_count(&self, e: &Expr) -> usize {
+        let tv = self.ti.types.get(&e.id()).unwrap();
+        match tv.mode {
+            OperandMode::NoValue => 0,
+            _ => match &self.tc_objs.types[tv.typ] {
+                Type::Tuple(detail) => detail.vars().len(),
+                _ => 1,
+            },
+        }
+    }
+
     pub fn get_return_tc_types(&mut self, e: &Expr) -> Vec<TCTypeKey> {
         let typ = self.ti.types.get(&e.id()).unwrap().typ;
         self.return_tc_types(typ)
@@ -151,12 +192,226 @@ impl<'a> TypeLookup<'a> {
         (t0, t1)
     }
 
+    /// named_type_name returns the package qualified name of a named type, like "main.T"
+    fn named_type_name(&self, typ: TCTypeKey) -> String {
+        let detail = self.tc_objs.types[typ].try_as_named().unwrap();
+        match detail.obj() {
+            Some(okey) => {
+                let obj = &self.tc_objs.lobjs[*okey];
+                let pkg_name = obj.pkg().and_then(|p| self.tc_objs.pkgs[p].name().clone());
+                match pkg_name {
+                    Some(pkg) => format!("{}.{}", pkg, obj.name()),
+                    None => obj.name().clone(),
+                }
+            }
+            None => String::new(),
+        }
+    }
+
     pub fn meta_from_tc(&mut self, typ: TCTypeKey, vm_objs: &mut VMObjects) -> GosMetadata {
-        if !self.types_cache.contains_key(&typ) {
+        if !self.types_cache.metas.contains_key(&typ) {
             let val = self.meta_from_tc_impl(typ, vm_objs);
-            self.types_cache.insert(typ, val);
+            self.types_cache.metas.insert(typ, val);
+            // the wrappers may refer to the type, so it's added after being cached
+            if let Type::Named(_) = &self.tc_objs.types[typ] {
+                self.add_promoted_methods(typ, val, vm_objs);
+            }
+        }
+        self.types_cache.metas.get(&typ).unwrap().clone()
+    }
+
+    /// add_promoted_methods adds the methods a named type gets from its embedded
+    /// fields, each of them is a wrapper calling the method on the embedded field
+    fn add_promoted_methods(&mut self, typ: TCTypeKey, md: GosMetadata, vm_objs: &mut VMObjects) {
+        let tc_objs = self.tc_objs;
+        let mut candidates = vec![];
+        self.embedded_methods(typ, &mut HashSet::new(), &mut candidates);
+        let mut names = HashSet::new();
+        for okey in candidates.into_iter() {
+            let (pkg, name) = (tc_objs.lobjs[okey].pkg(), tc_objs.lobjs[okey].name());
+            if !names.insert(name.clone()) {
+                continue;
+            }
+            // the methods with pointer receivers are only promoted to the pointer type
+            let (pointer_recv, result) =
+                match lookup_field_or_method(typ, false, pkg, name, tc_objs) {
+                    LookupResult::BadMethodReceiver => {
+                        (true, lookup_field_or_method(typ, true, pkg, name, tc_objs))
+                    }
+                    r => (false, r),
+                };
+            match result {
+                LookupResult::Entry(target, indices, _)
+                    if indices.len() > 1 && tc_objs.lobjs[target].entity_type().is_func() =>
+                {
+                    md.add_method(name.clone(), pointer_recv, &mut vm_objs.metas);
+                    let wrapper =
+                        self.gen_promoted_wrapper(typ, md, pointer_recv, target, &indices, vm_objs);
+                    md.set_method_code(name, wrapper, &mut vm_objs.metas);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// embedded_methods collects the methods of the embedded fields of 'typ' and
+    /// of their embedded fields, some of them may be shadowed
+    fn embedded_methods(
+        &self,
+        typ: TCTypeKey,
+        seen: &mut HashSet<TCTypeKey>,
+        result: &mut Vec<ObjKey>,
+    ) {
+        let fields = match self.tc_objs.types[typ].underlying_val(self.tc_objs) {
+            Type::Struct(detail) => detail.fields(),
+            _ => return,
+        };
+        for f in fields.iter() {
+            let fobj = &self.tc_objs.lobjs[*f];
+            if !fobj.var_embedded() {
+                continue;
+            }
+            let ftype = self.deref_tc(fobj.typ().unwrap());
+            if !seen.insert(ftype) {
+                continue;
+            }
+            match &self.tc_objs.types[ftype] {
+                Type::Named(detail) => result.extend(detail.methods().iter()),
+                _ => {}
+            }
+            match self.tc_objs.types[ftype].underlying_val(self.tc_objs) {
+                Type::Interface(detail) => {
+                    result.extend(detail.all_methods().as_ref().unwrap().iter())
+                }
+                _ => self.embedded_methods(ftype, seen, result),
+            }
+        }
+    }
+
+    /// gen_promoted_wrapper generates the function that loads the embedded field
+    /// along the path of 'indices' and calls the method 'target' on it
+    fn gen_promoted_wrapper(
+        &mut self,
+        typ: TCTypeKey,
+        md: GosMetadata,
+        pointer_recv: bool,
+        target: ObjKey,
+        indices: &[usize],
+        vm_objs: &mut VMObjects,
+    ) -> FunctionKey {
+        let tc_objs = self.tc_objs;
+        let tobj = &tc_objs.lobjs[target];
+        let name = tobj.name();
+        // the embedded fields on the path, and the type the method belongs to
+        let mut fields = vec![];
+        let mut owner = typ;
+        for i in indices[..indices.len() - 1].iter() {
+            let base = self.deref_tc(owner);
+            let st = tc_objs.types[base].underlying_val(tc_objs);
+            let f = st.try_as_struct().unwrap().fields()[*i];
+            owner = tc_objs.lobjs[f].typ().unwrap();
+            fields.push((*i as OpIndex, self.value_type_from_tc(owner)));
+        }
+        let owner_meta = self.meta_from_tc(self.deref_tc(owner), vm_objs);
+        let in_iface = owner_meta
+            .get_underlying(&vm_objs.metas)
+            .get_value_type(&vm_objs.metas)
+            == ValueType::Interface;
+        let (target_ptr_recv, method) = if in_iface {
+            (false, owner_meta.iface_method_index(name, &vm_objs.metas))
+        } else {
+            (
+                tobj.entity_type().func_has_ptr_recv(),
+                owner_meta.method_index(name, &vm_objs.metas),
+            )
+        };
+
+        let sig = self.meta_from_tc(tobj.typ().unwrap(), vm_objs);
+        let s = match &vm_objs.metas[sig.as_non_ptr()] {
+            MetadataType::Signature(s) => s.clone(),
+            _ => unreachable!(),
+        };
+        let param_types: Vec<ValueType> = s
+            .params
+            .iter()
+            .map(|x| x.get_value_type(&vm_objs.metas))
+            .collect();
+        let result_types: Vec<ValueType> = s
+            .results
+            .iter()
+            .map(|x| x.get_value_type(&vm_objs.metas))
+            .collect();
+        let recv = if pointer_recv { md.ptr_to() } else { md };
+        let fmeta = GosMetadata::new_sig(
+            Some(recv),
+            s.params,
+            s.results,
+            s.variadic,
+            &mut vm_objs.metas,
+        );
+        let f = GosValue::new_function(null_key!(), fmeta, vm_objs, false);
+        let fkey = *f.as_function();
+        let func = &mut vm_objs.functions[fkey];
+        // the results, the receiver and the params
+        for _ in 0..(result_types.len() + 1 + param_types.len()) {
+            func.add_local(None);
+        }
+        let ret_count = result_types.len() as OpIndex;
+        let mut t = if pointer_recv {
+            ValueType::Pointer
+        } else {
+            ValueType::Named
+        };
+        Emitter::new(func).emit_load(EntIndex::LocalVar(ret_count), None, t, None);
+        for (n, (i, ftype)) in fields.iter().enumerate() {
+            let last = n == fields.len() - 1;
+            if last && target_ptr_recv && *ftype != ValueType::Pointer {
+                // the method takes the address of the embedded field
+                if t == ValueType::Pointer {
+                    func.emit_code_with_type(Opcode::DEREF, t, None);
+                }
+                func.emit_code_with_type_imm(Opcode::REF_STRUCT_FIELD, ValueType::Named, *i, None);
+                t = ValueType::Pointer;
+            } else {
+                Emitter::new(func).emit_load_struct_field(*i, t, None);
+                t = *ftype;
+            }
+        }
+        if in_iface {
+            func.emit_code_with_type_imm(Opcode::BIND_INTERFACE_METHOD, t, method, None);
+        } else {
+            if !target_ptr_recv && t == ValueType::Pointer {
+                func.emit_code_with_type(Opcode::DEREF, t, None);
+                t = ValueType::Named;
+            }
+            let index: OpIndex = func.add_const(None, GosValue::new_nil()).into();
+            func.emit_code_with_type_imm(Opcode::BIND_METHOD, t, index, None);
+            let desc = owner_meta.get_method(method, &vm_objs.metas);
+            self.types_cache.promoted.push((fkey, index, desc));
+        }
+        Emitter::new(func).emit_pre_call(None);
+        for (i, t) in param_types.iter().enumerate() {
+            let index = EntIndex::LocalVar(ret_count + 1 + i as OpIndex);
+            Emitter::new(func).emit_load(index, None, *t, None);
+        }
+        // the variadic params are packed already
+        Emitter::new(func).emit_call(CallStyle::Default, s.variadic.is_some(), None);
+        for (i, t) in result_types.iter().enumerate() {
+            let lhs = LeftHandSide::Primitive(EntIndex::LocalVar(i as OpIndex));
+            Emitter::new(func).emit_store(&lhs, i as OpIndex - ret_count, None, None, *t, None);
+        }
+        if ret_count > 0 {
+            Emitter::new(func).emit_pop(ret_count, None);
+        }
+        Emitter::new(func).emit_return(None);
+        fkey
+    }
+
+    fn deref_tc(&self, typ: TCTypeKey) -> TCTypeKey {
+        match &self.tc_objs.types[typ] {
+            Type::Pointer(detail) => detail.base(),
+            _ => typ,
         }
-        self.types_cache.get(&typ).unwrap().clone()
     }
 
     pub fn get_sig_params_tc_types(
@@ -174,14 +429,22 @@ impl<'a> TypeLookup<'a> {
             .collect();
         let variadic = if sig.variadic() {
             let slice_key = *params.last().unwrap();
-            let slice = &self.tc_objs.types[slice_key].try_as_slice().unwrap();
-            Some((slice_key, slice.elem()))
+            // it's a string for the special case of append([]byte, string...)
+            let elem = self.tc_objs.types[slice_key]
+                .try_as_slice()
+                .map_or(slice_key, |s| s.elem());
+            Some((slice_key, elem))
         } else {
             None
         };
         (params, variadic)
     }
 
+    pub fn get_chan_elem_tc_type(&self, chan: TCTypeKey) -> TCTypeKey {
+        let typ = &self.tc_objs.types[chan].underlying_val(self.tc_objs);
+        typ.try_as_chan().unwrap().elem()
+    }
+
     pub fn get_sig_returns_tc_types(&mut self, func: TCTypeKey) -> Vec<TCTypeKey> {
         let typ = &self.tc_objs.types[func].underlying_val(self.tc_objs);
         let sig = typ.try_as_signature().unwrap();
@@ -208,7 +471,7 @@ impl<'a> TypeLookup<'a> {
             BasicType::Float32 => vm_objs.metadata.mfloat32,
             BasicType::Float64 | BasicType::UntypedFloat => vm_objs.metadata.mfloat64,
             BasicType::Complex64 => vm_objs.metadata.mcomplex64,
-            BasicType::Complex128 => vm_objs.metadata.mcomplex128,
+            BasicType::Complex128 | BasicType::UntypedComplex => vm_objs.metadata.mcomplex128,
             BasicType::Str | BasicType::UntypedString => vm_objs.metadata.mstr,
             BasicType::UntypedNil => GosMetadata::Untyped,
             _ => {
@@ -251,7 +514,7 @@ impl<'a> TypeLookup<'a> {
                 let (i, _) = val.to_int().int_as_u64();
                 GosValue::Uint(i as usize)
             }
-            BasicType::Uint8 => {
+            BasicType::Uint8 | BasicType::Byte => {
                 let (i, _) = val.to_int().int_as_u64();
                 GosValue::Uint8(i as u8)
             }
@@ -276,11 +539,11 @@ impl<'a> TypeLookup<'a> {
                 GosValue::Float64(f.into())
             }
             BasicType::Complex64 => {
-                let (cr, ci, _) = val.complex_as_complex64();
+                let (cr, ci, _) = val.to_complex().complex_as_complex64();
                 GosValue::Complex64(cr, ci)
             }
-            BasicType::Complex128 => {
-                let (cr, ci, _) = val.complex_as_complex128();
+            BasicType::Complex128 | BasicType::UntypedComplex => {
+                let (cr, ci, _) = val.to_complex().complex_as_complex128();
                 GosValue::Complex128(Box::new((cr, ci)))
             }
             BasicType::Str | BasicType::UntypedString => GosValue::new_str(val.str_as_string()),
@@ -305,6 +568,15 @@ impl<'a> TypeLookup<'a> {
                 let vtype = self.meta_from_tc(detail.elem(), vm_objs);
                 GosMetadata::new_map(ktype, vtype, &mut vm_objs.metas)
             }
+            Type::Chan(detail) => {
+                let typ = match detail.dir() {
+                    ChanDir::RecvOnly => ChannelType::Recv,
+                    ChanDir::SendOnly => ChannelType::Send,
+                    ChanDir::SendRecv => ChannelType::SendRecv,
+                };
+                let vmeta = self.meta_from_tc(detail.elem(), vm_objs);
+                GosMetadata::new_channel(typ, vmeta, &mut vm_objs.metas)
+            }
             Type::Struct(detail) => {
                 let fields = self.get_fields(detail.fields(), vm_objs);
                 GosMetadata::new_struct(fields, vm_objs)
@@ -350,15 +622,16 @@ impl<'a> TypeLookup<'a> {
                 inner.ptr_to()
             }
             Type::Named(detail) => {
+                let name = self.named_type_name(typ);
                 // put a place holder there to avoid recursion
                 let mdph = GosMetadata::new(
-                    MetadataType::Named(Methods::new(), GosMetadata::Untyped),
+                    MetadataType::Named(Methods::new(), GosMetadata::Untyped, name.clone()),
                     &mut vm_objs.metas,
                 );
-                self.types_cache.insert(typ, mdph);
+                self.types_cache.metas.insert(typ, mdph);
                 let underlying = self.meta_from_tc(detail.underlying(), vm_objs);
-                self.types_cache.remove(&typ);
-                let md = GosMetadata::new_named(underlying, &mut vm_objs.metas);
+                self.types_cache.metas.remove(&typ);
+                let md = GosMetadata::new_named(name, underlying, &mut vm_objs.metas);
                 for key in detail.methods().iter() {
                     let mobj = &self.tc_objs.lobjs[*key];
                     md.add_method(
@@ -400,7 +673,7 @@ impl<'a> TypeLookup<'a> {
                 BasicType::Float32 => ValueType::Float32,
                 BasicType::Float64 | BasicType::UntypedFloat => ValueType::Float64,
                 BasicType::Complex64 => ValueType::Complex64,
-                BasicType::Complex128 => ValueType::Complex128,
+                BasicType::Complex128 | BasicType::UntypedComplex => ValueType::Complex128,
                 BasicType::Str | BasicType::UntypedString => ValueType::Str,
                 BasicType::UntypedNil => ValueType::Nil,
                 _ => {
@@ -411,6 +684,7 @@ impl<'a> TypeLookup<'a> {
             Type::Array(_) => ValueType::Array,
             Type::Slice(_) => ValueType::Slice,
             Type::Map(_) => ValueType::Map,
+            Type::Chan(_) => ValueType::Channel,
             Type::Struct(_) => ValueType::Struct,
             Type::Interface(_) => ValueType::Interface,
             Type::Signature(_) => ValueType::Closure,
@@ -432,6 +706,8 @@ impl<'a> TypeLookup<'a> {
             },
             Type::Slice(detail) => [typ, t_int, detail.elem()],
             Type::Map(detail) => [typ, detail.key(), detail.elem()],
+            // only the first iteration variable is allowed for channels
+            Type::Chan(detail) => [typ, detail.elem(), detail.elem()],
             _ => {
                 dbg!(&self.tc_objs.types[typ]);
                 unreachable!()
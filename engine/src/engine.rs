@@ -2,60 +2,165 @@ extern crate goscript_codegen as cg;
 extern crate goscript_parser as fe;
 extern crate goscript_types as types;
 extern crate goscript_vm as vm;
-use super::std::fmt;
+use super::std::{self as gos_std, fmt};
+use super::virtual_pkg::{self, NativePackage, VirtualPkgs};
+use std::cell::RefCell;
+use std::rc::Rc;
+pub use types::{EmbeddedSource, FsSource, LayeredSource, MemSource, SourceProvider};
+pub use vm::gc::MemStats;
+pub use vm::value::GosValue;
+pub use vm::vm::{ErrorKind, GosVM, InterruptHandle, Limits, RuntimeError, StepResult};
 
 pub struct Config {
     // working directory
     pub work_dir: Option<String>,
-    // base path for non-local imports
+    // base path for non-local imports, the packages in it take precedence
+    // over the std packages built into the engine
     pub base_path: Option<String>,
     // print debug info in parser
     pub trace_parser: bool,
     // print debug info in checker
     pub trace_checker: bool,
-    // proint debug info for vm
+    // print the compile errors, they are returned in the RuntimeError anyway
     pub trace_vm: bool,
 }
 
 pub struct Engine {
     config: Config,
     ffi: vm::ffi::FfiFactory,
+    virtual_pkgs: Rc<RefCell<VirtualPkgs>>,
+    source: Rc<dyn SourceProvider>,
+    limits: Limits,
 }
 
 impl Engine {
     pub fn new(config: Config) -> Engine {
+        let vpkgs = Rc::new(RefCell::new(VirtualPkgs::default()));
         let mut ffi = vm::ffi::FfiFactory::new();
-        ffi.register("fmt", Box::new(fmt::Fmt::new));
+        ffi.register("fmt", fmt::Fmt::new_ctor());
+        ffi.register(
+            virtual_pkg::GLOBALS_FFI,
+            VirtualPkgs::new_globals_ctor(vpkgs.clone()),
+        );
         Engine {
             config: config,
             ffi: ffi,
+            virtual_pkgs: vpkgs,
+            source: Rc::new(FsSource),
+            limits: Limits::default(),
         }
     }
 
-    pub fn run(&self, path: &str) -> usize {
-        let config = types::Config {
-            work_dir: self.config.work_dir.clone(),
-            base_path: self.config.base_path.clone(),
-            trace_parser: self.config.trace_parser,
-            trace_checker: self.config.trace_checker,
+    /// load compiles the program at 'path' and initializes its packages, the
+    /// returned VM can then run the main function or call any other function
+    pub fn load(&self, path: &str) -> Result<GosVM<'_>, RuntimeError> {
+        self.load_with(path, self.source.clone())
+    }
+
+    /// load_source is like load but the main package is the single file 'src',
+    /// with the 'path' relative to the working directory, the packages it
+    /// imports are read from the source provider of the engine
+    pub fn load_source(&self, path: &str, src: &str) -> Result<GosVM<'_>, RuntimeError> {
+        let path = if path.starts_with("./") || path.starts_with("../") {
+            path.to_string()
+        } else {
+            format!("./{}", path)
         };
+        let wd = self.types_config(self.source.clone()).get_working_dir();
+        let wd = wd.map_err(|e| RuntimeError::new(ErrorKind::Compile, e.to_string()))?;
+        let mut mem = MemSource::new();
+        mem.add_file(wd.join(&path), src.to_string());
+        let source = LayeredSource::new(vec![Rc::new(mem), self.source.clone()]);
+        self.load_with(&path, Rc::new(source))
+    }
+
+    fn load_with(
+        &self,
+        path: &str,
+        source: Rc<dyn SourceProvider>,
+    ) -> Result<GosVM<'_>, RuntimeError> {
+        let config = self.types_config(source);
         let mut fs = fe::FileSet::new();
         let el = &mut fe::errors::ErrorList::new();
-        let code = cg::entry::parse_check_gen(path, &config, &mut fs, el);
-        if let Ok(bc) = code {
-            let mut vm = vm::vm::GosVM::new(bc);
-            vm.run(&self.ffi, Some(&fs));
-            0
-        } else {
-            if self.config.trace_vm {
+        let natives = self.virtual_pkgs.borrow().native_funcs();
+        let code = cg::entry::parse_check_gen(path, &config, &natives, &mut fs, el);
+        match code {
+            Ok(bc) => {
+                let mut vm = GosVM::new(bc, &self.ffi, Some(fs));
+                vm.set_limits(self.limits.clone());
+                vm.init()?;
+                Ok(vm)
+            }
+            Err(_) => {
                 el.sort();
-                print!("{}", el);
+                if self.config.trace_vm {
+                    print!("{}", el);
+                }
+                Err(RuntimeError::new(ErrorKind::Compile, el.to_string()))
             }
-            code.unwrap_err()
         }
     }
 
+    pub fn run(&self, path: &str) -> Result<Vec<GosValue>, RuntimeError> {
+        self.load(path)?.run()
+    }
+
+    pub fn run_source(&self, path: &str, src: &str) -> Result<Vec<GosValue>, RuntimeError> {
+        self.load_source(path, src)?.run()
+    }
+
+    /// set_source_provider sets where the source files are read from, which
+    /// is the file system by default
+    pub fn set_source_provider(&mut self, source: Rc<dyn SourceProvider>) {
+        self.source = source;
+    }
+
+    /// set_limits sets the limits of the VMs loaded after it, the
+    /// initialization of the packages counts too
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
     pub fn register_extension(&mut self, name: &'static str, ctor: Box<vm::ffi::Ctor>) {
         self.ffi.register(name, ctor);
     }
+
+    /// define_global defines a variable 'name' with the value 'val' in the
+    /// package 'pkg', which the scripts can import by the name, the package is
+    /// created by the first global in it. 'val' must be of a basic type, the
+    /// type of the variable. 'pkg' and 'name' must be identifiers, and 'name'
+    /// can't be a predeclared type, or a function of the package.
+    pub fn define_global(&mut self, pkg: &str, name: &str, val: GosValue) -> Result<(), String> {
+        self.virtual_pkgs.borrow_mut().define(pkg, name, val, false)
+    }
+
+    /// define_const is like define_global but defines a typed constant
+    pub fn define_const(&mut self, pkg: &str, name: &str, val: GosValue) -> Result<(), String> {
+        self.virtual_pkgs.borrow_mut().define(pkg, name, val, true)
+    }
+
+    /// register_package makes the native package 'pkg' importable by its
+    /// name, it can have globals too
+    pub fn register_package(&mut self, pkg: NativePackage) -> Result<(), String> {
+        self.virtual_pkgs.borrow_mut().add_native(pkg)
+    }
+
+    /// set_user_data gives the FFIs of this engine access to 'data' through
+    /// FfiCtx::user_data, one value is kept for each type
+    pub fn set_user_data<T: 'static>(&mut self, data: T) {
+        self.ffi.set_user_data(data);
+    }
+
+    fn types_config(&self, source: Rc<dyn SourceProvider>) -> types::Config {
+        types::Config {
+            work_dir: self.config.work_dir.clone(),
+            base_path: self.config.base_path.clone(),
+            trace_parser: self.config.trace_parser,
+            trace_checker: self.config.trace_checker,
+            virtual_pkgs: self.virtual_pkgs.borrow().sources(),
+            native_pkgs: self.virtual_pkgs.borrow().native_sigs(),
+            source: source,
+            std_source: Some(Rc::new(EmbeddedSource::new(gos_std::SOURCES))),
+        }
+    }
 }
@@ -1,4 +1,6 @@
 mod engine;
+mod virtual_pkg;
 mod std;
 
 pub use engine::*;
+pub use virtual_pkg::{GoResults, GoType, NativeFn, NativePackage};
@@ -1,27 +1,14 @@
-use goscript_vm::ffi::{Ffi, FfiResult};
+use goscript_macro::goscript_ffi;
 use goscript_vm::value::GosValue;
-use std::cell::RefCell;
-use std::rc::Rc;
 
+#[derive(Default)]
 pub struct Fmt {}
 
-impl Ffi for Fmt {
-    fn call(&self, func_name: &str, params: Vec<GosValue>) -> Vec<GosValue> {
-        if func_name == "println" {
-            self.println(params)
-        }
-        vec![]
-    }
-}
-
+#[goscript_ffi]
 impl Fmt {
-    pub fn new(_v: Vec<GosValue>) -> FfiResult<Rc<RefCell<dyn Ffi>>> {
-        Ok(Rc::new(RefCell::new(Fmt {})))
-    }
-
-    fn println(&self, params: Vec<GosValue>) {
-        let vec = params[0].as_slice().0.get_vec();
-        let strs: Vec<String> = vec
+    #[variadic]
+    fn println(&self, a: Vec<GosValue>) {
+        let strs: Vec<String> = a
             .iter()
             .map(|x| {
                 if x.is_nil() {
@@ -34,3 +21,14 @@ impl Fmt {
         println!("{}", strs.join(", "));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fmt_interface() {
+        let gos = include_str!("../../std/fmt/fmt.gos");
+        assert!(gos.contains(Fmt::GOS_INTERFACE));
+    }
+}
@@ -1 +1,34 @@
 pub mod fmt;
+
+/// the sources of the std packages, by their paths in the std directory
+pub const SOURCES: &[(&str, &str)] = &[
+    (
+        "errors/errors.gos",
+        include_str!("../../std/errors/errors.gos"),
+    ),
+    ("fmt/fmt.gos", include_str!("../../std/fmt/fmt.gos")),
+    ("math/math.gos", include_str!("../../std/math/math.gos")),
+    ("math/math2.gos", include_str!("../../std/math/math2.gos")),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_sources_complete() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("std");
+        for pkg in std::fs::read_dir(&root).unwrap() {
+            for file in std::fs::read_dir(pkg.unwrap().path()).unwrap() {
+                let path = file.unwrap().path();
+                let rel = path.strip_prefix(&root).unwrap().to_string_lossy();
+                assert!(
+                    SOURCES.iter().any(|(p, _)| *p == rel),
+                    "{} not embedded",
+                    rel
+                );
+            }
+        }
+    }
+}
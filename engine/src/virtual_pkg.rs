@@ -0,0 +1,505 @@
+//! Packages provided by the host instead of source files, they are imported
+//! like any other package:
+//!  - the constants are literals in a generated source
+//!  - the variables are initialized in the generated source by an FFI
+//!    returning the values kept by the engine
+//!  - the functions are declared by the type checker from their signatures,
+//!    which are derived from the Rust types, and the calls go straight to the
+//!    native functions
+use goscript_codegen::entry::NativeFuncs;
+use goscript_parser::token::Token;
+use goscript_types::{BasicType, NativeFunc, NativeType};
+use goscript_vm::ffi::{Ctor, Ffi, FfiCtx, FfiFn, FfiMethods, FfiResult};
+use goscript_vm::value::GosValue;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// the name the FFI of the variables is registered with
+pub const GLOBALS_FFI: &str = "__globals";
+
+/// the names declared by the generated source, and the predeclared ones it
+/// uses, which the globals and the functions can't take
+const RESERVED: &[&str] = &[
+    "__globals",
+    "ffiGlobals",
+    "ffi",
+    "error",
+    "true",
+    "false",
+    "bool",
+    "int",
+    "int8",
+    "int16",
+    "int32",
+    "int64",
+    "uint",
+    "uint8",
+    "uint16",
+    "uint32",
+    "uint64",
+    "float32",
+    "float64",
+    "complex64",
+    "complex128",
+    "string",
+];
+
+/// GoType is implemented for the Rust types the native functions can take and
+/// return, it gives the Go type they are converted from or to
+pub trait GoType {
+    fn go_type() -> NativeType;
+}
+
+macro_rules! impl_go_type {
+    ($($t:ty => $basic:ident),*) => {
+        $(
+            impl GoType for $t {
+                fn go_type() -> NativeType {
+                    NativeType::Basic(BasicType::$basic)
+                }
+            }
+        )*
+    };
+}
+
+impl_go_type!(
+    bool => Bool,
+    isize => Int,
+    i8 => Int8,
+    i16 => Int16,
+    i32 => Int32,
+    i64 => Int64,
+    usize => Uint,
+    u8 => Uint8,
+    u16 => Uint16,
+    u32 => Uint32,
+    u64 => Uint64,
+    f32 => Float32,
+    f64 => Float64,
+    String => Str
+);
+
+/// None is nil, so Option is for slices and maps
+impl<T: GoType> GoType for Option<T> {
+    fn go_type() -> NativeType {
+        T::go_type()
+    }
+}
+
+impl<T: GoType> GoType for Vec<T> {
+    fn go_type() -> NativeType {
+        NativeType::Slice(Box::new(T::go_type()))
+    }
+}
+
+impl<K: GoType, V: GoType> GoType for HashMap<K, V> {
+    fn go_type() -> NativeType {
+        NativeType::Map(Box::new(K::go_type()), Box::new(V::go_type()))
+    }
+}
+
+/// GoResults gives the Go result types of the return type of a native
+/// function, a tuple is for multiple results and an FfiResult adds an error
+pub trait GoResults {
+    fn go_results() -> Vec<NativeType>;
+}
+
+impl GoResults for () {
+    fn go_results() -> Vec<NativeType> {
+        vec![]
+    }
+}
+
+impl<T: GoType> GoResults for T {
+    fn go_results() -> Vec<NativeType> {
+        vec![T::go_type()]
+    }
+}
+
+macro_rules! impl_tuple_go_results {
+    ($($t:ident),+) => {
+        impl<$($t: GoType),+> GoResults for ($($t,)+) {
+            fn go_results() -> Vec<NativeType> {
+                vec![$($t::go_type()),+]
+            }
+        }
+    };
+}
+
+impl_tuple_go_results!(A);
+impl_tuple_go_results!(A, B);
+impl_tuple_go_results!(A, B, C);
+impl_tuple_go_results!(A, B, C, D);
+
+impl<T: GoResults> GoResults for FfiResult<T> {
+    fn go_results() -> Vec<NativeType> {
+        let mut results = T::go_results();
+        results.push(NativeType::Error);
+        results
+    }
+}
+
+/// NativeFn gives the Go signature of a Rust function or closure that can be
+/// added to a NativePackage, 'Args' is the tuple of its parameter types
+pub trait NativeFn<Args> {
+    fn params() -> Vec<NativeType>;
+
+    fn results() -> Vec<NativeType>;
+}
+
+macro_rules! impl_native_fn {
+    ($($t:ident),*) => {
+        impl<F, R $(, $t)*> NativeFn<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R,
+            R: GoResults,
+            $($t: GoType,)*
+        {
+            fn params() -> Vec<NativeType> {
+                vec![$($t::go_type()),*]
+            }
+
+            fn results() -> Vec<NativeType> {
+                R::go_results()
+            }
+        }
+    };
+}
+
+impl_native_fn!();
+impl_native_fn!(A);
+impl_native_fn!(A, B);
+impl_native_fn!(A, B, C);
+impl_native_fn!(A, B, C, D);
+impl_native_fn!(A, B, C, D, E);
+impl_native_fn!(A, B, C, D, E, G);
+
+/// NativePackage is a package of functions implemented in Rust, which the
+/// scripts import by its name after it's registered with
+/// Engine::register_package
+pub struct NativePackage {
+    name: String,
+    funcs: BTreeMap<String, NativeFunc>,
+    methods: FfiMethods,
+}
+
+impl NativePackage {
+    pub fn new(name: &str) -> NativePackage {
+        NativePackage {
+            name: name.to_string(),
+            funcs: BTreeMap::new(),
+            methods: FfiMethods::new(),
+        }
+    }
+
+    /// func adds the function 'name' implemented by 'f', its signature in Go
+    /// is given by the types of 'f': the parameters and the results are
+    /// converted as in FfiMethods, and an FfiResult returns an error as the
+    /// last result
+    pub fn func<Args, F>(&mut self, name: &'static str, f: F) -> Result<(), String>
+    where
+        F: FfiFn<Args> + NativeFn<Args> + 'static,
+    {
+        self.add(name, f, false)
+    }
+
+    /// variadic_func is like func but the last parameter of 'f', which must
+    /// be a Vec, takes the variadic arguments
+    pub fn variadic_func<Args, F>(&mut self, name: &'static str, f: F) -> Result<(), String>
+    where
+        F: FfiFn<Args> + NativeFn<Args> + 'static,
+    {
+        self.add(name, f, true)
+    }
+
+    fn add<Args, F>(&mut self, name: &'static str, f: F, variadic: bool) -> Result<(), String>
+    where
+        F: FfiFn<Args> + NativeFn<Args> + 'static,
+    {
+        check_name(&self.name, name)?;
+        let params = F::params();
+        if variadic {
+            match params.last() {
+                Some(NativeType::Slice(_)) => {}
+                _ => {
+                    return Err(format!(
+                        "the last parameter of {}.{} is not a slice",
+                        self.name, name
+                    ))
+                }
+            }
+        }
+        let sig = NativeFunc {
+            name: name.to_string(),
+            params: params,
+            results: F::results(),
+            variadic: variadic,
+        };
+        self.funcs.insert(name.to_string(), sig);
+        self.methods.register(name, f);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Global {
+    val: GosValue,
+    is_const: bool,
+}
+
+/// VirtualPkgs are all the packages provided by the host, by package name
+#[derive(Default)]
+pub struct VirtualPkgs {
+    globals: HashMap<String, BTreeMap<String, Global>>,
+    natives: HashMap<String, NativePackage>,
+}
+
+impl VirtualPkgs {
+    pub fn define(
+        &mut self,
+        pkg: &str,
+        name: &str,
+        val: GosValue,
+        is_const: bool,
+    ) -> Result<(), String> {
+        check_pkg_name(pkg)?;
+        check_name(pkg, name)?;
+        if self
+            .natives
+            .get(pkg)
+            .map_or(false, |p| p.funcs.contains_key(name))
+        {
+            return Err(format!("{}.{} is already a function", pkg, name));
+        }
+        if basic_type(&val).is_none() {
+            return Err(format!("unsupported type of global {}.{}", pkg, name));
+        }
+        if is_const && literal(&val).is_none() {
+            return Err(format!("{}.{} is not a valid constant", pkg, name));
+        }
+        self.globals
+            .entry(pkg.to_string())
+            .or_default()
+            .insert(name.to_string(), Global { val, is_const });
+        Ok(())
+    }
+
+    /// add_native adds the functions of 'pkg', replacing the ones of the
+    /// package of the same name added before
+    pub fn add_native(&mut self, pkg: NativePackage) -> Result<(), String> {
+        check_pkg_name(&pkg.name)?;
+        if let Some(globals) = self.globals.get(&pkg.name) {
+            if let Some(name) = pkg.funcs.keys().find(|x| globals.contains_key(*x)) {
+                return Err(format!("{}.{} is already a global", pkg.name, name));
+            }
+        }
+        self.natives.insert(pkg.name.clone(), pkg);
+        Ok(())
+    }
+
+    /// sources returns the generated source of every package with globals, by
+    /// import path
+    pub fn sources(&self) -> HashMap<String, String> {
+        self.globals
+            .iter()
+            .map(|(pkg, globals)| (pkg.clone(), gen_source(pkg, globals)))
+            .collect()
+    }
+
+    /// native_sigs returns the signatures of the native functions of every
+    /// package, by import path
+    pub fn native_sigs(&self) -> HashMap<String, Vec<NativeFunc>> {
+        self.natives
+            .iter()
+            .map(|(pkg, p)| (pkg.clone(), p.funcs.values().cloned().collect()))
+            .collect()
+    }
+
+    /// native_funcs returns the implementations of the native functions, every
+    /// function has its own Ffi object, so a function can be called by the
+    /// callbacks of another one
+    pub fn native_funcs(&self) -> NativeFuncs {
+        self.natives
+            .iter()
+            .map(|(pkg, p)| {
+                let funcs = p
+                    .funcs
+                    .keys()
+                    .map(|name| {
+                        let ffi: Rc<RefCell<dyn Ffi>> = Rc::new(RefCell::new(p.methods.clone()));
+                        (name.clone(), ffi)
+                    })
+                    .collect();
+                (pkg.clone(), funcs)
+            })
+            .collect()
+    }
+
+    /// new_globals_ctor returns the Ctor of the FFI the variables are read
+    /// from, it takes the name of the package as the argument
+    pub fn new_globals_ctor(pkgs: Rc<RefCell<VirtualPkgs>>) -> Box<Ctor> {
+        Box::new(move |_, params| {
+            let pkg = pkg_name(&params)?;
+            let vals = match pkgs.borrow().globals.get(&pkg) {
+                Some(g) => g
+                    .iter()
+                    .map(|(name, g)| (name.clone(), g.val.clone()))
+                    .collect(),
+                None => HashMap::new(),
+            };
+            Ok(Rc::new(RefCell::new(PkgGlobals { vals })))
+        })
+    }
+}
+
+/// check_pkg_name checks that 'pkg' can be the name of a package imported
+/// by the scripts
+fn check_pkg_name(pkg: &str) -> Result<(), String> {
+    if !is_ident(pkg) || pkg == "_" || pkg == "main" {
+        return Err(format!("invalid package name \"{}\"", pkg));
+    }
+    Ok(())
+}
+
+/// check_name checks that 'name' can be declared in the package 'pkg', next
+/// to its generated source
+fn check_name(pkg: &str, name: &str) -> Result<(), String> {
+    if !is_ident(name) || name == "_" || RESERVED.contains(&name) {
+        return Err(format!("invalid name \"{}\" in package {}", name, pkg));
+    }
+    Ok(())
+}
+
+/// is_ident tells if 's' is a Go identifier, which is not a keyword
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    let first_ok = chars
+        .next()
+        .map_or(false, |c| c.is_alphabetic() || c == '_');
+    first_ok
+        && chars.all(|c| c.is_alphabetic() || c == '_' || c.is_ascii_digit())
+        && !Token::ident_token(s.to_string()).is_keyword()
+}
+
+fn pkg_name(params: &[GosValue]) -> FfiResult<String> {
+    match params.first() {
+        Some(GosValue::Str(s)) => Ok(s.as_str().to_string()),
+        _ => Err("package name expected".to_string()),
+    }
+}
+
+/// PkgGlobals returns the variables of a package, the methods are named
+/// after the variables
+struct PkgGlobals {
+    vals: HashMap<String, GosValue>,
+}
+
+impl Ffi for PkgGlobals {
+    fn call(
+        &mut self,
+        _ctx: &mut FfiCtx,
+        func_name: &str,
+        _params: Vec<GosValue>,
+    ) -> FfiResult<Vec<GosValue>> {
+        match self.vals.get(func_name) {
+            Some(v) => Ok(vec![v.clone()]),
+            None => Err(format!("globals: {} not found", func_name)),
+        }
+    }
+}
+
+fn gen_source(pkg: &str, globals: &BTreeMap<String, Global>) -> String {
+    let mut src = format!("package {}\n\n", pkg);
+    for (name, g) in globals.iter().filter(|(_, g)| g.is_const) {
+        let typ = basic_type(&g.val).unwrap();
+        let lit = literal(&g.val).unwrap();
+        writeln!(src, "const {} {} = {}", name, typ, lit).unwrap();
+    }
+    let vars: Vec<(&String, &Global)> = globals.iter().filter(|(_, g)| !g.is_const).collect();
+    if !vars.is_empty() {
+        src.push_str("\ntype ffiGlobals interface {\n");
+        for (name, g) in vars.iter() {
+            writeln!(src, "\t{}() {}", name, basic_type(&g.val).unwrap()).unwrap();
+        }
+        src.push_str("}\n\n");
+        writeln!(
+            src,
+            "var __globals = ffi(ffiGlobals, \"{}\", \"{}\")\n",
+            GLOBALS_FFI, pkg
+        )
+        .unwrap();
+        for (name, _) in vars.iter() {
+            writeln!(src, "var {} = __globals.{}()", name, name).unwrap();
+        }
+    }
+    src
+}
+
+fn basic_type(val: &GosValue) -> Option<&'static str> {
+    match val {
+        GosValue::Bool(_) => Some("bool"),
+        GosValue::Int(_) => Some("int"),
+        GosValue::Int8(_) => Some("int8"),
+        GosValue::Int16(_) => Some("int16"),
+        GosValue::Int32(_) => Some("int32"),
+        GosValue::Int64(_) => Some("int64"),
+        GosValue::Uint(_) => Some("uint"),
+        GosValue::Uint8(_) => Some("uint8"),
+        GosValue::Uint16(_) => Some("uint16"),
+        GosValue::Uint32(_) => Some("uint32"),
+        GosValue::Uint64(_) => Some("uint64"),
+        GosValue::Float32(_) => Some("float32"),
+        GosValue::Float64(_) => Some("float64"),
+        GosValue::Complex64(_, _) => Some("complex64"),
+        GosValue::Complex128(_) => Some("complex128"),
+        GosValue::Str(_) => Some("string"),
+        _ => None,
+    }
+}
+
+/// literal returns the value as a Go literal, None if it can't be a constant
+fn literal(val: &GosValue) -> Option<String> {
+    let float = |f: f64| match f.is_finite() {
+        // Debug always prints a decimal point or an exponent
+        true => Some(format!("{:?}", f)),
+        false => None,
+    };
+    match val {
+        GosValue::Bool(b) => Some(b.to_string()),
+        GosValue::Int(i) => Some(i.to_string()),
+        GosValue::Int8(i) => Some(i.to_string()),
+        GosValue::Int16(i) => Some(i.to_string()),
+        GosValue::Int32(i) => Some(i.to_string()),
+        GosValue::Int64(i) => Some(i.to_string()),
+        GosValue::Uint(i) => Some(i.to_string()),
+        GosValue::Uint8(i) => Some(i.to_string()),
+        GosValue::Uint16(i) => Some(i.to_string()),
+        GosValue::Uint32(i) => Some(i.to_string()),
+        GosValue::Uint64(i) => Some(i.to_string()),
+        GosValue::Float32(f) => float(f.into_inner() as f64),
+        GosValue::Float64(f) => float(f.into_inner()),
+        GosValue::Str(s) => Some(quote(s.as_str())),
+        _ => None,
+    }
+}
+
+/// quote returns a Go string literal of 's'
+fn quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\r' => q.push_str("\\r"),
+            '\t' => q.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => write!(q, "\\x{:02x}", c as u32).unwrap(),
+            c => q.push(c),
+        }
+    }
+    q.push('"');
+    q
+}
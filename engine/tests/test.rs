@@ -1,17 +1,202 @@
 #[macro_use]
 extern crate time_test;
 extern crate goscript_engine as engine;
-
-fn run(path: &str, trace: bool) -> usize {
+extern crate goscript_macro;
+extern crate goscript_vm as vm;
+use goscript_macro::goscript_ffi;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use vm::ffi::{Ctor, Ffi, FfiCtx, FfiMethods, FfiResult, FromGos};
+use vm::value::GosValue;
+
+/// the FFI called by group1/case1.gos and group1/ffi.gos
+struct TestFfi {}
+
+impl Ffi for TestFfi {
+    fn call(
+        &mut self,
+        _ctx: &mut FfiCtx,
+        func_name: &str,
+        _params: Vec<GosValue>,
+    ) -> FfiResult<Vec<GosValue>> {
+        match func_name {
+            "area" => Ok(vec![GosValue::Int(666)]),
+            "none" => Ok(vec![]),
+            _ => Ok(vec![GosValue::Int(0)]),
+        }
+    }
+}
+
+impl TestFfi {
+    fn new(_ctx: &mut FfiCtx, _v: Vec<GosValue>) -> FfiResult<Rc<RefCell<dyn Ffi>>> {
+        Ok(Rc::new(RefCell::new(TestFfi {})))
+    }
+}
+
+/// the FFI called by group1/ffi.gos
+fn new_conv_ctor() -> Box<Ctor> {
+    let mut methods = FfiMethods::new();
+    methods.register("add", |a: isize, b: isize| a + b);
+    methods.register("join", |s: Vec<String>, sep: String| s.join(&sep));
+    methods.register("split", |s: String| {
+        s.split(',').map(|x| x.to_string()).collect::<Vec<String>>()
+    });
+    methods.register("counts", |s: Vec<String>| {
+        let mut m: HashMap<String, isize> = HashMap::new();
+        for x in s.into_iter() {
+            *m.entry(x).or_insert(0) += 1;
+        }
+        m
+    });
+    methods.register("lookup", |m: HashMap<String, isize>, k: String| {
+        match m.get(&k) {
+            Some(v) => (*v, true),
+            None => (0, false),
+        }
+    });
+    methods.register("evens", |n: isize| {
+        if n > 0 {
+            Some((0..n).filter(|x| x % 2 == 0).collect::<Vec<isize>>())
+        } else {
+            None
+        }
+    });
+    methods.register("half", |f: f64| f / 2.0);
+    methods.register("bad", || "not an int");
+    methods.register("nop", || ());
+    methods.register("parse", |s: String| {
+        s.parse::<isize>().map_err(|e| e.to_string())
+    });
+    methods.register("mustParse", |s: String| {
+        s.parse::<isize>().map_err(|e| e.to_string())
+    });
+    methods.into_ctor()
+}
+
+/// the FFI called by group1/ffi_derive.gos
+struct Counter {
+    count: isize,
+}
+
+#[goscript_ffi]
+impl Counter {
+    fn new(start: isize) -> Counter {
+        Counter { count: start }
+    }
+
+    fn add(&mut self, n: isize) -> isize {
+        self.count += n;
+        self.count
+    }
+
+    fn div(&self, n: isize) -> FfiResult<(isize, isize)> {
+        match n {
+            0 => Err("division by zero".to_string()),
+            _ => Ok((self.count / n, self.count % n)),
+        }
+    }
+
+    #[variadic]
+    fn sum(&self, nums: Vec<isize>) -> isize {
+        nums.iter().sum()
+    }
+
+    fn label(&self, prefix: String) -> String {
+        format!("{}{}", prefix, self.count)
+    }
+}
+
+/// the FFI called by group1/callback.gos
+#[derive(Default)]
+struct Callback {}
+
+#[goscript_ffi]
+impl Callback {
+    fn sort(&self, ctx: &mut FfiCtx, s: Vec<isize>, less: GosValue) -> FfiResult<Vec<isize>> {
+        let mut s = s;
+        for i in 1..s.len() {
+            let mut j = i;
+            while j > 0 {
+                let args = vec![GosValue::Int(s[j]), GosValue::Int(s[j - 1])];
+                let ret = ctx.call_closure(&less, args).map_err(|e| e.message)?;
+                if !bool::from_gos(&ret[0])? {
+                    break;
+                }
+                s.swap(j, j - 1);
+                j -= 1;
+            }
+        }
+        Ok(s)
+    }
+
+    fn apply(&self, ctx: &mut FfiCtx, f: GosValue, n: isize) -> FfiResult<isize> {
+        let ret = ctx
+            .call_closure(&f, vec![GosValue::Int(n)])
+            .map_err(|e| e.message)?;
+        isize::from_gos(&ret[0])
+    }
+}
+
+/// the host data of the engine running group1/user_data.gos
+struct TenantInfo {
+    name: String,
+}
+
+/// the FFI called by group1/user_data.gos
+struct Tenant {
+    name: String,
+}
+
+#[goscript_ffi]
+impl Tenant {
+    fn new(ctx: &mut FfiCtx) -> FfiResult<Tenant> {
+        match ctx.user_data::<TenantInfo>() {
+            Some(info) => Ok(Tenant {
+                name: info.name.clone(),
+            }),
+            None => Err("no tenant".to_string()),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn quota(&self, ctx: &mut FfiCtx) -> isize {
+        ctx.user_data::<isize>().copied().unwrap_or(0)
+    }
+}
+
+fn new_engine(trace: bool) -> engine::Engine {
     let cfg = engine::Config {
         work_dir: Some("./".to_string()),
-        base_path: Some("./std/".to_string()),
+        base_path: None,
         trace_parser: trace,
         trace_checker: trace,
         trace_vm: true,
     };
-    let engine = engine::Engine::new(cfg);
-    engine.run(path)
+    let mut engine = engine::Engine::new(cfg);
+    engine.register_extension("test", Box::new(TestFfi::new));
+    engine.register_extension("conv", new_conv_ctor());
+    engine.register_extension("counter", Counter::new_ctor());
+    engine.register_extension("callback", Callback::new_ctor());
+    engine.register_extension("tenant", Tenant::new_ctor());
+    engine
+}
+
+fn run_result(path: &str, trace: bool) -> Result<Vec<engine::GosValue>, engine::RuntimeError> {
+    new_engine(trace).run(path)
+}
+
+fn run(path: &str, trace: bool) -> usize {
+    match run_result(path, trace) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
 }
 
 #[test]
@@ -104,6 +289,12 @@ fn test_slice1() {
     assert!(err_cnt == 0);
 }
 
+#[test]
+fn test_append() {
+    let err_cnt = run("./tests/group1/append.gos", true);
+    assert!(err_cnt == 0);
+}
+
 #[test]
 fn test_map1() {
     let err_cnt = run("./tests/group1/map1.gos", true);
@@ -188,6 +379,383 @@ fn test_array() {
     assert!(err_cnt == 0);
 }
 
+#[test]
+fn test_goroutine() {
+    let err_cnt = run("./tests/group1/goroutine.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_channel() {
+    let err_cnt = run("./tests/group1/channel.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_select() {
+    let err_cnt = run("./tests/group1/select.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_defer() {
+    let err_cnt = run("./tests/group1/defer.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_conversion() {
+    let err_cnt = run("./tests/group1/conversion.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_builtins() {
+    let err_cnt = run("./tests/group1/builtins.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_label() {
+    let err_cnt = run("./tests/group1/label.gos", true);
+    assert!(err_cnt == 0);
+    // the slots of the range loops left are popped
+    let mut engine = new_engine(false);
+    engine.set_limits(engine::Limits {
+        max_stack_size: Some(1000),
+        ..Default::default()
+    });
+    let result = engine.run("./tests/group1/label.gos");
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_typeassert() {
+    let err_cnt = run("./tests/group1/typeassert.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_ffi() {
+    let err_cnt = run("./tests/group1/ffi.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_callback() {
+    let gos = std::fs::read_to_string("./tests/group1/callback.gos").unwrap();
+    assert!(gos.contains(Callback::GOS_INTERFACE));
+    let err_cnt = run("./tests/group1/callback.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_user_data() {
+    let gos = std::fs::read_to_string("./tests/group1/user_data.gos").unwrap();
+    assert!(gos.contains(Tenant::GOS_INTERFACE));
+    let path = "./tests/group1/user_data.gos";
+
+    let mut engine = new_engine(true);
+    engine.set_user_data(TenantInfo {
+        name: "acme".to_string(),
+    });
+    engine.set_user_data(3isize);
+    assert!(engine.run(path).is_ok());
+
+    // every engine has its own data
+    let mut other = new_engine(true);
+    other.set_user_data(TenantInfo {
+        name: "other".to_string(),
+    });
+    assert!(other.run(path).is_err());
+    assert!(new_engine(true).run(path).is_err());
+}
+
+#[test]
+fn test_globals() {
+    let mut engine = new_engine(true);
+    engine
+        .define_global("config", "Timeout", GosValue::Int(30))
+        .unwrap();
+    engine
+        .define_global("config", "Name", GosValue::new_str("demo".to_string()))
+        .unwrap();
+    engine
+        .define_global("config", "Verbose", GosValue::Bool(true))
+        .unwrap();
+    engine
+        .define_const("config", "Size", GosValue::Int(4))
+        .unwrap();
+    engine
+        .define_const("config", "Ratio", GosValue::Float64(0.25.into()))
+        .unwrap();
+    engine
+        .define_const(
+            "config",
+            "Quoted",
+            GosValue::new_str("a \"b\"\n\tc\\".to_string()),
+        )
+        .unwrap();
+    engine
+        .define_const("config", "Small", GosValue::Int8(-3))
+        .unwrap();
+    assert!(engine
+        .define_global("config", "Bad", GosValue::new_nil())
+        .is_err());
+    // the names go into the source of the package
+    for (pkg, name) in [
+        ("config", "x = 1; var y"),
+        ("config", "1x"),
+        ("config", "func"),
+        ("config", "_"),
+        ("config", "__globals"),
+        ("config", "ffiGlobals"),
+        ("config", "int"),
+        ("main", "X"),
+        ("a/b", "X"),
+        ("var", "X"),
+    ]
+    .iter()
+    {
+        assert!(engine.define_global(pkg, name, GosValue::Int(1)).is_err());
+        assert!(engine.define_const(pkg, name, GosValue::Int(1)).is_err());
+    }
+    let result = engine.run("./tests/group1/globals.gos");
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_native_package() {
+    let mut pkg = engine::NativePackage::new("hostpkg");
+    pkg.func("Add", |a: isize, b: isize| a + b).unwrap();
+    pkg.func("Join", |s: Vec<String>, sep: String| s.join(&sep))
+        .unwrap();
+    pkg.variadic_func("Sum", |nums: Vec<isize>| nums.iter().sum::<isize>())
+        .unwrap();
+    pkg.func("Div", |a: isize, b: isize| match b {
+        0 => Err("division by zero".to_string()),
+        _ => Ok(a / b),
+    })
+    .unwrap();
+    pkg.func("Split", |s: String| {
+        let (a, b) = s.split_at(s.len() / 2);
+        (a.to_string(), b.to_string())
+    })
+    .unwrap();
+    pkg.func("Total", |m: HashMap<String, f64>| m.values().sum::<f64>())
+        .unwrap();
+    assert!(pkg.variadic_func("Neg", |a: isize| -a).is_err());
+    assert!(pkg.variadic_func("Nop", || ()).is_err());
+    assert!(pkg.func("ffiGlobals", |a: isize| a).is_err());
+    assert!(pkg.func("type", |a: isize| a).is_err());
+    let mut engine = new_engine(true);
+    engine.register_package(pkg).unwrap();
+    engine
+        .define_const("hostpkg", "Version", GosValue::Int(2))
+        .unwrap();
+    assert!(engine
+        .define_const("hostpkg", "Add", GosValue::Int(1))
+        .is_err());
+    assert!(engine
+        .register_package(engine::NativePackage::new("main"))
+        .is_err());
+    let mut other = engine::NativePackage::new("config");
+    other.func("Size", || 1isize).unwrap();
+    let mut other_engine = new_engine(false);
+    other_engine
+        .define_const("config", "Size", GosValue::Int(4))
+        .unwrap();
+    assert!(other_engine.register_package(other).is_err());
+    // the calls are type checked against the signatures of the functions
+    for call in &[
+        "hostpkg.Add(1)",
+        "hostpkg.Add(1, \"2\")",
+        "hostpkg.Join([]int{1}, \"-\")",
+        "hostpkg.Total(map[string]int{})",
+        "var s string = hostpkg.Add(1, 2); _ = s",
+        "var n int = hostpkg.Div(1, 2); _ = n",
+    ] {
+        let src = format!(
+            "package main\n\nimport \"hostpkg\"\n\nfunc main() {{\n\t{}\n}}\n",
+            call
+        );
+        let err = engine.run_source("native_err.gos", &src).unwrap_err();
+        assert!(err.kind == engine::ErrorKind::Compile, "{}", call);
+    }
+    let err_cnt = match engine.run("./tests/group1/native.gos") {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    };
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_run_source() {
+    let src = r#"
+package main
+
+import "fmt"
+import "./util"
+
+func main() {
+    fmt.Println("from memory")
+    assert(util.Twice(21) == 42)
+}
+"#;
+    let mut mem = engine::MemSource::new();
+    mem.add_file(
+        "./util/util.gos",
+        "package util\n\nfunc Twice(n int) int { return n * 2 }\n".to_string(),
+    );
+    let layers: Vec<Rc<dyn engine::SourceProvider>> = vec![Rc::new(mem), Rc::new(engine::FsSource)];
+    let mut engine = new_engine(true);
+    engine.set_source_provider(Rc::new(engine::LayeredSource::new(layers)));
+    let result = engine.run_source("main.gos", src);
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+    assert!(result.is_ok());
+
+    // the file system has no "./util"
+    assert!(new_engine(true).run_source("main.gos", src).is_err());
+}
+
+#[test]
+fn test_std_override() {
+    let src = r#"
+package main
+
+import "fmt"
+import "math"
+
+func main() {
+    assert(fmt.Overridden)
+    assert(math.V2 == 2)
+}
+"#;
+    let mut mem = engine::MemSource::new();
+    mem.add_file(
+        "./mystd/fmt/fmt.gos",
+        "package fmt\n\nvar Overridden = true\n".to_string(),
+    );
+    let cfg = engine::Config {
+        work_dir: Some("./".to_string()),
+        base_path: Some("./mystd/".to_string()),
+        trace_parser: true,
+        trace_checker: true,
+        trace_vm: true,
+    };
+    let mut engine = engine::Engine::new(cfg);
+    engine.set_source_provider(Rc::new(mem));
+    // fmt is found under base_path, math is the built-in one
+    let result = engine.run_source("main.gos", src);
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_ffi_derive() {
+    let gos = std::fs::read_to_string("./tests/group1/ffi_derive.gos").unwrap();
+    assert!(gos.contains(Counter::GOS_INTERFACE));
+    let err_cnt = run("./tests/group1/ffi_derive.gos", true);
+    assert!(err_cnt == 0);
+}
+
+#[test]
+fn test_runtime_error() {
+    let err = run_result("./tests/group2/panic.gos", false).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Panic);
+    assert!(err.message == "bad input");
+    let lines: Vec<usize> = err.stack_trace.iter().map(|p| p.line).collect();
+    assert!(lines == vec![6, 10, 15]);
+
+    let err = run_result("./tests/group2/assert.gos", false).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Assert);
+
+    let err = run_result("./tests/group2/deadlock.gos", false).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Deadlock);
+
+    let err = run_result("./tests/group2/missing.gos", false).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Compile);
+    assert!(err.message.contains("failed to locate path"));
+}
+
+#[test]
+fn test_call() {
+    let engine = new_engine(false);
+    let mut vm = engine.load("./tests/group2/call.gos").unwrap();
+    let r = vm.call("main", "Add", vec![GosValue::Int(1), GosValue::Int(2)]);
+    assert!(r.unwrap() == vec![GosValue::Int(3)]);
+    let r = vm.call(
+        "main",
+        "Greet",
+        vec![GosValue::new_str("gopher".to_string())],
+    );
+    let expected = vec![
+        GosValue::new_str("hello gopher".to_string()),
+        GosValue::Int(6),
+    ];
+    assert!(r.unwrap() == expected);
+    let r = vm.call(
+        "main",
+        "Sum",
+        vec![GosValue::Int(1), GosValue::Int(2), GosValue::Int(3)],
+    );
+    assert!(r.unwrap() == vec![GosValue::Int(6)]);
+    // package variables live across calls
+    assert!(vm.call("main", "Calls", vec![]).unwrap() == vec![GosValue::Int(3)]);
+
+    let err = vm
+        .call("main", "Fail", vec![GosValue::new_str("oops".to_string())])
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Panic);
+    assert!(err.message == "oops");
+    // the VM is still usable after a panic
+    assert!(vm.call("main", "Calls", vec![]).unwrap() == vec![GosValue::Int(3)]);
+
+    assert!(vm.call("main", "Missing", vec![]).is_err());
+    assert!(vm.call("main", "Add", vec![GosValue::Int(1)]).is_err());
+    let err = vm
+        .call(
+            "main",
+            "Add",
+            vec![GosValue::Int(1), GosValue::new_str("2".to_string())],
+        )
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Runtime);
+    assert!(err.message == "cannot use string as int in argument 2 in call to main.Add");
+    let err = vm
+        .call(
+            "main",
+            "Sum",
+            vec![GosValue::Int(1), GosValue::Float64(2.0.into())],
+        )
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Runtime);
+    // the arguments are boxed into interfaces
+    let r = vm.call("main", "Describe", vec![GosValue::Int(1)]);
+    assert!(r.unwrap() == vec![GosValue::new_str("int".to_string())]);
+    let r = vm.call("main", "Describe", vec![GosValue::new_str("s".to_string())]);
+    assert!(r.unwrap() == vec![GosValue::new_str("string s".to_string())]);
+    let err = vm
+        .call("main", "Measure", vec![GosValue::Int(1)])
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Runtime);
+    // there is no main function to run
+    assert!(vm.run().is_err());
+}
+
 #[test]
 fn test_linked() {
     let err_cnt = run("./tests/demo/linked.gos", true);
@@ -201,3 +769,231 @@ fn test_leetcode5() {
     let err_cnt = run("./tests/demo/leetcode5.gos", true);
     assert!(err_cnt == 0);
 }
+
+#[test]
+fn test_gc() {
+    let engine = new_engine(true);
+    let mut vm = engine.load("./tests/group1/gc.gos").unwrap();
+    vm.set_gc_threshold(100);
+    let result = vm.run();
+    if let Err(e) = &result {
+        eprintln!("{}", e);
+    }
+    assert!(result.is_ok());
+    // the cycles made by the loop are collected, but the ones made since the
+    // last collection, they are all left without collections
+    let stats = vm.mem_stats();
+    let mut leaky = engine.load("./tests/group1/gc.gos").unwrap();
+    leaky.set_gc_threshold(usize::MAX);
+    assert!(leaky.run().is_ok());
+    let leaked = leaky.mem_stats();
+    assert!(stats.total() * 50 < leaked.total());
+    assert!(stats.channels * 100 < leaked.channels);
+    assert!(stats.structs * 100 < leaked.structs);
+}
+
+#[test]
+fn test_limits() {
+    let mut engine = new_engine(false);
+    engine.set_limits(engine::Limits {
+        max_instructions: Some(100000),
+        max_call_depth: Some(200),
+        ..Default::default()
+    });
+    let mut vm = engine.load("./tests/group2/limits.gos").unwrap();
+    let err = vm.call("main", "Spin", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::InstructionLimit);
+    assert!(vm.executed() >= 100000 && vm.executed() < 100000 + 2048);
+    // the count goes on until the limits are set again
+    let err = vm.call("main", "Count", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::InstructionLimit);
+    vm.set_limits(engine::Limits::default());
+    // the deferred call is not run
+    let r = vm.call("main", "Count", vec![]).unwrap();
+    assert!(*r[0].as_int() > 0);
+
+    vm.set_limits(engine::Limits {
+        max_instructions: Some(100000),
+        ..Default::default()
+    });
+    let err = vm.call("main", "SpinAll", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::InstructionLimit);
+
+    vm.set_limits(engine::Limits {
+        max_call_depth: Some(200),
+        ..Default::default()
+    });
+    let err = vm.call("main", "Recurse", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::CallDepthLimit);
+    assert!(err.stack_trace.len() == 200);
+
+    vm.set_limits(engine::Limits {
+        max_stack_size: Some(1000),
+        ..Default::default()
+    });
+    let err = vm.call("main", "Recurse", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::StackLimit);
+
+    // the stack grows up to its maximum size, which is the limit by default
+    vm.set_limits(engine::Limits::default());
+    let r = vm.call("main", "Deep", vec![GosValue::Int(100000)]);
+    assert!(r.unwrap() == vec![GosValue::Int(100000)]);
+    let err = vm.call("main", "Recurse", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::StackLimit);
+    vm.set_limits(engine::Limits {
+        max_stack_size: Some(1_000_000),
+        ..Default::default()
+    });
+    let err = vm.call("main", "Recurse", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::StackLimit);
+    assert!(err.message == "stack size limit exceeded: 1000000 slots");
+
+    vm.set_limits(engine::Limits {
+        deadline: Some(std::time::Instant::now() + std::time::Duration::from_millis(50)),
+        ..Default::default()
+    });
+    let err = vm.call("main", "Spin", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::Timeout);
+
+    // the calls from native code back into the VM count as well
+    vm.set_limits(engine::Limits {
+        max_call_depth: Some(10),
+        ..Default::default()
+    });
+    let r = vm.call("main", "NativeDeep", vec![GosValue::Int(5)]);
+    assert!(r.unwrap() == vec![GosValue::Int(5)]);
+    let err = vm
+        .call("main", "NativeDeep", vec![GosValue::Int(20)])
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::CallDepthLimit);
+    vm.set_limits(engine::Limits {
+        max_stack_size: Some(50),
+        ..Default::default()
+    });
+    let err = vm
+        .call("main", "NativeDeep", vec![GosValue::Int(25)])
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::StackLimit);
+    // and they can only nest so deep
+    vm.set_limits(engine::Limits::default());
+    let r = vm.call("main", "NativeDeep", vec![GosValue::Int(25)]);
+    assert!(r.unwrap() == vec![GosValue::Int(25)]);
+    let err = vm
+        .call("main", "NativeDeep", vec![GosValue::Int(20000)])
+        .unwrap_err();
+    assert!(err.kind == engine::ErrorKind::StackLimit);
+
+    // the VM is still usable within the limits
+    vm.set_limits(engine::Limits {
+        max_instructions: Some(100000),
+        ..Default::default()
+    });
+    let r = vm.call("main", "Sum", vec![GosValue::Int(100)]);
+    assert!(r.unwrap() == vec![GosValue::Int(4950)]);
+}
+
+#[test]
+fn test_heap_limit() {
+    let mut engine = new_engine(false);
+    engine.set_limits(engine::Limits {
+        max_heap_size: Some(1 << 20),
+        ..Default::default()
+    });
+    let mut vm = engine.load("./tests/group2/memory.gos").unwrap();
+    vm.call("main", "Setup", vec![]).unwrap();
+    let stats = vm.mem_stats();
+    assert!(stats.slices >= 1000 * std::mem::size_of::<GosValue>());
+    assert!(stats.maps > 0 && stats.strings > 0);
+    assert!(stats.total() < 1 << 20);
+
+    for f in ["Grow", "Double", "Fill", "Huge"].iter() {
+        let err = vm.call("main", f, vec![]).unwrap_err();
+        assert!(err.kind == engine::ErrorKind::OutOfMemory);
+    }
+    // the garbage is not counted
+    let r = vm.call("main", "Churn", vec![]);
+    assert!(r.unwrap() == vec![GosValue::Int(2000000)]);
+}
+
+#[test]
+fn test_interrupt() {
+    let engine = new_engine(false);
+    let mut vm = engine.load("./tests/group2/interrupt.gos").unwrap();
+    let handle = vm.interrupt_handle();
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let err = vm.call("main", "Spin", vec![]).unwrap_err();
+    t.join().unwrap();
+    assert!(err.kind == engine::ErrorKind::Interrupted);
+    // the deferred call is run
+    let r = vm.call("main", "Cleaned", vec![]);
+    assert!(r.unwrap() == vec![GosValue::Int(1)]);
+
+    // an interrupt when the VM is not running is dropped
+    vm.interrupt_handle().interrupt();
+    let r = vm.call("main", "Cleaned", vec![]);
+    assert!(r.unwrap() == vec![GosValue::Int(1)]);
+
+    // the calls from native code back into the VM are interrupted as well
+    let handle = vm.interrupt_handle();
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let err = vm.call("main", "SpinInCallback", vec![]).unwrap_err();
+    t.join().unwrap();
+    assert!(err.kind == engine::ErrorKind::Interrupted);
+    let r = vm.call("main", "Cleaned", vec![]);
+    assert!(r.unwrap() == vec![GosValue::Int(2)]);
+
+    // and so are the limits
+    vm.set_limits(engine::Limits {
+        max_instructions: Some(100000),
+        ..Default::default()
+    });
+    let err = vm.call("main", "SpinInCallback", vec![]).unwrap_err();
+    assert!(err.kind == engine::ErrorKind::InstructionLimit);
+}
+
+#[test]
+fn test_step() {
+    let engine = new_engine(false);
+    // two scripts run in turn on one thread
+    let mut vms = vec![
+        engine.load("./tests/group1/step.gos").unwrap(),
+        engine.load("./tests/group1/step.gos").unwrap(),
+    ];
+    let mut steps = vec![0, 0];
+    let mut done = vec![false, false];
+    while done.iter().any(|x| !x) {
+        for (i, vm) in vms.iter_mut().enumerate() {
+            if done[i] {
+                continue;
+            }
+            match vm.step(1000) {
+                engine::StepResult::Yielded => steps[i] += 1,
+                engine::StepResult::Finished(results) => {
+                    assert!(results.is_empty());
+                    done[i] = true;
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+    assert!(steps[0] > 100 && steps[0] == steps[1]);
+    assert!(vms[0].executed() / (steps[0] + 1) < 2000);
+    // the next step starts a new run
+    assert!(matches!(vms[0].step(10), engine::StepResult::Yielded));
+
+    let mut vm = engine.load("./tests/group2/deadlock.gos").unwrap();
+    assert!(matches!(vm.step(1000), engine::StepResult::Blocked));
+    assert!(matches!(vm.step(1000), engine::StepResult::Blocked));
+
+    let mut vm = engine.load("./tests/group2/panic.gos").unwrap();
+    match vm.step(1000) {
+        engine::StepResult::Panicked(e) => assert!(e.kind == engine::ErrorKind::Panic),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
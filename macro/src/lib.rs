@@ -0,0 +1,395 @@
+//! goscript_ffi exposes a Rust type to goscript as an FFI object.
+//!
+//! Put it on an impl block, every method that takes '&self' or '&mut self'
+//! becomes a method of the FFI, with its parameters and results converted by
+//! FromGos and IntoGosResults. For the type it generates:
+//!  - the implementation of 'Ffi', which dispatches 'call' by method name
+//!  - 'new_ctor()', which returns the Ctor to pass to FfiFactory::register,
+//!    the objects are created by an associated function 'new' if there is one,
+//!    with the extra arguments of the 'ffi' call, or by Default otherwise
+//!  - 'GOS_INTERFACE', the declaration of the interface to use in the script
+//!
+//! ```ignore
+//! #[goscript_ffi(ffiCounter)]
+//! impl Counter {
+//!     fn new(start: isize) -> Counter { ... }
+//!     fn add(&mut self, n: isize) -> isize { ... }
+//!     fn div(&self, a: isize, b: isize) -> FfiResult<isize> { ... }
+//!     #[variadic]
+//!     fn sum(&self, nums: Vec<isize>) -> isize { ... }
+//! }
+//! ```
+//! declares:
+//! ```go
+//! type ffiCounter interface {
+//!     add(n int) int
+//!     div(a int, b int) (int, error)
+//!     sum(nums ...int) int
+//! }
+//! ```
+//! The name of the interface defaults to 'ffi' followed by the type name.
+//! A method, or 'new', can take a '&mut FfiCtx' parameter to call back into
+//! the VM or to read the user data of the engine, it's not a parameter in
+//! goscript.
+//! A method returning a Result declares 'error' as its last result, which is
+//! nil for Ok and carries the message of Err. Methods not meant for the script
+//! belong to another impl block.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, FnArg, GenericArgument, Ident, ImplItem, ImplItemMethod, ItemImpl, Pat,
+    PathArguments, ReturnType, Type,
+};
+
+#[proc_macro_attribute]
+pub fn goscript_ffi(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let iface_name = if attr.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(attr as Ident))
+    };
+    let mut imp = parse_macro_input!(item as ItemImpl);
+    match expand(iface_name, &mut imp) {
+        Ok(ts) => ts.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Method is a method exposed to the script
+struct Method {
+    name: Ident,
+    params: Vec<(Ident, Type)>,
+    // the position of the '&mut FfiCtx' parameter, if the method takes one
+    ctx_index: Option<usize>,
+    result: Option<Type>,
+    fallible: bool,
+    variadic: bool,
+}
+
+fn expand(iface_name: Option<Ident>, imp: &mut ItemImpl) -> syn::Result<TokenStream2> {
+    if let Some((_, path, _)) = &imp.trait_ {
+        return Err(syn::Error::new(
+            path.span(),
+            "goscript_ffi must be used on an inherent impl block",
+        ));
+    }
+    if !imp.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            imp.generics.span(),
+            "goscript_ffi does not support generic types",
+        ));
+    }
+    let type_name = match &*imp.self_ty {
+        Type::Path(p) => p.path.segments.last().unwrap().ident.clone(),
+        t => {
+            return Err(syn::Error::new(
+                t.span(),
+                "goscript_ffi expects a named type",
+            ))
+        }
+    };
+    let iface_name = iface_name.unwrap_or_else(|| format_ident!("ffi{}", type_name));
+
+    let mut methods = vec![];
+    let mut ctor = None;
+    for item in imp.items.iter_mut() {
+        if let ImplItem::Method(m) = item {
+            let variadic = take_attr(m, "variadic");
+            if m.sig.receiver().is_some() {
+                methods.push(parse_method(m, variadic)?);
+            } else if m.sig.ident == "new" {
+                ctor = Some(parse_method(m, variadic)?);
+            }
+        }
+    }
+
+    let iface = gen_interface(&iface_name, &methods)?;
+    let arms = methods.iter().map(|m| {
+        let name = &m.name;
+        let name_str = name.to_string();
+        let (check, args) = gen_params(&m.params);
+        let mut args: Vec<TokenStream2> = args.iter().map(|x| quote!(#x)).collect();
+        if let Some(i) = m.ctx_index {
+            args.insert(i, quote!(ctx));
+        }
+        let q = if m.fallible { quote!(?) } else { quote!() };
+        quote! {
+            #name_str => {
+                #check
+                ::goscript_vm::ffi::IntoGosResults::into_gos_results(self.#name(#(#args),*)#q, ctx)
+            }
+        }
+    });
+    let mut ctor_ctx = format_ident!("_ctx");
+    let new_obj = match &ctor {
+        Some(m) => {
+            let (check, args) = gen_params(&m.params);
+            let mut args: Vec<TokenStream2> = args.iter().map(|x| quote!(#x)).collect();
+            if let Some(i) = m.ctx_index {
+                ctor_ctx = format_ident!("ctx");
+                args.insert(i, quote!(ctx));
+            }
+            let q = if m.fallible { quote!(?) } else { quote!() };
+            quote! {
+                #check
+                let obj = Self::new(#(#args),*)#q;
+            }
+        }
+        None => quote! {
+            let _ = params;
+            let obj: Self = ::std::default::Default::default();
+        },
+    };
+    let self_ty = &imp.self_ty;
+    Ok(quote! {
+        #imp
+
+        impl #self_ty {
+            /// the declaration of the interface of this FFI in goscript
+            pub const GOS_INTERFACE: &'static str = #iface;
+
+            /// new_ctor returns the Ctor to register this FFI with
+            pub fn new_ctor() -> ::std::boxed::Box<::goscript_vm::ffi::Ctor> {
+                ::std::boxed::Box::new(
+                    |#ctor_ctx: &mut ::goscript_vm::ffi::FfiCtx,
+                     params: ::std::vec::Vec<::goscript_vm::value::GosValue>|
+                     -> ::goscript_vm::ffi::FfiResult<
+                        ::std::rc::Rc<::std::cell::RefCell<dyn ::goscript_vm::ffi::Ffi>>,
+                    > {
+                        #new_obj
+                        Ok(::std::rc::Rc::new(::std::cell::RefCell::new(obj)))
+                    },
+                )
+            }
+        }
+
+        impl ::goscript_vm::ffi::Ffi for #self_ty {
+            #[allow(unused_variables)]
+            fn call(
+                &mut self,
+                ctx: &mut ::goscript_vm::ffi::FfiCtx,
+                func_name: &str,
+                params: ::std::vec::Vec<::goscript_vm::value::GosValue>,
+            ) -> ::goscript_vm::ffi::FfiResult<::std::vec::Vec<::goscript_vm::value::GosValue>> {
+                match func_name {
+                    #(#arms)*
+                    _ => Err(format!("ffi: method {} not found", func_name)),
+                }
+            }
+        }
+    })
+}
+
+/// take_attr removes the helper attribute 'name' from the method, returns if
+/// it was there
+fn take_attr(m: &mut ImplItemMethod, name: &str) -> bool {
+    let len = m.attrs.len();
+    m.attrs.retain(|a| !a.path.is_ident(name));
+    m.attrs.len() != len
+}
+
+fn parse_method(m: &ImplItemMethod, variadic: bool) -> syn::Result<Method> {
+    let mut params = vec![];
+    let mut ctx_index = None;
+    for arg in m.sig.inputs.iter() {
+        match arg {
+            FnArg::Receiver(r) => {
+                if r.reference.is_none() {
+                    return Err(syn::Error::new(
+                        r.span(),
+                        "ffi methods must take &self or &mut self",
+                    ));
+                }
+            }
+            FnArg::Typed(t) if is_ctx(&t.ty) => ctx_index = Some(params.len()),
+            FnArg::Typed(t) => match &*t.pat {
+                Pat::Ident(i) => params.push((i.ident.clone(), (*t.ty).clone())),
+                p => return Err(syn::Error::new(p.span(), "expected a parameter name")),
+            },
+        }
+    }
+    if variadic
+        && params
+            .last()
+            .and_then(|(_, t)| generic_arg(t, "Vec"))
+            .is_none()
+    {
+        return Err(syn::Error::new(
+            m.sig.span(),
+            "the last parameter of a variadic method must be a Vec",
+        ));
+    }
+    let (result, fallible) = match &m.sig.output {
+        ReturnType::Default => (None, false),
+        ReturnType::Type(_, t) => match result_ok_type(t) {
+            Some(ok) => (Some(ok), true),
+            None => (Some((**t).clone()), false),
+        },
+    };
+    Ok(Method {
+        name: m.sig.ident.clone(),
+        params,
+        ctx_index,
+        result,
+        fallible,
+        variadic,
+    })
+}
+
+/// gen_params generates the argument count check and the conversion of the
+/// arguments, returns the check and the names of the converted arguments
+fn gen_params(params: &[(Ident, Type)]) -> (TokenStream2, Vec<Ident>) {
+    let count = params.len();
+    let args: Vec<Ident> = (0..count).map(|i| format_ident!("arg{}", i)).collect();
+    let tys = params.iter().map(|(_, t)| t);
+    let check = quote! {
+        if params.len() != #count {
+            return Err(format!(
+                "ffi: {} arguments passed, {} expected",
+                params.len(),
+                #count
+            ));
+        }
+        let mut iter = params.iter();
+        #(let #args = <#tys as ::goscript_vm::ffi::FromGos>::from_gos(iter.next().unwrap())?;)*
+    };
+    (check, args)
+}
+
+fn gen_interface(name: &Ident, methods: &[Method]) -> syn::Result<String> {
+    let mut text = format!("type {} interface {{\n", name);
+    for m in methods.iter() {
+        let mut params = vec![];
+        for (i, (pname, ty)) in m.params.iter().enumerate() {
+            let gt = if m.variadic && i == m.params.len() - 1 {
+                format!("...{}", go_type(generic_arg(ty, "Vec").unwrap())?)
+            } else {
+                go_type(ty)?
+            };
+            params.push(format!("{} {}", pname, gt));
+        }
+        let mut results = match &m.result {
+            None => vec![],
+            Some(Type::Tuple(t)) => t.elems.iter().map(go_type).collect::<syn::Result<_>>()?,
+            Some(t) => vec![go_type(t)?],
+        };
+        if m.fallible {
+            results.push("error".to_string());
+        }
+        let result = match results.len() {
+            0 => "".to_string(),
+            1 => format!(" {}", results[0]),
+            _ => format!(" ({})", results.join(", ")),
+        };
+        text.push_str(&format!("\t{}({}){}\n", m.name, params.join(", "), result));
+    }
+    text.push_str("}\n");
+    Ok(text)
+}
+
+/// go_type maps a Rust type supported by FromGos/IntoGos to its Go type
+fn go_type(ty: &Type) -> syn::Result<String> {
+    let unsupported = || {
+        syn::Error::new(
+            ty.span(),
+            "type not supported by goscript_ffi, use GosValue instead",
+        )
+    };
+    let seg = match ty {
+        // the parameters are converted by FromGos, which can't lend them
+        Type::Reference(r) => {
+            let hint = match &*r.elem {
+                Type::Path(p) if p.path.is_ident("str") => "use String instead of &str",
+                _ => "take it by value",
+            };
+            return Err(syn::Error::new(
+                ty.span(),
+                format!("references are not supported by goscript_ffi, {}", hint),
+            ));
+        }
+        Type::Path(p) => p.path.segments.last().unwrap(),
+        _ => return Err(unsupported()),
+    };
+    let name = seg.ident.to_string();
+    let basic = match name.as_str() {
+        "bool" => "bool",
+        "isize" => "int",
+        "i8" => "int8",
+        "i16" => "int16",
+        "i32" => "int32",
+        "i64" => "int64",
+        "usize" => "uint",
+        "u8" => "uint8",
+        "u16" => "uint16",
+        "u32" => "uint32",
+        "u64" => "uint64",
+        "f32" => "float32",
+        "f64" => "float64",
+        "String" => "string",
+        "GosValue" => "interface{}",
+        _ => "",
+    };
+    if !basic.is_empty() {
+        return Ok(basic.to_string());
+    }
+    let args: Vec<&Type> = match &seg.arguments {
+        PathArguments::AngleBracketed(a) => a
+            .args
+            .iter()
+            .filter_map(|x| match x {
+                GenericArgument::Type(t) => Some(t),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    match (name.as_str(), args.as_slice()) {
+        ("Vec", [t]) => Ok(format!("[]{}", go_type(t)?)),
+        ("HashMap", [k, v]) => Ok(format!("map[{}]{}", go_type(k)?, go_type(v)?)),
+        ("Option", [t]) => go_type(t),
+        _ => Err(unsupported()),
+    }
+}
+
+/// is_ctx returns if 'ty' is '&mut FfiCtx', which is passed to the method
+/// instead of being a parameter in goscript
+fn is_ctx(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(r) if r.mutability.is_some() => match &*r.elem {
+            Type::Path(p) => p.path.segments.last().unwrap().ident == "FfiCtx",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// generic_arg returns T if 'ty' is 'name<T>'
+fn generic_arg<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    match ty {
+        Type::Path(p) => {
+            let seg = p.path.segments.last()?;
+            if seg.ident != name {
+                return None;
+            }
+            match &seg.arguments {
+                PathArguments::AngleBracketed(a) => match a.args.first()? {
+                    GenericArgument::Type(t) => Some(t),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// result_ok_type returns T if 'ty' is FfiResult<T> or Result<T, E>
+fn result_ok_type(ty: &Type) -> Option<Type> {
+    generic_arg(ty, "FfiResult")
+        .or_else(|| generic_arg(ty, "Result"))
+        .cloned()
+}
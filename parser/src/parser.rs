@@ -2038,8 +2038,8 @@ impl<'a> Parser<'a> {
                     self.next();
                     let rhs = self.parse_rhs();
                     let ass = Stmt::new_assign(
-                        &mut self.objects, lhs, pos, token, vec![rhs]);
-                    if self.token == Token::DEFINE {
+                        &mut self.objects, lhs, pos, token.clone(), vec![rhs]);
+                    if token == Token::DEFINE {
                         self.short_var_decl(&ass);
                     }
                     Some(ass)
@@ -445,7 +445,16 @@ impl<'a> Scanner<'a> {
             Some(&ch) => match ch {
                 'a' | 'b' | 'f' | 'n' | 'r' | 't' | 'v' | '\\' => {
                     self.advance_and_push(lit, ch);
-                    return Some(ch);
+                    return Some(match ch {
+                        'a' => '\u{07}',
+                        'b' => '\u{08}',
+                        'f' => '\u{0c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'v' => '\u{0b}',
+                        _ => ch,
+                    });
                 }
                 c if c == quote => {
                     self.advance_and_push(lit, c);
@@ -484,7 +484,10 @@ impl<'a> Checker<'a> {
                 return Err(());
             }
         }
-        self.tc_objs.pkgs[self.pkg].set_name(pkg_name.unwrap());
+        // a package declared by the host may have no files, it's named already
+        if let Some(name) = pkg_name {
+            self.tc_objs.pkgs[self.pkg].set_name(name);
+        }
         Ok(())
     }
 
@@ -138,7 +138,12 @@ impl Value {
             |val: &Value, btype: BasicType, rounded: Option<&mut Value>| -> bool {
                 match val.to_float() {
                     Value::Float(f) => match btype {
-                        BasicType::Float64 => true,
+                        BasicType::Float64 => {
+                            if let Some(r) = rounded {
+                                *r = Value::Float(f);
+                            }
+                            true
+                        }
                         BasicType::Float32 => {
                             let f32_ = *f as f32;
                             let ok = !f32_.is_infinite();
@@ -147,7 +152,12 @@ impl Value {
                             }
                             ok
                         }
-                        BasicType::UntypedFloat => true,
+                        BasicType::UntypedFloat => {
+                            if let Some(r) = rounded {
+                                *r = val.clone();
+                            }
+                            true
+                        }
                         _ => unreachable!(),
                     },
                     _ => false,
@@ -574,7 +584,7 @@ impl Value {
 
     pub fn str_as_string(&self) -> String {
         match self {
-            Value::Str(s) => quote_str(s),
+            Value::Str(s) => s.clone(),
             Value::Unknown => "".to_string(),
             _ => panic!("not a string"),
         }
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 use super::check::check::{Checker, TypeInfo};
-use super::objects::{PackageKey, TCObjects};
+use super::objects::{PackageKey, TCObjects, TypeKey};
+use super::scope::Scope;
+use super::source::SourceProvider;
+use super::typ::BasicType;
 use goscript_parser::ast;
 use goscript_parser::errors::{ErrorList, FilePosErrors};
 use goscript_parser::objects::Objects as AstObjects;
@@ -8,9 +11,9 @@ use goscript_parser::position;
 use goscript_parser::{FileSet, Parser};
 use std::collections::HashMap;
 use std::env;
-use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 pub struct Config {
     // working directory
@@ -21,10 +24,21 @@ pub struct Config {
     pub trace_parser: bool,
     // print debug info in checker
     pub trace_checker: bool,
+    // sources of the packages provided by the host instead of files,
+    // by import path
+    pub virtual_pkgs: HashMap<String, String>,
+    // signatures of the functions implemented by the host, by import path,
+    // they are declared in the package without any source
+    pub native_pkgs: HashMap<String, Vec<NativeFunc>>,
+    // where the source files are read from
+    pub source: Rc<dyn SourceProvider>,
+    // the std packages by import path, for the non-local imports not found
+    // under base_path
+    pub std_source: Option<Rc<dyn SourceProvider>>,
 }
 
 impl Config {
-    fn get_working_dir(&self) -> io::Result<PathBuf> {
+    pub fn get_working_dir(&self) -> io::Result<PathBuf> {
         if let Some(wd) = &self.work_dir {
             let mut buf = PathBuf::new();
             buf.push(wd);
@@ -35,6 +49,25 @@ impl Config {
     }
 }
 
+/// NativeType is the type of a parameter or a result of a native function
+#[derive(Clone, Debug, PartialEq)]
+pub enum NativeType {
+    Basic(BasicType),
+    Slice(Box<NativeType>),
+    Map(Box<NativeType>, Box<NativeType>),
+    Error,
+}
+
+/// NativeFunc is the signature of a function implemented by the host, if
+/// 'variadic' the last parameter is a slice taking the variadic arguments
+#[derive(Clone, Debug)]
+pub struct NativeFunc {
+    pub name: String,
+    pub params: Vec<NativeType>,
+    pub results: Vec<NativeType>,
+    pub variadic: bool,
+}
+
 /// ImportKey identifies an imported package by import path and source directory
 /// (directory containing the file containing the import). In practice, the directory
 /// may always be the same, or may not matter. Given an (import path, directory), an
@@ -94,12 +127,27 @@ impl<'a> Importer<'a> {
         if key.path == "unsafe" {
             return Ok(*self.tc_objs.universe().unsafe_pkg());
         }
-        let pb = self.validate_path(key)?;
-        let path = pb.0.as_path();
-        let import_path = pb.1;
+        let natives = self.config.native_pkgs.get(&key.path);
+        let (import_path, files) = match (self.config.virtual_pkgs.get(&key.path), natives) {
+            (Some(content), _) => {
+                let name = format!("<{}>", key.path);
+                (key.path.clone(), vec![self.parse_file(name, content)?])
+            }
+            (None, Some(_)) => (key.path.clone(), vec![]),
+            (None, None) => {
+                let (path, import_path, source) = self.validate_path(key)?;
+                let files = self.parse_dir(path.as_path(), &*source)?;
+                (import_path, files)
+            }
+        };
         let pkg = self.tc_objs.new_package(import_path.clone());
         self.pkgs.insert(import_path, pkg);
-        let files = self.parse_dir(path)?;
+        if let Some(funcs) = natives {
+            // the name of the package is the import path, unless a source
+            // file says otherwise
+            self.tc_objs.pkgs[pkg].set_name(key.path.clone());
+            self.declare_natives(pkg, funcs);
+        }
         Checker::new(
             self.tc_objs,
             self.ast_objs,
@@ -113,8 +161,64 @@ impl<'a> Importer<'a> {
         .check(files)
     }
 
-    fn validate_path(&mut self, key: &'a ImportKey) -> Result<(PathBuf, String), ()> {
+    /// declare_natives declares the functions implemented by the host in the
+    /// scope of 'pkg'
+    fn declare_natives(&mut self, pkg: PackageKey, funcs: &[NativeFunc]) {
+        let scope = *self.tc_objs.pkgs[pkg].scope();
+        for f in funcs.iter() {
+            let params = self.native_tuple(pkg, &f.params);
+            let results = self.native_tuple(pkg, &f.results);
+            let sig = self
+                .tc_objs
+                .new_t_signature(None, None, params, results, f.variadic);
+            let okey = self
+                .tc_objs
+                .new_func(0, Some(pkg), f.name.clone(), Some(sig));
+            if Scope::insert(scope, okey, self.tc_objs).is_some() {
+                let path = self.tc_objs.pkgs[pkg].path().clone();
+                self.error(format!("{} redeclared in package {}", f.name, path));
+            }
+        }
+    }
+
+    fn native_tuple(&mut self, pkg: PackageKey, types: &[NativeType]) -> TypeKey {
+        let vars = types
+            .iter()
+            .map(|t| {
+                let typ = self.native_type(t);
+                self.tc_objs
+                    .new_param_var(0, Some(pkg), String::new(), Some(typ))
+            })
+            .collect();
+        self.tc_objs.new_t_tuple(vars)
+    }
+
+    fn native_type(&mut self, t: &NativeType) -> TypeKey {
+        match t {
+            NativeType::Basic(b) => self.tc_objs.universe().types()[b],
+            NativeType::Slice(elem) => {
+                let elem = self.native_type(elem);
+                self.tc_objs.new_t_slice(elem)
+            }
+            NativeType::Map(key, elem) => {
+                let key = self.native_type(key);
+                let elem = self.native_type(elem);
+                self.tc_objs.new_t_map(key, elem)
+            }
+            NativeType::Error => {
+                let universe = *self.tc_objs.universe().scope();
+                let okey = self.tc_objs.scopes[universe].lookup("error").unwrap();
+                self.tc_objs.lobjs[*okey].typ().unwrap()
+            }
+        }
+    }
+
+    fn validate_path(
+        &mut self,
+        key: &'a ImportKey,
+    ) -> Result<(PathBuf, String, Rc<dyn SourceProvider>), ()> {
         let mut import_path = key.path.clone();
+        let mut source = self.config.source.clone();
         let path = if is_local(&key.path) {
             let working_dir = self.config.get_working_dir();
             if working_dir.is_err() {
@@ -131,22 +235,31 @@ impl<'a> Importer<'a> {
             }
             wd
         } else {
-            if let Some(base) = &self.config.base_path {
+            let in_base = self.config.base_path.as_ref().map(|base| {
                 let mut p = PathBuf::new();
                 p.push(base);
                 p.push(&key.path);
                 p
-            } else {
-                self.error(format!("base dir required for path: {}", key.path));
-                return Err(());
+            });
+            match (in_base, &self.config.std_source) {
+                (Some(p), _) if source.exists(&p) => p,
+                (_, Some(std)) if std.exists(Path::new(&key.path)) => {
+                    source = std.clone();
+                    PathBuf::from(&key.path)
+                }
+                (Some(p), _) => p,
+                (None, _) => {
+                    self.error(format!("base dir required for path: {}", key.path));
+                    return Err(());
+                }
             }
         };
-        if !path.exists() {
+        if !source.exists(&path) {
             self.error(format!("failed to locate path: {}", key.path));
             return Err(());
         }
-        match path.canonicalize() {
-            Ok(p) => Ok((p, import_path)),
+        match source.canonicalize(&path) {
+            Ok(p) => Ok((p, import_path, source)),
             Err(_) => {
                 self.error(format!("failed to canonicalize path: {}", key.path));
                 return Err(());
@@ -154,14 +267,18 @@ impl<'a> Importer<'a> {
         }
     }
 
-    fn parse_dir(&mut self, path: &Path) -> Result<Vec<ast::File>, ()> {
+    fn parse_dir(
+        &mut self,
+        path: &Path,
+        source: &dyn SourceProvider,
+    ) -> Result<Vec<ast::File>, ()> {
         let working_dir = self
             .config
             .get_working_dir()
             .ok()
-            .map(|x| x.canonicalize().ok())
+            .map(|x| source.canonicalize(&x).ok())
             .flatten();
-        match read_content(path) {
+        match read_content(source, path) {
             Ok(contents) => {
                 if contents.len() == 0 {
                     self.error(format!("no source file found in dir: {}", path.display()));
@@ -177,26 +294,7 @@ impl<'a> Importer<'a> {
                         }
                         .to_string_lossy()
                         .to_string();
-                        let mut pfile = self.fset.add_file(
-                            full_name,
-                            Some(self.fset.base()),
-                            content.chars().count(),
-                        );
-                        let afile = Parser::new(
-                            self.ast_objs,
-                            &mut pfile,
-                            self.errors,
-                            &content,
-                            self.config.trace_parser,
-                        )
-                        .parse_file();
-                        if afile.is_none() {
-                            // parse error, the details should be in the errorlist already.
-                            // give up
-                            return Err(());
-                        } else {
-                            afiles.push(afile.unwrap());
-                        }
+                        afiles.push(self.parse_file(full_name, &content)?);
                     }
                     Ok(afiles)
                 }
@@ -208,39 +306,49 @@ impl<'a> Importer<'a> {
         }
     }
 
+    fn parse_file(&mut self, name: String, content: &str) -> Result<ast::File, ()> {
+        let mut pfile = self
+            .fset
+            .add_file(name, Some(self.fset.base()), content.chars().count());
+        let afile = Parser::new(
+            self.ast_objs,
+            &mut pfile,
+            self.errors,
+            content,
+            self.config.trace_parser,
+        )
+        .parse_file();
+        // parse error, the details should be in the errorlist already.
+        // give up
+        afile.ok_or(())
+    }
+
     fn error(&self, err: String) {
-        let pos_file = self.fset.file(self.pos).unwrap();
-        FilePosErrors::new(pos_file, self.errors).add(self.pos, err, false);
+        match self.fset.file(self.pos) {
+            Some(pos_file) => FilePosErrors::new(pos_file, self.errors).add(self.pos, err, false),
+            // the main package is not imported by any file
+            None => {
+                let p = position::Position {
+                    filename: Rc::new(String::new()),
+                    offset: 0,
+                    line: 0,
+                    column: 0,
+                };
+                self.errors.add(p, err, false, false);
+            }
+        }
     }
 }
 
-fn read_content(p: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+fn read_content(source: &dyn SourceProvider, p: &Path) -> io::Result<Vec<(PathBuf, String)>> {
     let mut result = vec![];
-    let mut read = |path: PathBuf| -> io::Result<()> {
+    for path in source.list_files(p)?.into_iter() {
         if let Some(ext) = path.extension() {
             if ext == "gos" || ext == "go" || ext == "src" {
-                let content = fs::read_to_string(path.as_path())?;
+                let content = source.read_file(path.as_path())?;
                 result.push((path, content))
             }
         }
-        Ok(())
-    };
-
-    if p.is_dir() {
-        let mut paths = vec![];
-        for entry in fs::read_dir(p)? {
-            let entry = entry?;
-            let path = entry.path();
-            if !path.is_dir() {
-                paths.push(path);
-            }
-        }
-        paths.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
-        for p in paths.into_iter() {
-            read(p)?;
-        }
-    } else if p.is_file() {
-        read(p.to_path_buf())?;
     }
     if result.len() == 0 {
         return Err(io::Error::new(io::ErrorKind::Other, "no file/dir found"));
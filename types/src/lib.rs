@@ -26,10 +26,14 @@ mod check;
 
 mod importer;
 
+mod source;
+
 pub use check::{DeclInfo, TypeInfo};
 pub use constant::Value as ConstValue;
-pub use importer::{Config, ImportKey, Importer};
+pub use importer::{Config, ImportKey, Importer, NativeFunc, NativeType};
+pub use lookup::{lookup_field_or_method, LookupResult};
 pub use obj::EntityType;
 pub use objects::{DeclInfoKey, ObjKey, PackageKey, ScopeKey, TCObjects, TypeKey};
 pub use operand::OperandMode;
-pub use typ::{BasicType, Type};
+pub use source::{EmbeddedSource, FsSource, LayeredSource, MemSource, SourceProvider};
+pub use typ::{BasicType, ChanDir, Type};
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// SourceProvider is where the importer reads the source files from, the
+/// paths are the ones built from Config.work_dir and Config.base_path
+pub trait SourceProvider {
+    /// exists returns if there is a file or a directory at 'path'
+    fn exists(&self, path: &Path) -> bool;
+
+    /// canonicalize returns the form of 'path' used to identify the file or
+    /// the directory
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// list_files returns the files in the directory 'path' sorted by path,
+    /// or the file itself if 'path' is a file
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn read_file(&self, path: &Path) -> io::Result<String>;
+}
+
+/// FsSource reads the files from the file system
+pub struct FsSource;
+
+impl SourceProvider for FsSource {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let p = entry?.path();
+                if !p.is_dir() {
+                    paths.push(p);
+                }
+            }
+            paths.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+        } else if path.is_file() {
+            paths.push(path.to_path_buf());
+        }
+        Ok(paths)
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+/// MemSource keeps the files in memory, the directories are the ones
+/// containing them
+#[derive(Default)]
+pub struct MemSource {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemSource {
+    pub fn new() -> MemSource {
+        MemSource::default()
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P, content: String) {
+        self.files.insert(normalize(path.as_ref()), content);
+    }
+}
+
+impl SourceProvider for MemSource {
+    fn exists(&self, path: &Path) -> bool {
+        mem_exists(self.files.keys().map(|p| p.as_path()), path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(normalize(path))
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(mem_list_files(self.files.keys().map(|p| p.as_path()), path))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        match self.files.get(&normalize(path)) {
+            Some(c) => Ok(c.clone()),
+            None => Err(not_found()),
+        }
+    }
+}
+
+/// EmbeddedSource serves files compiled into the binary, as pairs of path
+/// and content:
+/// ```ignore
+/// static STD: EmbeddedSource = EmbeddedSource::new(&[
+///     ("std/fmt/fmt.gos", include_str!("std/fmt/fmt.gos")),
+/// ]);
+/// ```
+pub struct EmbeddedSource {
+    files: &'static [(&'static str, &'static str)],
+}
+
+impl EmbeddedSource {
+    pub const fn new(files: &'static [(&'static str, &'static str)]) -> EmbeddedSource {
+        EmbeddedSource { files: files }
+    }
+}
+
+impl SourceProvider for EmbeddedSource {
+    fn exists(&self, path: &Path) -> bool {
+        mem_exists(self.files.iter().map(|(p, _)| Path::new(p)), path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(normalize(path))
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let files = self.files.iter().map(|(p, _)| Path::new(*p));
+        Ok(mem_list_files(files, path))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        let path = normalize(path);
+        match self
+            .files
+            .iter()
+            .find(|(p, _)| normalize(Path::new(p)) == path)
+        {
+            Some((_, c)) => Ok(c.to_string()),
+            None => Err(not_found()),
+        }
+    }
+}
+
+/// LayeredSource looks for a path in each of the providers in turn, the
+/// first one having it serves it
+pub struct LayeredSource {
+    layers: Vec<Rc<dyn SourceProvider>>,
+}
+
+impl LayeredSource {
+    pub fn new(layers: Vec<Rc<dyn SourceProvider>>) -> LayeredSource {
+        LayeredSource { layers: layers }
+    }
+
+    fn find(&self, path: &Path) -> io::Result<&Rc<dyn SourceProvider>> {
+        self.layers
+            .iter()
+            .find(|x| x.exists(path))
+            .ok_or_else(not_found)
+    }
+}
+
+impl SourceProvider for LayeredSource {
+    fn exists(&self, path: &Path) -> bool {
+        self.find(path).is_ok()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.find(path)?.canonicalize(path)
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.find(path)?.list_files(path)
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.find(path)?.read_file(path)
+    }
+}
+
+/// normalize removes the '.' components of 'path' and resolves the '..'
+/// ones, without looking at the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push(c);
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn mem_exists<'a>(mut files: impl Iterator<Item = &'a Path>, path: &Path) -> bool {
+    let path = normalize(path);
+    files.any(|p| normalize(p).starts_with(&path))
+}
+
+fn mem_list_files<'a>(files: impl Iterator<Item = &'a Path>, path: &Path) -> Vec<PathBuf> {
+    let path = normalize(path);
+    let mut result: Vec<PathBuf> = files
+        .map(normalize)
+        .filter(|p| *p == path || p.parent() == Some(&path))
+        .collect();
+    result.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+    result
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no file/dir found")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mem_source() {
+        let mut src = MemSource::new();
+        src.add_file("./main.gos", "package main".to_string());
+        src.add_file("pkg/b.gos", "package pkg".to_string());
+        src.add_file("pkg/a.gos", "package pkg".to_string());
+        src.add_file("pkg/sub/c.gos", "package sub".to_string());
+
+        assert!(src.exists(Path::new("main.gos")));
+        assert!(src.exists(Path::new("./x/../pkg")));
+        assert!(!src.exists(Path::new("pk")));
+        let files = src.list_files(Path::new("./pkg")).unwrap();
+        assert_eq!(files, vec![Path::new("pkg/a.gos"), Path::new("pkg/b.gos")]);
+        assert_eq!(src.list_files(Path::new("main.gos")).unwrap().len(), 1);
+        assert_eq!(
+            src.read_file(Path::new("./main.gos")).unwrap(),
+            "package main"
+        );
+    }
+}
@@ -6,6 +6,7 @@ use std::fs;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Debug)]
 struct ErrInfo {
@@ -67,6 +68,10 @@ fn test_file(path: &str, trace: bool) {
         base_path: None,
         trace_parser: trace,
         trace_checker: trace,
+        virtual_pkgs: HashMap::new(),
+        native_pkgs: HashMap::new(),
+        source: Rc::new(types::FsSource),
+        std_source: None,
     };
     let fs = &mut fe::FileSet::new();
     let asto = &mut fe::objects::Objects::new();
@@ -1,14 +1,62 @@
+use super::gc::GcObjs;
+use super::instruction::ValueType;
+use super::metadata::{GosMetadata, MetaCategory, MetadataType};
+use super::objects::{IfaceUnderlying, MetadataObjs, UnderlyingFfi, VMObjects};
 use super::value::GosValue;
+use super::vm::{self, Env, Fiber, RuntimeError};
+use std::any::{Any, TypeId};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::rc::Rc;
 
 pub type FfiResult<T> = std::result::Result<T, String>;
 
-pub type Ctor = dyn Fn(Vec<GosValue>) -> FfiResult<Rc<RefCell<dyn Ffi>>>;
+/// Ctor creates the FFI object for an 'ffi' call, with its extra arguments
+pub type Ctor = dyn Fn(&mut FfiCtx, Vec<GosValue>) -> FfiResult<Rc<RefCell<dyn Ffi>>>;
 
+/// FfiCtx is what an FFI call needs to build Go values and to call back
+/// into the VM
+pub struct FfiCtx<'a> {
+    // the declared results of the method being called
+    pub results: &'a [GosMetadata],
+    pub objs: &'a mut VMObjects,
+    pub(crate) env: Env<'a>,
+    pub(crate) spawned: &'a mut Vec<Fiber>,
+}
+
+impl<'a> FfiCtx<'a> {
+    /// user_data returns the data of type T set by the embedding application
+    /// with FfiFactory::set_user_data, if there is one
+    pub fn user_data<T: 'static>(&self) -> Option<&'a T> {
+        self.env.ffi_factory.user_data::<T>()
+    }
+
+    /// call_closure calls the Go function value 'cls' with 'args' and returns
+    /// its results. It runs to the end before returning, so it can't wait for
+    /// goroutines other than the ones it starts, which keep running after it
+    /// returns. A panic not recovered by 'cls' is returned as an error.
+    pub fn call_closure(
+        &mut self,
+        cls: &GosValue,
+        args: Vec<GosValue>,
+    ) -> Result<Vec<GosValue>, RuntimeError> {
+        vm::call_closure(cls, args, self.objs, self.env, self.spawned)
+    }
+}
+
+/// An Err returned by call becomes the last result if the Go method declares
+/// 'error' as its last result, in which case call only returns the results
+/// before it. Otherwise the Err is raised as a runtime panic in the script.
+/// The object is borrowed mutably during call, calling a method of it again
+/// from a callback before call returns fails with an Err.
 pub trait Ffi {
-    fn call(&self, func_name: &str, params: Vec<GosValue>) -> Vec<GosValue>;
+    fn call(
+        &mut self,
+        ctx: &mut FfiCtx,
+        func_name: &str,
+        params: Vec<GosValue>,
+    ) -> FfiResult<Vec<GosValue>>;
 }
 
 impl std::fmt::Debug for dyn Ffi {
@@ -19,12 +67,15 @@ impl std::fmt::Debug for dyn Ffi {
 
 pub struct FfiFactory {
     registry: HashMap<&'static str, Box<Ctor>>,
+    // at most one value of each type
+    user_data: HashMap<TypeId, Box<dyn Any>>,
 }
 
 impl FfiFactory {
     pub fn new() -> FfiFactory {
         FfiFactory {
             registry: HashMap::new(),
+            user_data: HashMap::new(),
         }
     }
 
@@ -32,13 +83,26 @@ impl FfiFactory {
         self.registry.insert(name, ctor);
     }
 
+    /// set_user_data makes 'data' available to the FFIs through
+    /// FfiCtx::user_data, replacing the data of the same type set before
+    pub fn set_user_data<T: 'static>(&mut self, data: T) {
+        self.user_data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+
+    pub fn user_data<T: 'static>(&self) -> Option<&T> {
+        self.user_data
+            .get(&TypeId::of::<T>())
+            .and_then(|x| x.downcast_ref::<T>())
+    }
+
     pub fn create_by_name(
         &self,
+        ctx: &mut FfiCtx,
         name: &str,
         params: Vec<GosValue>,
     ) -> FfiResult<Rc<RefCell<dyn Ffi>>> {
         match self.registry.get(name) {
-            Some(ctor) => (*ctor)(params),
+            Some(ctor) => (*ctor)(ctx, params),
             None => Err(format!("FFI named {} not found", name)),
         }
     }
@@ -49,3 +113,498 @@ impl std::fmt::Debug for FfiFactory {
         write!(f, "FfiFactory")
     }
 }
+
+// ----------------------------------------------------------------------------
+// conversions
+
+/// FromGos converts a Go value to a Rust value
+pub trait FromGos: Sized {
+    fn from_gos(val: &GosValue) -> FfiResult<Self>;
+}
+
+/// IntoGos converts a Rust value to a Go value of the type 'meta'
+pub trait IntoGos {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue>;
+}
+
+/// IntoGosResults converts the return value of a Rust function to the results
+/// of a Go function, a tuple is for multiple results
+pub trait IntoGosResults {
+    /// the number of results, not counting the error an FfiResult may return
+    const COUNT: usize;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>>;
+}
+
+/// concrete strips off the named type and the interface of a value
+fn concrete(val: &GosValue) -> GosValue {
+    match val {
+        GosValue::Named(n) => concrete(&n.0),
+        GosValue::Interface(i) => match i.0.borrow().underlying_value() {
+            Some(v) => concrete(v),
+            None => GosValue::new_nil(),
+        },
+        _ => val.clone(),
+    }
+}
+
+fn mismatch<T>(val: &GosValue) -> String {
+    format!(
+        "ffi: cannot convert {:?} to {}",
+        val.get_type(),
+        std::any::type_name::<T>()
+    )
+}
+
+/// typed checks 'val' against the Go type 'meta', a value of a named type is
+/// wrapped in Named, and a value of an empty interface type is boxed
+fn typed(val: GosValue, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+    if let GosMetadata::NonPtr(k, MetaCategory::Default) = meta {
+        match &ctx.objs.metas[*k] {
+            MetadataType::Named(_, underlying, _) => {
+                let underlying = *underlying;
+                let v = typed(val, &underlying, ctx)?;
+                return Ok(GosValue::Named(Box::new((v, *meta))));
+            }
+            MetadataType::Interface(fields) if fields.fields.is_empty() => {
+                let under = IfaceUnderlying::Gos(val, Rc::new(vec![]));
+                return Ok(GosValue::new_iface(*meta, under, &mut ctx.objs.gcobjs));
+            }
+            _ => {}
+        }
+    }
+    if *meta != GosMetadata::Untyped && meta.get_value_type(&ctx.objs.metas) == val.get_type() {
+        Ok(val)
+    } else {
+        Err(format!(
+            "ffi: cannot use {:?} as the result",
+            val.get_type()
+        ))
+    }
+}
+
+/// elem_metas returns the element (and key) types of a slice, array or map type
+fn elem_metas(meta: &GosMetadata, metas: &MetadataObjs) -> Option<(GosMetadata, GosMetadata)> {
+    match meta {
+        GosMetadata::NonPtr(k, _) => match &metas[*k] {
+            MetadataType::Named(_, underlying, _) => elem_metas(underlying, metas),
+            MetadataType::SliceOrArray(elem, _) => Some((GosMetadata::Untyped, *elem)),
+            MetadataType::Map(key, val) => Some((*key, *val)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+macro_rules! impl_scalar {
+    ($t:ty, $variant:ident) => {
+        impl FromGos for $t {
+            fn from_gos(val: &GosValue) -> FfiResult<$t> {
+                match concrete(val) {
+                    GosValue::$variant(v) => Ok(v.into()),
+                    v => Err(mismatch::<$t>(&v)),
+                }
+            }
+        }
+
+        impl IntoGos for $t {
+            fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+                typed(GosValue::$variant(self.into()), meta, ctx)
+            }
+        }
+    };
+}
+
+impl_scalar!(bool, Bool);
+impl_scalar!(isize, Int);
+impl_scalar!(i8, Int8);
+impl_scalar!(i16, Int16);
+impl_scalar!(i32, Int32);
+impl_scalar!(i64, Int64);
+impl_scalar!(usize, Uint);
+impl_scalar!(u8, Uint8);
+impl_scalar!(u16, Uint16);
+impl_scalar!(u32, Uint32);
+impl_scalar!(u64, Uint64);
+impl_scalar!(f32, Float32);
+impl_scalar!(f64, Float64);
+
+impl FromGos for String {
+    fn from_gos(val: &GosValue) -> FfiResult<String> {
+        match concrete(val) {
+            GosValue::Str(s) => Ok(s.as_str().to_string()),
+            v => Err(mismatch::<String>(&v)),
+        }
+    }
+}
+
+impl IntoGos for String {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        typed(GosValue::new_str(self), meta, ctx)
+    }
+}
+
+impl IntoGos for &str {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        self.to_string().into_gos(meta, ctx)
+    }
+}
+
+/// GosValue is passed through as it is
+impl FromGos for GosValue {
+    fn from_gos(val: &GosValue) -> FfiResult<GosValue> {
+        Ok(val.clone())
+    }
+}
+
+impl IntoGos for GosValue {
+    fn into_gos(self, _meta: &GosMetadata, _ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        Ok(self)
+    }
+}
+
+/// nil is None
+impl<T: FromGos> FromGos for Option<T> {
+    fn from_gos(val: &GosValue) -> FfiResult<Option<T>> {
+        if val.equals_nil() {
+            Ok(None)
+        } else {
+            T::from_gos(val).map(Some)
+        }
+    }
+}
+
+impl<T: IntoGos> IntoGos for Option<T> {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        match self {
+            Some(v) => v.into_gos(meta, ctx),
+            None => Ok(meta.zero_val(&ctx.objs.metas, &mut ctx.objs.gcobjs)),
+        }
+    }
+}
+
+/// Vec is for slices and arrays
+impl<T: FromGos> FromGos for Vec<T> {
+    fn from_gos(val: &GosValue) -> FfiResult<Vec<T>> {
+        match concrete(val) {
+            GosValue::Slice(s) => (0..s.0.len())
+                .map(|i| T::from_gos(&s.0.get(i).unwrap()))
+                .collect(),
+            GosValue::Array(a) => (0..a.0.len())
+                .map(|i| T::from_gos(&a.0.get(i).unwrap()))
+                .collect(),
+            GosValue::Nil(_) => Ok(vec![]),
+            v => Err(mismatch::<Vec<T>>(&v)),
+        }
+    }
+}
+
+impl<T: IntoGos> IntoGos for Vec<T> {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        let elem = match elem_metas(meta, &ctx.objs.metas) {
+            Some((GosMetadata::Untyped, elem)) => elem,
+            _ => return Err("ffi: cannot use Vec as the result".to_string()),
+        };
+        let vals = self
+            .into_iter()
+            .map(|x| x.into_gos(&elem, ctx))
+            .collect::<FfiResult<Vec<GosValue>>>()?;
+        let named = meta.get_underlying(&ctx.objs.metas);
+        let val = match named {
+            GosMetadata::NonPtr(_, MetaCategory::Array) => {
+                GosValue::array_with_val(vals, named, &mut ctx.objs.gcobjs)
+            }
+            _ => GosValue::slice_with_val(vals, named, &mut ctx.objs.gcobjs),
+        };
+        typed(val, meta, ctx)
+    }
+}
+
+impl<K, V> FromGos for HashMap<K, V>
+where
+    K: FromGos + Eq + Hash,
+    V: FromGos,
+{
+    fn from_gos(val: &GosValue) -> FfiResult<HashMap<K, V>> {
+        match concrete(val) {
+            GosValue::Map(m) if m.0.is_nil() => Ok(HashMap::new()),
+            GosValue::Map(m) => {
+                m.0.borrow_data()
+                    .iter()
+                    .map(|(k, v)| Ok((K::from_gos(k)?, V::from_gos(&v.borrow())?)))
+                    .collect()
+            }
+            GosValue::Nil(_) => Ok(HashMap::new()),
+            v => Err(mismatch::<HashMap<K, V>>(&v)),
+        }
+    }
+}
+
+impl<K: IntoGos, V: IntoGos> IntoGos for HashMap<K, V> {
+    fn into_gos(self, meta: &GosMetadata, ctx: &mut FfiCtx) -> FfiResult<GosValue> {
+        let (kmeta, vmeta) = match elem_metas(meta, &ctx.objs.metas) {
+            Some((GosMetadata::Untyped, _)) | None => {
+                return Err("ffi: cannot use HashMap as the result".to_string())
+            }
+            Some(metas) => metas,
+        };
+        let default = vmeta.default_val(&ctx.objs.metas, &mut ctx.objs.gcobjs);
+        let val = GosValue::new_map(
+            meta.get_underlying(&ctx.objs.metas),
+            default,
+            &mut ctx.objs.gcobjs,
+        );
+        for (k, v) in self.into_iter() {
+            let k = k.into_gos(&kmeta, ctx)?;
+            let v = v.into_gos(&vmeta, ctx)?;
+            val.as_map().0.insert(k, v);
+        }
+        typed(val, meta, ctx)
+    }
+}
+
+impl IntoGosResults for () {
+    const COUNT: usize = 0;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+        if !ctx.results.is_empty() {
+            return Err(format!(
+                "ffi: 0 results returned, {} expected",
+                ctx.results.len()
+            ));
+        }
+        Ok(vec![])
+    }
+}
+
+macro_rules! impl_into_gos_results {
+    ($($t:ty),*) => {
+        $(
+            impl IntoGosResults for $t {
+                const COUNT: usize = 1;
+
+                fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+                    (self,).into_gos_results(ctx)
+                }
+            }
+        )*
+    };
+}
+
+impl_into_gos_results!(
+    bool, isize, i8, i16, i32, i64, usize, u8, u16, u32, u64, f32, f64, String, &str, GosValue
+);
+
+impl<T: IntoGos> IntoGosResults for Option<T> {
+    const COUNT: usize = 1;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+        (self,).into_gos_results(ctx)
+    }
+}
+
+impl<T: IntoGos> IntoGosResults for Vec<T> {
+    const COUNT: usize = 1;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+        (self,).into_gos_results(ctx)
+    }
+}
+
+impl<K: IntoGos, V: IntoGos> IntoGosResults for HashMap<K, V> {
+    const COUNT: usize = 1;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+        (self,).into_gos_results(ctx)
+    }
+}
+
+macro_rules! impl_tuple_results {
+    ($count:expr, $($t:ident $v:ident $i:tt),+) => {
+        impl<$($t: IntoGos),+> IntoGosResults for ($($t,)+) {
+            const COUNT: usize = $count;
+
+            fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+                if ctx.results.len() != $count {
+                    return Err(format!(
+                        "ffi: {} results returned, {} expected",
+                        $count,
+                        ctx.results.len()
+                    ));
+                }
+                let metas = ctx.results;
+                $(let $v = self.$i.into_gos(&metas[$i], ctx)?;)+
+                Ok(vec![$($v),+])
+            }
+        }
+    };
+}
+
+impl<T: IntoGosResults> IntoGosResults for FfiResult<T> {
+    const COUNT: usize = T::COUNT;
+
+    fn into_gos_results(self, ctx: &mut FfiCtx) -> FfiResult<Vec<GosValue>> {
+        self?.into_gos_results(ctx)
+    }
+}
+
+impl_tuple_results!(1, A a 0);
+impl_tuple_results!(2, A a 0, B b 1);
+impl_tuple_results!(3, A a 0, B b 1, C c 2);
+impl_tuple_results!(4, A a 0, B b 1, C c 2, D d 3);
+
+// ----------------------------------------------------------------------------
+// errors
+
+/// is_error_type returns if 'meta' is the predeclared type error
+pub fn is_error_type(meta: &GosMetadata, metas: &MetadataObjs) -> bool {
+    match meta {
+        GosMetadata::NonPtr(k, MetaCategory::Default) => match &metas[*k] {
+            MetadataType::Named(_, underlying, name) => {
+                name == "error" && underlying.get_value_type(metas) == ValueType::Interface
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// new_error creates a value of the error type 'meta', its Error method
+/// returns 'msg'
+pub fn new_error(
+    msg: String,
+    meta: &GosMetadata,
+    metas: &MetadataObjs,
+    gcos: &mut GcObjs,
+) -> GosValue {
+    let iface_meta = meta.get_underlying(metas);
+    let info = metas[iface_meta.as_non_ptr()]
+        .as_interface()
+        .iface_ffi_info();
+    let obj: Rc<RefCell<dyn Ffi>> = Rc::new(RefCell::new(FfiError { msg: msg }));
+    let iface = GosValue::new_iface(
+        iface_meta,
+        IfaceUnderlying::Ffi(UnderlyingFfi::new(obj, info)),
+        gcos,
+    );
+    GosValue::Named(Box::new((iface, *meta)))
+}
+
+/// FfiError is the underlying value of the errors returned by FFI calls
+struct FfiError {
+    msg: String,
+}
+
+impl Ffi for FfiError {
+    fn call(
+        &mut self,
+        _ctx: &mut FfiCtx,
+        func_name: &str,
+        _params: Vec<GosValue>,
+    ) -> FfiResult<Vec<GosValue>> {
+        match func_name {
+            "Error" => Ok(vec![GosValue::new_str(self.msg.clone())]),
+            _ => Err(format!("ffi: method {} not found", func_name)),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// FfiMethods
+
+/// FfiFn is implemented for Rust functions and closures that can be added to
+/// FfiMethods, 'Args' is the tuple of their parameter types
+pub trait FfiFn<Args> {
+    /// the number of parameters
+    const PARAMS: usize;
+    /// the number of results, as in IntoGosResults
+    const RESULTS: usize;
+
+    fn call_ffi(&self, ctx: &mut FfiCtx, params: Vec<GosValue>) -> FfiResult<Vec<GosValue>>;
+}
+
+macro_rules! impl_ffi_fn {
+    ($count:expr $(, $t:ident $v:ident)*) => {
+        impl<F, R $(, $t)*> FfiFn<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R,
+            R: IntoGosResults,
+            $($t: FromGos,)*
+        {
+            const PARAMS: usize = $count;
+            const RESULTS: usize = R::COUNT;
+
+            #[allow(unused_variables, unused_mut)]
+            fn call_ffi(
+                &self,
+                ctx: &mut FfiCtx,
+                params: Vec<GosValue>,
+            ) -> FfiResult<Vec<GosValue>> {
+                if params.len() != $count {
+                    return Err(format!(
+                        "ffi: {} arguments passed, {} expected",
+                        params.len(),
+                        $count
+                    ));
+                }
+                let mut iter = params.iter();
+                $(let $v = $t::from_gos(iter.next().unwrap())?;)*
+                (self)($($v),*).into_gos_results(ctx)
+            }
+        }
+    };
+}
+
+impl_ffi_fn!(0);
+impl_ffi_fn!(1, A a);
+impl_ffi_fn!(2, A a, B b);
+impl_ffi_fn!(3, A a, B b, C c);
+impl_ffi_fn!(4, A a, B b, C c, D d);
+impl_ffi_fn!(5, A a, B b, C c, D d, E e);
+impl_ffi_fn!(6, A a, B b, C c, D d, E e, G g);
+
+type FfiMethod = dyn Fn(&mut FfiCtx, Vec<GosValue>) -> FfiResult<Vec<GosValue>>;
+
+/// FfiMethods is an Ffi made of Rust functions, with the parameters and the
+/// results converted by FromGos and IntoGosResults. Conversion failures are
+/// raised as runtime panics in the script.
+#[derive(Clone)]
+pub struct FfiMethods {
+    methods: HashMap<&'static str, Rc<FfiMethod>>,
+}
+
+impl FfiMethods {
+    pub fn new() -> FfiMethods {
+        FfiMethods {
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn register<Args, F>(&mut self, name: &'static str, f: F)
+    where
+        F: FfiFn<Args> + 'static,
+    {
+        self.methods
+            .insert(name, Rc::new(move |ctx, params| f.call_ffi(ctx, params)));
+    }
+
+    /// into_ctor returns a Ctor for FfiFactory::register, which gives every
+    /// 'ffi' call in the script a new instance sharing the methods
+    pub fn into_ctor(self) -> Box<Ctor> {
+        Box::new(move |_, _| Ok(Rc::new(RefCell::new(self.clone()))))
+    }
+}
+
+impl Ffi for FfiMethods {
+    fn call(
+        &mut self,
+        ctx: &mut FfiCtx,
+        func_name: &str,
+        params: Vec<GosValue>,
+    ) -> FfiResult<Vec<GosValue>> {
+        match self.methods.get(func_name) {
+            Some(m) => m(ctx, params),
+            None => Err(format!("ffi: method {} not found", func_name)),
+        }
+    }
+}
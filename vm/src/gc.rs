@@ -1,10 +1,87 @@
+use super::instruction::ValueType;
 use super::objects::*;
-use super::value::{GosValue, RCQueue, RCount, IRC};
+use super::value::{GosValue, RCount};
 use std::cell::RefCell;
-use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 use std::rc::{Rc, Weak};
 
-pub type GcObjs = Vec<GcWeak>;
+/// the number of tracked objects that triggers the first collection
+pub const DEFAULT_GC_THRESHOLD: usize = 10000;
+
+/// GcObjs keeps track of the objects that may be part of reference cycles.
+/// A collection is due when the number of tracked objects reaches the
+/// threshold, after which the threshold is set to twice the number of the
+/// survivors, but no lower than the minimum.
+///
+/// It also keeps an estimate of the size of the heap, which is the size last
+/// measured plus the bytes allocated since then, the memory freed is not
+/// taken into account until the heap is measured again.
+#[derive(Debug)]
+pub struct GcObjs {
+    objs: Vec<GcWeak>,
+    threshold: usize,
+    min_threshold: usize,
+    heap_size: usize,
+    allocated: usize,
+}
+
+impl GcObjs {
+    pub fn new() -> GcObjs {
+        GcObjs {
+            objs: vec![],
+            threshold: DEFAULT_GC_THRESHOLD,
+            min_threshold: DEFAULT_GC_THRESHOLD,
+            heap_size: 0,
+            allocated: 0,
+        }
+    }
+
+    /// alloc adds 'bytes' to the estimate of the heap size
+    #[inline]
+    pub fn alloc(&mut self, bytes: usize) {
+        self.allocated = self.allocated.saturating_add(bytes);
+    }
+
+    /// alloc_values is alloc for the storage of 'n' values
+    #[inline]
+    pub fn alloc_values(&mut self, n: usize) {
+        self.alloc(n.saturating_mul(mem::size_of::<RefCell<GosValue>>()));
+    }
+
+    #[inline]
+    pub fn heap_estimate(&self) -> usize {
+        self.heap_size.saturating_add(self.allocated)
+    }
+
+    /// set_heap_size resets the estimate to the size just measured
+    pub fn set_heap_size(&mut self, size: usize) {
+        self.heap_size = size;
+        self.allocated = 0;
+    }
+
+    #[inline]
+    pub fn push(&mut self, w: GcWeak) {
+        self.objs.push(w);
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.objs.len()
+    }
+
+    #[inline]
+    pub fn should_collect(&self) -> bool {
+        self.objs.len() >= self.threshold
+    }
+
+    /// set_threshold sets the minimum number of tracked objects that
+    /// triggers a collection
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.min_threshold = threshold;
+        self.threshold = threshold.max(self.objs.len() * 2);
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum GcWeak {
@@ -33,195 +110,490 @@ impl GcWeak {
 
     fn to_gosv(&self) -> Option<GosValue> {
         match &self {
-            GcWeak::Array(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Array(v)
-            }),
-            GcWeak::Closure(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Closure(v)
-            }),
-            GcWeak::Slice(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Slice(v)
-            }),
-            GcWeak::Map(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Map(v)
-            }),
-            GcWeak::Interface(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Interface(v)
-            }),
-            GcWeak::Struct(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Struct(v)
-            }),
-            GcWeak::Channel(w) => w.upgrade().map(|v| {
-                v.1.set(i32::try_from(w.strong_count()).unwrap() - 1);
-                GosValue::Channel(v)
-            }),
+            GcWeak::Array(w) => w.upgrade().map(GosValue::Array),
+            GcWeak::Closure(w) => w.upgrade().map(GosValue::Closure),
+            GcWeak::Slice(w) => w.upgrade().map(GosValue::Slice),
+            GcWeak::Map(w) => w.upgrade().map(GosValue::Map),
+            GcWeak::Interface(w) => w.upgrade().map(GosValue::Interface),
+            GcWeak::Struct(w) => w.upgrade().map(GosValue::Struct),
+            GcWeak::Channel(w) => w.upgrade().map(GosValue::Channel),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match &self {
+            GcWeak::Array(w) => w.strong_count() > 0,
+            GcWeak::Closure(w) => w.strong_count() > 0,
+            GcWeak::Slice(w) => w.strong_count() > 0,
+            GcWeak::Map(w) => w.strong_count() > 0,
+            GcWeak::Interface(w) => w.strong_count() > 0,
+            GcWeak::Struct(w) => w.strong_count() > 0,
+            GcWeak::Channel(w) => w.strong_count() > 0,
         }
     }
 }
 
-fn children_ref_sub_one(val: &GosValue) {
-    match val {
-        GosValue::Array(arr) => arr
-            .0
-            .borrow_data_mut()
-            .iter()
-            .for_each(|obj| obj.borrow().ref_sub_one()),
-        GosValue::Closure(c) => c.0.borrow().ref_sub_one(),
-        GosValue::Slice(s) => {
-            let sdata = &s.0;
-            if !sdata.is_nil() {
-                sdata
-                    .borrow_data()
-                    .iter()
-                    .for_each(|obj| obj.borrow().ref_sub_one())
-            }
+/// Node is an allocation the collector counts the references to: the tracked
+/// objects and the storage they can share with each other, which are the
+/// elements of arrays and slices, the data of maps and the upvalues.
+/// Strings are nodes too but only for measuring the heap, they cannot be
+/// part of cycles.
+enum Node {
+    Obj(GosValue),
+    Vec(Rc<RefCell<GosVec>>),
+    Map(Rc<RefCell<GosHashMap>>),
+    UpVal(Rc<RefCell<UpValueState>>),
+    Str(Rc<StringObj>),
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Obj(v) => match v {
+                GosValue::Array(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Closure(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Slice(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Map(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Interface(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Struct(o) => Rc::as_ptr(o) as *const u8 as usize,
+                GosValue::Channel(o) => Rc::as_ptr(o) as *const u8 as usize,
+                _ => unreachable!(),
+            },
+            Node::Vec(v) => Rc::as_ptr(v) as *const u8 as usize,
+            Node::Map(m) => Rc::as_ptr(m) as *const u8 as usize,
+            Node::UpVal(u) => Rc::as_ptr(u) as *const u8 as usize,
+            // the strings sharing the data are counted once
+            Node::Str(s) => Rc::as_ptr(s.data()) as *const u8 as usize,
         }
-        GosValue::Map(m) => {
-            let mdata = &m.0;
-            if !mdata.is_nil() {
-                mdata.borrow_data().iter().for_each(|(k, v)| {
-                    k.ref_sub_one();
-                    v.borrow().ref_sub_one();
-                })
-            }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Obj(v) => match v {
+                GosValue::Array(o) => Rc::strong_count(o),
+                GosValue::Closure(o) => Rc::strong_count(o),
+                GosValue::Slice(o) => Rc::strong_count(o),
+                GosValue::Map(o) => Rc::strong_count(o),
+                GosValue::Interface(o) => Rc::strong_count(o),
+                GosValue::Struct(o) => Rc::strong_count(o),
+                GosValue::Channel(o) => Rc::strong_count(o),
+                _ => unreachable!(),
+            },
+            Node::Vec(v) => Rc::strong_count(v),
+            Node::Map(m) => Rc::strong_count(m),
+            Node::UpVal(u) => Rc::strong_count(u),
+            Node::Str(s) => Rc::strong_count(s),
         }
-        GosValue::Interface(i) => i.0.borrow().ref_sub_one(),
-        GosValue::Struct(s) => s.0.borrow().fields.iter().for_each(|obj| obj.ref_sub_one()),
-        GosValue::Channel(_) => unimplemented!(),
-        _ => unreachable!(),
-    };
-}
+    }
 
-fn children_mark_dirty(val: &GosValue, queue: &mut RCQueue) {
-    match val {
-        GosValue::Array(arr) => arr
-            .0
-            .borrow_data_mut()
-            .iter()
-            .for_each(|obj| obj.borrow().mark_dirty(queue)),
-        GosValue::Closure(c) => c.0.borrow().mark_dirty(queue),
-        GosValue::Slice(s) => {
-            let sdata = &s.0;
-            if !sdata.is_nil() {
-                sdata
-                    .borrow_data()
-                    .iter()
-                    .for_each(|obj| obj.borrow().mark_dirty(queue))
+    /// size returns roughly the number of bytes the node takes, not
+    /// including the other nodes it refers to
+    fn size(&self) -> usize {
+        let val_size = mem::size_of::<GosValue>();
+        match self {
+            Node::Obj(v) => match v {
+                GosValue::Array(_) => mem::size_of::<(ArrayObj, RCount)>(),
+                GosValue::Slice(_) => mem::size_of::<(SliceObj, RCount)>(),
+                GosValue::Map(_) => mem::size_of::<(MapObj, RCount)>(),
+                GosValue::Closure(c) => {
+                    let uvs =
+                        c.0.try_borrow()
+                            .map_or(0, |c| c.uvs.as_ref().map_or(0, |x| x.len()));
+                    mem::size_of::<(RefCell<ClosureObj>, RCount)>()
+                        + uvs * mem::size_of::<(usize, UpValue)>()
+                }
+                GosValue::Interface(_) => mem::size_of::<(RefCell<InterfaceObj>, RCount)>(),
+                GosValue::Struct(s) => {
+                    let fields = s.0.try_borrow().map_or(0, |s| s.fields.capacity());
+                    mem::size_of::<(RefCell<StructObj>, RCount)>() + fields * val_size
+                }
+                GosValue::Channel(c) => {
+                    let values = c.0.try_borrow().map_or(0, |c| c.values().count());
+                    mem::size_of::<(RefCell<ChannelObj>, RCount)>() + values * val_size
+                }
+                _ => unreachable!(),
+            },
+            Node::Vec(vec) => {
+                let cap = vec.try_borrow().map_or(0, |v| v.capacity());
+                mem::size_of::<RefCell<GosVec>>() + cap * mem::size_of::<RefCell<GosValue>>()
             }
-        }
-        GosValue::Map(m) => {
-            let mdata = &m.0;
-            if !mdata.is_nil() {
-                mdata.borrow_data().iter().for_each(|(k, v)| {
-                    k.mark_dirty(queue);
-                    v.borrow().mark_dirty(queue);
-                })
+            Node::Map(map) => {
+                let cap = map.try_borrow().map_or(0, |m| m.capacity());
+                let entry = val_size + mem::size_of::<RefCell<GosValue>>();
+                mem::size_of::<RefCell<GosHashMap>>() + cap * entry
             }
+            Node::UpVal(_) => mem::size_of::<RefCell<UpValueState>>(),
+            Node::Str(s) => mem::size_of::<String>() + s.data().capacity(),
         }
-        GosValue::Interface(i) => i.0.borrow().mark_dirty(queue),
-        GosValue::Struct(s) => {
-            s.0.borrow()
-                .fields
-                .iter()
-                .for_each(|obj| obj.mark_dirty(queue))
+    }
+
+    /// value_type tells what the node is counted as in MemStats, the storage
+    /// shared by arrays and slices is counted as what it's found in first
+    fn value_type(&self, found_in: ValueType) -> ValueType {
+        match self {
+            Node::Obj(v) => v.get_type(),
+            Node::Vec(_) => found_in,
+            Node::Map(_) => ValueType::Map,
+            Node::UpVal(_) => ValueType::Pointer,
+            Node::Str(_) => ValueType::Str,
         }
-        GosValue::Channel(_) => unimplemented!(),
-        _ => unreachable!(),
-    };
-}
+    }
 
-fn release(obj: &mut GosValue) {
-    match obj {
-        GosValue::Array(arr) => arr.0.borrow_data_mut().clear(),
-        GosValue::Closure(c) => {
-            let r: &mut ClosureObj = &mut RefCell::borrow_mut(&c.0);
-            if let Some(uvs) = &mut r.uvs {
-                uvs.clear();
+    /// children calls 'f' with every reference this node holds to other nodes.
+    /// A node that is borrowed at the moment is in use, the references it
+    /// holds are skipped so that they are counted as external ones.
+    fn children(&self, f: &mut dyn FnMut(Node)) {
+        match self {
+            Node::Obj(v) => match v {
+                GosValue::Array(a) => f(Node::Vec(a.0.vec.clone())),
+                GosValue::Slice(s) => f(Node::Vec(s.0.vec.clone())),
+                GosValue::Map(m) => {
+                    if let Some(data) = &m.0.map {
+                        f(Node::Map(data.clone()))
+                    }
+                }
+                GosValue::Closure(c) => {
+                    if let Ok(cls) = c.0.try_borrow() {
+                        if let Some(uvs) = &cls.uvs {
+                            uvs.values().for_each(|uv| f(Node::UpVal(uv.inner.clone())));
+                        }
+                        if let Some(recv) = &cls.recv {
+                            value_children(recv, f);
+                        }
+                    }
+                }
+                GosValue::Interface(i) => {
+                    if let Ok(iface) = i.0.try_borrow() {
+                        if let IfaceUnderlying::Gos(v, _) = iface.underlying() {
+                            value_children(v, f);
+                        }
+                    }
+                }
+                GosValue::Struct(s) => {
+                    if let Ok(s) = s.0.try_borrow() {
+                        s.fields.iter().for_each(|v| value_children(v, f));
+                    }
+                }
+                GosValue::Channel(c) => {
+                    if let Ok(c) = c.0.try_borrow() {
+                        c.values().for_each(|v| value_children(v, f));
+                    }
+                }
+                _ => unreachable!(),
+            },
+            Node::Vec(vec) => {
+                if let Ok(vec) = vec.try_borrow() {
+                    for cell in vec.iter() {
+                        if let Ok(v) = cell.try_borrow() {
+                            value_children(&v, f);
+                        }
+                    }
+                }
             }
-            r.recv = None;
-            r.ffi = None;
-        }
-        GosValue::Slice(s) => {
-            let sdata = &s.0;
-            if !sdata.is_nil() {
-                sdata.borrow_data_mut().clear();
+            Node::Map(map) => {
+                if let Ok(map) = map.try_borrow() {
+                    for (k, cell) in map.iter() {
+                        value_children(k, f);
+                        if let Ok(v) = cell.try_borrow() {
+                            value_children(&v, f);
+                        }
+                    }
+                }
             }
+            Node::UpVal(uv) => {
+                if let Ok(state) = uv.try_borrow() {
+                    if let UpValueState::Closed(v) = &*state {
+                        value_children(v, f);
+                    }
+                }
+            }
+            Node::Str(_) => {}
         }
-        GosValue::Map(m) => {
-            let mdata = &m.0;
-            if !mdata.is_nil() {
-                mdata.borrow_data_mut().clear();
+    }
+
+    /// release drops the references this node holds, to break the cycles it
+    /// is part of. What is dropped is returned, to be dropped after all the
+    /// nodes are released.
+    fn release(&self, garbage: &mut Vec<GosValue>) {
+        match self {
+            Node::Obj(v) => match v {
+                // the data is released with its own node
+                GosValue::Array(_) | GosValue::Slice(_) | GosValue::Map(_) => {}
+                GosValue::Closure(c) => {
+                    if let Ok(mut cls) = c.0.try_borrow_mut() {
+                        cls.uvs = None;
+                        garbage.extend(cls.recv.take());
+                    }
+                }
+                GosValue::Interface(i) => {
+                    if let Ok(mut iface) = i.0.try_borrow_mut() {
+                        if let IfaceUnderlying::Gos(v, _) = iface.underlying() {
+                            garbage.push(v.clone());
+                        }
+                        iface.set_underlying(IfaceUnderlying::None);
+                    }
+                }
+                GosValue::Struct(s) => {
+                    if let Ok(mut s) = s.0.try_borrow_mut() {
+                        garbage.append(&mut s.fields);
+                    }
+                }
+                GosValue::Channel(c) => {
+                    if let Ok(mut c) = c.0.try_borrow_mut() {
+                        garbage.extend(c.values().cloned().collect::<Vec<GosValue>>());
+                        c.clear();
+                    }
+                }
+                _ => unreachable!(),
+            },
+            Node::Vec(vec) => {
+                if let Ok(mut vec) = vec.try_borrow_mut() {
+                    garbage.extend(vec.drain(..).map(|x| x.into_inner()));
+                }
             }
+            Node::Map(map) => {
+                if let Ok(mut map) = map.try_borrow_mut() {
+                    for (k, v) in map.drain() {
+                        garbage.push(k);
+                        garbage.push(v.into_inner());
+                    }
+                }
+            }
+            Node::UpVal(uv) => {
+                if let Ok(mut state) = uv.try_borrow_mut() {
+                    if let UpValueState::Closed(v) = &mut *state {
+                        garbage.push(mem::replace(v, GosValue::new_nil()));
+                    }
+                }
+            }
+            Node::Str(_) => {}
         }
-        GosValue::Interface(i) => RefCell::borrow_mut(&i.0).set_underlying(IfaceUnderlying::None),
-        GosValue::Struct(s) => RefCell::borrow_mut(&s.0).fields.clear(),
-        GosValue::Channel(_) => unimplemented!(),
-        _ => unreachable!(),
-    };
+    }
 }
 
-/// put the non-zero-rc on the left, and the others on the right
-fn partition_to_scan(to_scan: &mut Vec<GosValue>) -> usize {
-    let len = to_scan.len();
-    if len == 0 {
-        return 0;
-    }
-    let mut p0 = 0;
-    let mut p1 = len - 1;
-    loop {
-        while p0 < len - 1 && to_scan[p0].rc() > 0 {
-            p0 += 1;
+/// value_children calls 'f' with the nodes the value 'v' refers to
+fn value_children(v: &GosValue, f: &mut dyn FnMut(Node)) {
+    match v {
+        GosValue::Array(_)
+        | GosValue::Closure(_)
+        | GosValue::Slice(_)
+        | GosValue::Map(_)
+        | GosValue::Interface(_)
+        | GosValue::Struct(_)
+        | GosValue::Channel(_) => f(Node::Obj(v.clone())),
+        GosValue::Pointer(p) => match p.as_ref() {
+            PointerObj::UpVal(uv) => f(Node::UpVal(uv.inner.clone())),
+            PointerObj::Struct(s, _) => f(Node::Obj(GosValue::Struct(s.clone()))),
+            PointerObj::Array(a, _) => f(Node::Obj(GosValue::Array(a.clone()))),
+            PointerObj::Slice(s, _) => f(Node::Obj(GosValue::Slice(s.clone()))),
+            PointerObj::Map(m, _) => f(Node::Obj(GosValue::Map(m.clone()))),
+            PointerObj::SliceMember(s, _) => f(Node::Obj(GosValue::Slice(s.clone()))),
+            PointerObj::StructField(s, _) => f(Node::Obj(GosValue::Struct(s.clone()))),
+            PointerObj::Released | PointerObj::PkgMember(_, _) => {}
+        },
+        GosValue::Named(n) => value_children(&n.0, f),
+        GosValue::Str(s) => f(Node::Str(s.clone())),
+        _ => {}
+    }
+}
+
+/// gc breaks the reference cycles no longer reachable from outside of the
+/// tracked objects, and removes the freed objects from 'objs'.
+///
+/// The references that are not held by the nodes, which are the ones on the
+/// stacks, in the package members, in the native code, etc. make the nodes
+/// they refer to roots. A node is garbage if it cannot be reached from any
+/// root; the references they hold are dropped, which frees them.
+pub fn gc(objs: &mut GcObjs) {
+    let mut nodes: Vec<Node> = vec![];
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    let mut add = |nodes: &mut Vec<Node>, node: Node| -> usize {
+        *index.entry(node.addr()).or_insert_with(|| {
+            nodes.push(node);
+            nodes.len() - 1
+        })
+    };
+    for w in objs.objs.iter() {
+        if let Some(v) = w.to_gosv() {
+            add(&mut nodes, Node::Obj(v));
         }
-        while p1 > 1 && to_scan[p1].rc() <= 0 {
-            p1 -= 1;
+    }
+
+    // find all the nodes reachable from the tracked objects, and count the
+    // references to each of them held by the others
+    let mut edges: Vec<Vec<usize>> = vec![];
+    let mut i = 0;
+    while i < nodes.len() {
+        let mut children = vec![];
+        nodes[i].children(&mut |n| {
+            if !matches!(n, Node::Str(_)) {
+                children.push(n)
+            }
+        });
+        edges.push(children.into_iter().map(|n| add(&mut nodes, n)).collect());
+        i += 1;
+    }
+    // the references left, besides the one in 'nodes', are external ones
+    let mut rc: Vec<usize> = nodes.iter().map(|n| n.strong_count() - 1).collect();
+    for targets in edges.iter() {
+        for t in targets.iter() {
+            rc[*t] -= 1;
         }
-        if p0 >= p1 {
-            break;
+    }
+
+    let mut alive: Vec<bool> = rc.iter().map(|x| *x > 0).collect();
+    let mut queue: Vec<usize> = (0..nodes.len()).filter(|x| alive[*x]).collect();
+    while let Some(i) = queue.pop() {
+        for t in edges[i].iter() {
+            if !alive[*t] {
+                alive[*t] = true;
+                queue.push(*t);
+            }
         }
-        to_scan.swap(p0, p1);
     }
-    p0
-}
 
-pub fn gc(objs: &mut GcObjs) {
-    let mut to_scan: Vec<GosValue> = objs.iter().filter_map(|o| o.to_gosv()).collect();
-    print!("objs before GC: {}\n", to_scan.len());
-    for v in to_scan.iter() {
-        children_ref_sub_one(v);
+    let mut garbage = vec![];
+    for (node, _) in nodes.iter().zip(alive.iter()).filter(|(_, a)| !**a) {
+        node.release(&mut garbage);
     }
+    drop(garbage);
+    drop(nodes);
 
-    let boundary = partition_to_scan(&mut to_scan);
-    for i in boundary..to_scan.len() {
-        to_scan[i].set_rc(-(i as IRC));
+    objs.objs.retain(|w| w.is_alive());
+    objs.threshold = objs.min_threshold.max(objs.objs.len() * 2);
+}
+
+/// MemStats is roughly the number of bytes taken by the objects of each type
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemStats {
+    pub strings: usize,
+    pub arrays: usize,
+    pub slices: usize,
+    pub maps: usize,
+    pub structs: usize,
+    pub closures: usize,
+    pub interfaces: usize,
+    pub channels: usize,
+    // the variables captured by closures or pointed to by pointers
+    pub pointers: usize,
+}
+
+impl MemStats {
+    pub fn total(&self) -> usize {
+        self.strings
+            + self.arrays
+            + self.slices
+            + self.maps
+            + self.structs
+            + self.closures
+            + self.interfaces
+            + self.channels
+            + self.pointers
     }
 
-    let mut queue: RCQueue = RCQueue::new();
-    for i in 0..boundary {
-        children_mark_dirty(&to_scan[i], &mut queue);
+    fn add(&mut self, t: ValueType, bytes: usize) {
+        let count = match t {
+            ValueType::Str => &mut self.strings,
+            ValueType::Array => &mut self.arrays,
+            ValueType::Slice => &mut self.slices,
+            ValueType::Map => &mut self.maps,
+            ValueType::Struct => &mut self.structs,
+            ValueType::Closure => &mut self.closures,
+            ValueType::Interface => &mut self.interfaces,
+            ValueType::Channel => &mut self.channels,
+            _ => &mut self.pointers,
+        };
+        *count += bytes;
     }
+}
 
-    loop {
-        if let Some(i) = queue.pop_front() {
-            let obj = &to_scan[(-i) as usize];
-            obj.set_rc(666);
-            children_mark_dirty(&obj, &mut queue);
-        } else {
-            break;
+/// mem_stats measures the objects reachable from 'roots' and the tracked
+/// objects, each of them is counted once however many references there are
+pub fn mem_stats<'a>(objs: &GcObjs, roots: impl Iterator<Item = &'a GosValue>) -> MemStats {
+    let mut stats = MemStats::default();
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut queue: Vec<(Node, ValueType)> = vec![];
+    let mut push = |queue: &mut Vec<(Node, ValueType)>, n: Node, found_in: ValueType| {
+        if visited.insert(n.addr()) {
+            let t = n.value_type(found_in);
+            queue.push((n, t));
         }
+    };
+    for v in roots {
+        value_children(v, &mut |n| push(&mut queue, n, ValueType::Nil));
     }
-
-    for mut obj in to_scan.into_iter() {
-        if obj.rc() <= 0 {
-            release(&mut obj);
+    for w in objs.objs.iter() {
+        if let Some(v) = w.to_gosv() {
+            push(&mut queue, Node::Obj(v), ValueType::Nil);
         }
     }
+    while let Some((node, t)) = queue.pop() {
+        stats.add(t, node.size());
+        node.children(&mut |n| push(&mut queue, n, t));
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metadata::GosMetadata;
+    use std::cell::Cell;
+
+    fn new_struct(gcos: &mut GcObjs) -> GosValue {
+        let obj = StructObj {
+            meta: GosMetadata::Untyped,
+            fields: vec![GosValue::new_nil(), GosValue::Int(1)],
+        };
+        GosValue::new_struct(obj, gcos)
+    }
+
+    fn link(from: &GosValue, to: &GosValue) {
+        let ptr = PointerObj::Struct(to.as_struct().clone(), GosMetadata::Untyped);
+        from.as_struct().0.borrow_mut().fields[0] = GosValue::new_pointer(ptr);
+    }
+
+    #[test]
+    fn test_gc() {
+        let mut gcos = GcObjs::new();
+        let a = new_struct(&mut gcos);
+        let b = new_struct(&mut gcos);
+        link(&a, &b);
+        link(&b, &a);
+        let weak = Rc::downgrade(a.as_struct());
+        drop(a);
+        drop(b);
+
+        // a cycle kept alive by a slice sharing the elements of another one
+        let c = new_struct(&mut gcos);
+        let s = GosValue::slice_with_val(vec![c.clone()], GosMetadata::Untyped, &mut gcos);
+        let s2 = GosValue::Slice(Rc::new((s.as_slice().0.clone(), Cell::new(0))));
+        gcos.push(GcWeak::from_gosv(&s2));
+        link(&c, &c);
+        drop(c);
+        drop(s);
+
+        assert_eq!(gcos.len(), 5);
+        gc(&mut gcos);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(gcos.len(), 2);
+        let c = s2.as_slice().0.get(0).unwrap();
+        assert_eq!(c.as_struct().0.borrow().fields[1], GosValue::Int(1));
+    }
 
-    let result: Vec<GosValue> = objs.iter().filter_map(|o| o.to_gosv()).collect();
-    print!("objs left after GC: {}\n", result.len());
+    #[test]
+    fn test_mem_stats() {
+        let mut gcos = GcObjs::new();
+        let s = GosValue::new_str("hello".to_string());
+        let vals = vec![s.clone(), s.clone(), GosValue::Int(1)];
+        let slice = GosValue::slice_with_val(vals, GosMetadata::Untyped, &mut gcos);
+        assert!(gcos.heap_estimate() >= 3 * mem::size_of::<RefCell<GosValue>>());
+
+        let roots = vec![s, slice];
+        let stats = mem_stats(&gcos, roots.iter());
+        // the string is counted once
+        assert_eq!(stats.strings, mem::size_of::<String>() + 5);
+        assert!(stats.slices >= 3 * mem::size_of::<RefCell<GosValue>>());
+        assert_eq!(stats.total(), stats.strings + stats.slices);
+    }
 }
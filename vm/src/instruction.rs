@@ -57,6 +57,7 @@ pub enum Opcode {
     REF_LITERAL,
     DEREF, // *
     ARROW, // <-
+    SEND,  // ch <- v
     NOT,   // !
     EQL,   // ==
     LSS,   // <
@@ -71,6 +72,8 @@ pub enum Opcode {
     CALL_ELLIPSIS, // call with the past parameter followed by ellipsis
     RETURN,
     RETURN_INIT_PKG,
+    GO,    // call in a new fiber
+    DEFER, // call when the current function returns
 
     // jump
     JUMP,
@@ -78,11 +81,13 @@ pub enum Opcode {
     JUMP_IF_NOT,
     SWITCH, // EQL + JUMP_IF + do not pop the first argument
     LOOP,
-    RANGE, // for ... range statement
+    RANGE,  // for ... range statement
+    SELECT, // select statement, followed by the descriptions of the cases
 
     // type
     TYPE_ASSERT,
     TYPE,
+    CONVERT, // type conversion
 
     // for shift ops: convert the second operator to uint32
     TO_UINT32,
@@ -97,6 +102,16 @@ pub enum Opcode {
     LEN,        // for built-in function len
     CAP,        // for built-in function cap
     APPEND,     // for built-in function append
+    CLOSE,      // for built-in function close
+    PANIC,      // for built-in function panic
+    RECOVER,    // for built-in function recover
+    COPY,       // for built-in function copy
+    DELETE,     // for built-in function delete
+    COMPLEX,    // for built-in function complex
+    REAL,       // for built-in function real
+    IMAG,       // for built-in function imag
+    PRINT,      // for built-in function print
+    PRINTLN,    // for built-in function println
     ASSERT,     // for built-in function assert
     FFI,        // for built-in function native
 }
@@ -157,6 +172,7 @@ impl Opcode {
             Opcode::REF_LITERAL => ("REF_LITERAL", 0),
             Opcode::DEREF => ("DEREF", 0),
             Opcode::ARROW => ("ARROW", 0),
+            Opcode::SEND => ("SEND", -2),
             Opcode::NOT => ("LNOT", 0),
             Opcode::EQL => ("EQL", -1),
             Opcode::LSS => ("LSS", -1),
@@ -170,6 +186,8 @@ impl Opcode {
             Opcode::CALL_ELLIPSIS => ("CALL_ELLIPSIS", -128),
             Opcode::RETURN => ("RETURN", -128),
             Opcode::RETURN_INIT_PKG => ("RETURN_INIT_PKG", -128),
+            Opcode::GO => ("GO", -128),
+            Opcode::DEFER => ("DEFER", -128),
 
             Opcode::JUMP => ("JUMP", 0),
             Opcode::LOOP => ("LOOP", 0),
@@ -177,9 +195,11 @@ impl Opcode {
             Opcode::JUMP_IF_NOT => ("JUMP_IF_NOT", -1),
             Opcode::SWITCH => ("SWITCH", -1),
             Opcode::RANGE => ("RANGE", 1),
+            Opcode::SELECT => ("SELECT", -128),
 
             Opcode::TYPE_ASSERT => ("TYPE_ASSERT", 0),
             Opcode::TYPE => ("TYPE", 1),
+            Opcode::CONVERT => ("CONVERT", 0),
 
             Opcode::TO_UINT32 => ("TO_UINT32", 0),
 
@@ -192,6 +212,16 @@ impl Opcode {
             Opcode::LEN => ("LEN", 0),
             Opcode::CAP => ("CAP", 0),
             Opcode::APPEND => ("APPEND", -128),
+            Opcode::CLOSE => ("CLOSE", -1),
+            Opcode::PANIC => ("PANIC", -1),
+            Opcode::RECOVER => ("RECOVER", 1),
+            Opcode::COPY => ("COPY", -1),
+            Opcode::DELETE => ("DELETE", -2),
+            Opcode::COMPLEX => ("COMPLEX", -1),
+            Opcode::REAL => ("REAL", 0),
+            Opcode::IMAG => ("IMAG", 0),
+            Opcode::PRINT => ("PRINT", -128),
+            Opcode::PRINTLN => ("PRINTLN", -128),
             Opcode::ASSERT => ("ASSERT", 0),
             Opcode::FFI => ("FFI", 0),
         }
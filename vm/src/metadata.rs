@@ -31,6 +31,7 @@ pub struct Metadata {
     pub mcomplex64: GosMetadata,
     pub mcomplex128: GosMetadata,
     pub mstr: GosMetadata,
+    pub empty_iface: GosMetadata,
     pub default_sig: GosMetadata,
 }
 
@@ -68,6 +69,10 @@ impl Metadata {
                 objs.insert(MetadataType::Str(GosValue::new_str("".to_string()))),
                 MetaCategory::Default,
             ),
+            empty_iface: GosMetadata::NonPtr(
+                objs.insert(MetadataType::Interface(Fields::new(vec![], HashMap::new()))),
+                MetaCategory::Default,
+            ),
             default_sig: GosMetadata::NonPtr(
                 objs.insert(MetadataType::Signature(SigMetadata::default())),
                 MetaCategory::Default,
@@ -84,6 +89,13 @@ pub enum MetaCategory {
     ArrayType,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelType {
+    Send,
+    Recv,
+    SendRecv,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GosMetadata {
     Untyped,
@@ -123,6 +135,15 @@ impl GosMetadata {
         GosMetadata::new(MetadataType::Map(kmeta, vmeta), metas)
     }
 
+    #[inline]
+    pub fn new_channel(
+        typ: ChannelType,
+        vmeta: GosMetadata,
+        metas: &mut MetadataObjs,
+    ) -> GosMetadata {
+        GosMetadata::new(MetadataType::Channel(typ, vmeta), metas)
+    }
+
     #[inline]
     pub fn new_interface(fields: Fields, metas: &mut MetadataObjs) -> GosMetadata {
         GosMetadata::new(MetadataType::Interface(fields), metas)
@@ -166,9 +187,13 @@ impl GosMetadata {
         GosMetadata::new(t, metas)
     }
 
-    pub fn new_named(underlying: GosMetadata, metas: &mut MetadataObjs) -> GosMetadata {
+    pub fn new_named(
+        name: String,
+        underlying: GosMetadata,
+        metas: &mut MetadataObjs,
+    ) -> GosMetadata {
         debug_assert!(underlying.get_value_type(metas) != ValueType::Named);
-        GosMetadata::new(MetadataType::Named(Methods::new(), underlying), metas)
+        GosMetadata::new(MetadataType::Named(Methods::new(), underlying, name), metas)
     }
 
     pub fn new_slice_from_array(array: GosMetadata) -> GosMetadata {
@@ -279,8 +304,8 @@ impl GosMetadata {
                     MetadataType::SliceOrArray(_, _) => ValueType::Slice,
                     MetadataType::Map(_, _) => ValueType::Map,
                     MetadataType::Interface(_) => ValueType::Interface,
-                    MetadataType::Channel => ValueType::Channel,
-                    MetadataType::Named(_, _) => ValueType::Named,
+                    MetadataType::Channel(_, _) => ValueType::Channel,
+                    MetadataType::Named(_, _, _) => ValueType::Named,
                 },
                 MetaCategory::Type | MetaCategory::ArrayType => ValueType::Metadata,
                 MetaCategory::Array => ValueType::Array,
@@ -334,8 +359,8 @@ impl GosMetadata {
                     GosValue::new_map_nil(*self, v.default_val(mobjs, gcos), gcos)
                 }
                 MetadataType::Interface(_) => GosValue::Nil(*self),
-                MetadataType::Channel => GosValue::Nil(*self),
-                MetadataType::Named(_, gm) => {
+                MetadataType::Channel(_, _) => GosValue::Nil(*self),
+                MetadataType::Named(_, gm, _) => {
                     let val = gm.default_val(mobjs, gcos);
                     GosValue::Named(Box::new((val, *gm)))
                 }
@@ -380,8 +405,8 @@ impl GosMetadata {
                     GosValue::new_map(*self, v.default_val(mobjs, gcos), gcos)
                 }
                 MetadataType::Interface(_) => GosValue::Nil(*self),
-                MetadataType::Channel => unimplemented!(),
-                MetadataType::Named(_, gm) => {
+                MetadataType::Channel(_, _) => GosValue::Nil(*self),
+                MetadataType::Named(_, gm, _) => {
                     let val = gm.default_val(mobjs, gcos);
                     GosValue::Named(Box::new((val, *gm)))
                 }
@@ -406,7 +431,7 @@ impl GosMetadata {
     pub fn get_underlying(&self, metas: &MetadataObjs) -> GosMetadata {
         match self {
             GosMetadata::NonPtr(k, _) => match &metas[*k] {
-                MetadataType::Named(_, u) => *u,
+                MetadataType::Named(_, u, _) => *u,
                 _ => *self,
             },
             _ => *self,
@@ -425,7 +450,7 @@ impl GosMetadata {
     pub fn add_method(&self, name: String, pointer_recv: bool, metas: &mut MetadataObjs) {
         let k = self.recv_meta_key();
         match &mut metas[k] {
-            MetadataType::Named(m, _) => {
+            MetadataType::Named(m, _, _) => {
                 m.members.push(Rc::new(RefCell::new(MethodDesc {
                     pointer_recv: pointer_recv,
                     func: None,
@@ -439,7 +464,7 @@ impl GosMetadata {
     pub fn set_method_code(&self, name: &String, func: FunctionKey, metas: &mut MetadataObjs) {
         let k = self.recv_meta_key();
         match &mut metas[k] {
-            MetadataType::Named(m, _) => {
+            MetadataType::Named(m, _, _) => {
                 let index = m.mapping[name] as usize;
                 m.members[index].borrow_mut().func = Some(func);
             }
@@ -454,7 +479,7 @@ impl GosMetadata {
     ) -> (&'a Methods, &'a GosMetadata) {
         let k = self.recv_meta_key();
         match &metas[k] {
-            MetadataType::Named(methods, md) => (methods, md),
+            MetadataType::Named(methods, md, _) => (methods, md),
             _ => unreachable!(),
         }
     }
@@ -483,6 +508,80 @@ impl GosMetadata {
         }
     }
 
+    /// type_name returns the name of the type in Go syntax, for runtime error messages
+    pub fn type_name(&self, metas: &MetadataObjs) -> String {
+        let (k, mc, ptrs) = match self {
+            GosMetadata::Untyped => return "nil".to_string(),
+            GosMetadata::NonPtr(k, mc) => (k, mc, 0),
+            GosMetadata::Ptr1(k, mc) => (k, mc, 1),
+            GosMetadata::Ptr2(k, mc) => (k, mc, 2),
+            GosMetadata::Ptr3(k, mc) => (k, mc, 3),
+            GosMetadata::Ptr4(k, mc) => (k, mc, 4),
+            GosMetadata::Ptr5(k, mc) => (k, mc, 5),
+            GosMetadata::Ptr6(k, mc) => (k, mc, 6),
+            GosMetadata::Ptr7(k, mc) => (k, mc, 7),
+        };
+        let fields_str =
+            |fields: &Fields, sep: &str, f: &dyn Fn(&String, &GosMetadata) -> String| {
+                let list: Vec<String> = fields
+                    .iface_ffi_info()
+                    .iter()
+                    .map(|(n, m)| f(n, m))
+                    .collect();
+                if list.is_empty() {
+                    "{}".to_string()
+                } else {
+                    format!("{{ {} }}", list.join(sep))
+                }
+            };
+        let name = match &metas[*k] {
+            MetadataType::Bool => "bool".to_string(),
+            MetadataType::Int => "int".to_string(),
+            MetadataType::Int8 => "int8".to_string(),
+            MetadataType::Int16 => "int16".to_string(),
+            MetadataType::Int32 => "int32".to_string(),
+            MetadataType::Int64 => "int64".to_string(),
+            MetadataType::Uint => "uint".to_string(),
+            MetadataType::Uint8 => "uint8".to_string(),
+            MetadataType::Uint16 => "uint16".to_string(),
+            MetadataType::Uint32 => "uint32".to_string(),
+            MetadataType::Uint64 => "uint64".to_string(),
+            MetadataType::Float32 => "float32".to_string(),
+            MetadataType::Float64 => "float64".to_string(),
+            MetadataType::Complex64 => "complex64".to_string(),
+            MetadataType::Complex128 => "complex128".to_string(),
+            MetadataType::Str(_) => "string".to_string(),
+            MetadataType::SliceOrArray(m, size) => match mc {
+                MetaCategory::Array | MetaCategory::ArrayType => {
+                    format!("[{}]{}", size, m.type_name(metas))
+                }
+                _ => format!("[]{}", m.type_name(metas)),
+            },
+            MetadataType::Struct(f, _) => format!(
+                "struct {}",
+                fields_str(f, "; ", &|n, m| format!("{} {}", n, m.type_name(metas)))
+            ),
+            MetadataType::Signature(sig) => format!("func{}", sig.type_name(metas)),
+            MetadataType::Map(k, v) => {
+                format!("map[{}]{}", k.type_name(metas), v.type_name(metas))
+            }
+            MetadataType::Interface(f) => format!(
+                "interface {}",
+                fields_str(f, "; ", &|n, m| match &metas[m.as_non_ptr()] {
+                    MetadataType::Signature(sig) => format!("{}{}", n, sig.type_name(metas)),
+                    _ => n.clone(),
+                })
+            ),
+            MetadataType::Channel(t, m) => match t {
+                ChannelType::Send => format!("chan<- {}", m.type_name(metas)),
+                ChannelType::Recv => format!("<-chan {}", m.type_name(metas)),
+                ChannelType::SendRecv => format!("chan {}", m.type_name(metas)),
+            },
+            MetadataType::Named(_, _, name) => name.clone(),
+        };
+        format!("{}{}", "*".repeat(ptrs), name)
+    }
+
     pub fn semantic_eq(&self, other: &Self, metas: &MetadataObjs) -> bool {
         match (self, other) {
             (Self::NonPtr(ak, ac), Self::NonPtr(bk, bc)) => {
@@ -622,6 +721,21 @@ impl Default for SigMetadata {
 }
 
 impl SigMetadata {
+    /// type_name returns the signature without the "func" keyword, like "(int, string) bool"
+    pub fn type_name(&self, metas: &MetadataObjs) -> String {
+        let mut params: Vec<String> = self.params.iter().map(|x| x.type_name(metas)).collect();
+        if let Some((_, elem)) = &self.variadic {
+            params.pop();
+            params.push(format!("...{}", elem.type_name(metas)));
+        }
+        let results: Vec<String> = self.results.iter().map(|x| x.type_name(metas)).collect();
+        match results.len() {
+            0 => format!("({})", params.join(", ")),
+            1 => format!("({}) {}", params.join(", "), results[0]),
+            _ => format!("({}) ({})", params.join(", "), results.join(", ")),
+        }
+    }
+
     pub fn pointer_recv(&self) -> bool {
         if let Some(r) = &self.recv {
             match r {
@@ -641,7 +755,12 @@ impl SigMetadata {
         } {
             return false;
         }
+        self.semantic_eq_no_recv(other, metas)
+    }
 
+    /// semantic_eq_no_recv compares the signatures ignoring the receivers, as when
+    /// checking a method against the method of an interface
+    pub fn semantic_eq_no_recv(&self, other: &Self, metas: &MetadataObjs) -> bool {
         if self.params.len() != other.params.len() {
             return false;
         }
@@ -651,7 +770,7 @@ impl SigMetadata {
             }
         }
 
-        if self.results.len() != other.params.len() {
+        if self.results.len() != other.results.len() {
             return false;
         }
         for (i, r) in self.results.iter().enumerate() {
@@ -693,8 +812,8 @@ pub enum MetadataType {
     Signature(SigMetadata),
     Map(GosMetadata, GosMetadata),
     Interface(Fields),
-    Channel, //todo
-    Named(Methods, GosMetadata),
+    Channel(ChannelType, GosMetadata),
+    Named(Methods, GosMetadata, String),
 }
 
 impl MetadataType {
@@ -722,6 +841,7 @@ impl MetadataType {
             (Self::Int16, Self::Int16) => true,
             (Self::Int32, Self::Int32) => true,
             (Self::Int64, Self::Int64) => true,
+            (Self::Uint, Self::Uint) => true,
             (Self::Uint8, Self::Uint8) => true,
             (Self::Uint16, Self::Uint16) => true,
             (Self::Uint32, Self::Uint32) => true,
@@ -748,8 +868,10 @@ impl MetadataType {
                 ak.semantic_eq(bk, metas) && av.semantic_eq(bv, metas)
             }
             (Self::Interface(a), Self::Interface(b)) => a.semantic_eq(b, metas),
-            (Self::Channel, Self::Channel) => unimplemented!(),
-            (Self::Named(_, a), Self::Named(_, b)) => a.semantic_eq(b, metas),
+            (Self::Channel(at, avt), Self::Channel(bt, bvt)) => {
+                at == bt && avt.semantic_eq(bvt, metas)
+            }
+            (Self::Named(_, a, an), Self::Named(_, b, bn)) => an == bn && a.semantic_eq(b, metas),
             _ => false,
         }
     }
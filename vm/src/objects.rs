@@ -3,13 +3,14 @@ use super::ffi::Ffi;
 use super::gc::GcObjs;
 use super::instruction::{Instruction, OpIndex, Opcode, ValueType};
 use super::metadata::*;
-use super::value::{rcount_mark_and_queue, GosValue, RCQueue, RCount};
+use super::stack::Stack;
+use super::value::{GosValue, RCount};
 use goscript_parser::objects::{EntityKey, IdentKey};
 use slotmap::{new_key_type, DenseSlotMap};
 use std::cell::Cell;
 use std::cell::{Ref, RefCell, RefMut};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt::Write;
@@ -65,7 +66,7 @@ impl VMObjects {
         let mut metas = DenseSlotMap::with_capacity_and_key(DEFAULT_CAPACITY);
         let md = Metadata::new(&mut metas);
         VMObjects {
-            gcobjs: vec![],
+            gcobjs: GcObjs::new(),
             metas: metas,
             functions: DenseSlotMap::with_capacity_and_key(DEFAULT_CAPACITY),
             packages: DenseSlotMap::with_capacity_and_key(DEFAULT_CAPACITY),
@@ -104,6 +105,12 @@ impl StringObj {
         &self.data.as_ref()[self.begin..self.end]
     }
 
+    /// the string this one is a part of, which may be shared by others
+    #[inline]
+    pub fn data(&self) -> &Rc<String> {
+        &self.data
+    }
+
     #[inline]
     pub fn into_string(self) -> String {
         Rc::try_unwrap(self.data).unwrap()
@@ -262,9 +269,17 @@ impl MapObj {
         }
     }
 
+    /// delete removes the value of 'key', it's a no-op for nil maps
+    #[inline]
+    pub fn delete(&self, key: &GosValue) {
+        if let Some(m) = &self.map {
+            m.borrow_mut().remove(key);
+        }
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
-        self.borrow_data().len()
+        self.map.as_ref().map_or(0, |m| m.borrow().len())
     }
 
     #[inline]
@@ -595,16 +610,32 @@ impl<'a> SliceObj {
         *self.end.get_mut() += 1;
     }
 
+    /// append writes the values after the end of the slice, the elements of the
+    /// underlying vec there are overwritten if the capacity is enough
     #[inline]
     pub fn append(&mut self, vals: &mut GosVec) {
         let new_len = self.len() + vals.len();
         self.try_grow_vec(new_len);
-        self.borrow_data_mut().append(vals);
+        let end = self.end();
+        {
+            let mut data = self.borrow_data_mut();
+            for (i, v) in vals.drain(..).enumerate() {
+                if end + i < data.len() {
+                    data[end + i] = v;
+                } else {
+                    data.push(v);
+                }
+            }
+        }
         *self.end.get_mut() = self.begin() + new_len;
+        self.is_nil = false;
     }
 
     #[inline]
     pub fn get(&self, i: usize) -> Option<GosValue> {
+        if i >= self.len() {
+            return None;
+        }
         self.borrow_data()
             .get(self.begin() + i)
             .map(|x| x.clone().into_inner())
@@ -635,7 +666,7 @@ impl<'a> SliceObj {
 
     #[inline]
     pub fn get_vec(&self) -> Vec<GosValue> {
-        self.borrow_data()
+        self.borrow_data()[self.begin()..self.end()]
             .iter()
             .map(|x| x.borrow().clone())
             .collect()
@@ -649,7 +680,7 @@ impl<'a> SliceObj {
         }
         while cap < len {
             if cap < 1024 {
-                cap *= 2
+                cap = (cap * 2).max(1)
             } else {
                 cap = (cap as f32 * 1.25) as usize
             }
@@ -859,22 +890,6 @@ impl InterfaceObj {
     pub fn is_nil(&self) -> bool {
         self.underlying() == &IfaceUnderlying::None
     }
-
-    /// for gc
-    pub fn ref_sub_one(&self) {
-        match self.underlying() {
-            IfaceUnderlying::Gos(v, _) => v.ref_sub_one(),
-            _ => {}
-        };
-    }
-
-    /// for gc
-    pub fn mark_dirty(&self, queue: &mut RCQueue) {
-        match self.underlying() {
-            IfaceUnderlying::Gos(v, _) => v.mark_dirty(queue),
-            _ => {}
-        };
-    }
 }
 
 impl Eq for InterfaceObj {}
@@ -901,7 +916,10 @@ impl Display for InterfaceObj {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.underlying() {
             IfaceUnderlying::Gos(v, _) => write!(f, "{}", v),
-            IfaceUnderlying::Ffi(ffi) => write!(f, "<ffi>{:?}", ffi.ffi_obj.borrow()),
+            IfaceUnderlying::Ffi(ffi) => match ffi.ffi_obj.try_borrow() {
+                Ok(obj) => write!(f, "<ffi>{:?}", obj),
+                Err(_) => f.write_str("<ffi>"),
+            },
             IfaceUnderlying::None => f.write_str("<nil>"),
         }
     }
@@ -910,8 +928,186 @@ impl Display for InterfaceObj {
 // ----------------------------------------------------------------------------
 // ChannelObj
 
-#[derive(Clone, Debug)]
-pub struct ChannelObj {}
+/// WaitState is shared between a parked fiber and the channels it waits on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitState {
+    Parked,
+    Woken,
+    Closed, // woken up by 'close', only matters to senders
+}
+
+pub type Waker = Rc<Cell<WaitState>>;
+
+#[inline]
+pub fn new_waker() -> Waker {
+    Rc::new(Cell::new(WaitState::Parked))
+}
+
+/// wake_all wakes up all the fibers in the queue that are still parked, they
+/// retry and the ones that fail park again. A fiber blocked in 'select' may be
+/// parked on more than one channel, so waking up only one of the receivers
+/// could leave the others sleeping forever.
+#[inline]
+fn wake_all(queue: &mut VecDeque<Waker>) {
+    for w in queue.drain(..) {
+        if w.get() == WaitState::Parked {
+            w.set(WaitState::Woken);
+        }
+    }
+}
+
+/// drop_dead drops the wakers that are not parked anymore. A fiber blocked in
+/// 'select' leaves its waker on all the channels, but only the channel that
+/// wakes it up drains it, so the others have to get rid of it.
+#[inline]
+fn drop_dead(queue: &mut VecDeque<Waker>) {
+    queue.retain(|w| w.get() == WaitState::Parked);
+}
+
+pub enum RecvResult {
+    Value(GosValue),
+    Closed(GosValue), // the zero value
+    WouldBlock,
+}
+
+/// ChannelObj is the channel shared by fibers. Values of an unbuffered channel are
+/// handed over directly from the queue of parked senders; receivers that cannot
+/// proceed park themselves and retry when woken up.
+#[derive(Debug)]
+pub struct ChannelObj {
+    pub meta: GosMetadata,
+    cap: usize,
+    zero_val: GosValue,
+    buffer: VecDeque<GosValue>,
+    closed: bool,
+    recvq: VecDeque<Waker>,
+    sendq: VecDeque<(Waker, GosValue)>,
+    // fibers blocked in 'select' waiting for a receiver to show up
+    send_waiters: VecDeque<Waker>,
+}
+
+impl ChannelObj {
+    pub fn new(meta: GosMetadata, cap: usize, zero_val: GosValue) -> ChannelObj {
+        ChannelObj {
+            meta: meta,
+            cap: cap,
+            zero_val: zero_val,
+            buffer: VecDeque::with_capacity(cap),
+            closed: false,
+            recvq: VecDeque::new(),
+            sendq: VecDeque::new(),
+            send_waiters: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    #[inline]
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// send puts the value into the buffer if there is room, otherwise the value
+    /// is queued and the returned waker is what the sender should park on
+    pub fn send(&mut self, val: GosValue) -> Result<Option<Waker>, String> {
+        if self.closed {
+            return Err("send on closed channel".to_string());
+        }
+        let ret = if self.buffer.len() < self.cap {
+            self.buffer.push_back(val);
+            None
+        } else {
+            let waker = new_waker();
+            self.sendq.push_back((waker.clone(), val));
+            Some(waker)
+        };
+        wake_all(&mut self.recvq);
+        Ok(ret)
+    }
+
+    /// can_send tells if a send would not block, sending on a closed channel
+    /// doesn't block, it panics
+    pub fn can_send(&self) -> bool {
+        self.closed
+            || self.buffer.len() < self.cap
+            || self.recvq.iter().any(|w| w.get() == WaitState::Parked)
+    }
+
+    pub fn can_recv(&self) -> bool {
+        self.closed
+            || !self.buffer.is_empty()
+            || self.sendq.iter().any(|(w, _)| w.get() == WaitState::Parked)
+    }
+
+    pub fn try_recv(&mut self) -> RecvResult {
+        if let Some(v) = self.buffer.pop_front() {
+            // make room for the first parked sender
+            if let Some(sv) = self.pop_sender() {
+                self.buffer.push_back(sv);
+            }
+            RecvResult::Value(v)
+        } else if let Some(sv) = self.pop_sender() {
+            RecvResult::Value(sv)
+        } else if self.closed {
+            RecvResult::Closed(self.zero_val.clone())
+        } else {
+            RecvResult::WouldBlock
+        }
+    }
+
+    /// add_receiver parks a receiver, the fibers in 'select' that wait to send
+    /// are woken up to check again
+    #[inline]
+    pub fn add_receiver(&mut self, waker: Waker) {
+        drop_dead(&mut self.recvq);
+        self.recvq.push_back(waker);
+        wake_all(&mut self.send_waiters);
+    }
+
+    #[inline]
+    pub fn add_send_waiter(&mut self, waker: Waker) {
+        drop_dead(&mut self.send_waiters);
+        self.send_waiters.push_back(waker);
+    }
+
+    pub fn close(&mut self) -> Result<(), String> {
+        if self.closed {
+            return Err("close of closed channel".to_string());
+        }
+        self.closed = true;
+        wake_all(&mut self.recvq);
+        wake_all(&mut self.send_waiters);
+        for (w, _) in self.sendq.drain(..) {
+            if w.get() == WaitState::Parked {
+                w.set(WaitState::Closed);
+            }
+        }
+        Ok(())
+    }
+
+    /// values held by the channel, for the GC
+    pub fn values(&self) -> impl Iterator<Item = &GosValue> {
+        self.buffer.iter().chain(self.sendq.iter().map(|(_, v)| v))
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.sendq.clear();
+    }
+
+    fn pop_sender(&mut self) -> Option<GosValue> {
+        while let Some((w, v)) = self.sendq.pop_front() {
+            if w.get() == WaitState::Parked {
+                w.set(WaitState::Woken);
+                return Some(v);
+            }
+        }
+        None
+    }
+}
 
 // ----------------------------------------------------------------------------
 // PointerObj
@@ -990,32 +1186,6 @@ impl PointerObj {
             _ => unreachable!(),
         }
     }
-
-    /// for gc
-    pub fn ref_sub_one(&self) {
-        match &self {
-            PointerObj::UpVal(uv) => uv.ref_sub_one(),
-            PointerObj::Struct(s, _) => s.1.set(s.1.get() - 1),
-            PointerObj::Slice(s, _) => s.1.set(s.1.get() - 1),
-            PointerObj::Map(s, _) => s.1.set(s.1.get() - 1),
-            PointerObj::SliceMember(s, _) => s.1.set(s.1.get() - 1),
-            PointerObj::StructField(s, _) => s.1.set(s.1.get() - 1),
-            _ => {}
-        };
-    }
-
-    /// for gc
-    pub fn mark_dirty(&self, queue: &mut RCQueue) {
-        match &self {
-            PointerObj::UpVal(uv) => uv.mark_dirty(queue),
-            PointerObj::Struct(s, _) => rcount_mark_and_queue(&s.1, queue),
-            PointerObj::Slice(s, _) => rcount_mark_and_queue(&s.1, queue),
-            PointerObj::Map(s, _) => rcount_mark_and_queue(&s.1, queue),
-            PointerObj::SliceMember(s, _) => rcount_mark_and_queue(&s.1, queue),
-            PointerObj::StructField(s, _) => rcount_mark_and_queue(&s.1, queue),
-            _ => {}
-        };
-    }
 }
 
 impl Eq for PointerObj {}
@@ -1084,10 +1254,13 @@ impl Display for PointerObj {
 #[derive(Clone, Debug)]
 pub struct ValueDesc {
     pub func: FunctionKey,
-    pub frame: OpIndex,
     pub index: OpIndex,
     pub typ: ValueType,
     pub is_up_value: bool,
+    // the stack of the fiber the var lives in, which is not necessarily the
+    // stack of the running fiber when goroutines are involved
+    pub(crate) stack: Weak<RefCell<Stack>>,
+    pub(crate) stack_base: usize,
 }
 
 impl Eq for ValueDesc {}
@@ -1100,13 +1273,25 @@ impl PartialEq for ValueDesc {
 }
 
 impl ValueDesc {
-    pub fn clone_with_frame(&self, frame: OpIndex) -> ValueDesc {
+    pub fn new(func: FunctionKey, index: OpIndex, typ: ValueType, is_up_value: bool) -> ValueDesc {
+        ValueDesc {
+            func: func,
+            index: index,
+            typ: typ,
+            is_up_value: is_up_value,
+            stack: Weak::new(),
+            stack_base: 0,
+        }
+    }
+
+    pub(crate) fn clone_with_stack(&self, stack: Weak<RefCell<Stack>>, sbase: usize) -> ValueDesc {
         ValueDesc {
             func: self.func,
-            frame: frame,
             index: self.index,
             typ: self.typ,
             is_up_value: self.is_up_value,
+            stack: stack,
+            stack_base: sbase,
         }
     }
 }
@@ -1154,22 +1339,6 @@ impl UpValue {
     pub fn close(&self, val: GosValue) {
         *self.inner.borrow_mut() = UpValueState::Closed(val);
     }
-
-    /// for gc
-    pub fn ref_sub_one(&self) {
-        let state: &UpValueState = &self.inner.borrow();
-        if let UpValueState::Closed(uvs) = state {
-            uvs.ref_sub_one()
-        }
-    }
-
-    /// for gc
-    pub fn mark_dirty(&self, queue: &mut RCQueue) {
-        let state: &UpValueState = &self.inner.borrow();
-        if let UpValueState::Closed(uvs) = state {
-            uvs.mark_dirty(queue)
-        }
-    }
 }
 
 impl Hash for UpValue {
@@ -1256,34 +1425,6 @@ impl ClosureObj {
             None => self.ffi.as_ref().unwrap().meta,
         }
     }
-
-    /// for gc
-    pub fn ref_sub_one(&self) {
-        if self.func.is_some() {
-            if let Some(uvs) = &self.uvs {
-                for (_, v) in uvs.iter() {
-                    v.ref_sub_one()
-                }
-            }
-            if let Some(recv) = &self.recv {
-                recv.ref_sub_one()
-            }
-        }
-    }
-
-    /// for gc
-    pub fn mark_dirty(&self, queue: &mut RCQueue) {
-        if self.func.is_some() {
-            if let Some(uvs) = &self.uvs {
-                for (_, v) in uvs.iter() {
-                    v.mark_dirty(queue)
-                }
-            }
-            if let Some(recv) = &self.recv {
-                recv.mark_dirty(queue)
-            }
-        }
-    }
 }
 
 // ----------------------------------------------------------------------------
@@ -1310,6 +1451,11 @@ impl PackageVal {
         }
     }
 
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn add_member(&mut self, name: String, val: GosValue) -> OpIndex {
         self.members.push(val);
         let index = (self.members.len() - 1) as OpIndex;
@@ -1356,6 +1502,17 @@ impl PackageVal {
     pub fn member_mut(&mut self, i: OpIndex) -> &mut GosValue {
         &mut self.members[i as usize]
     }
+
+    #[inline]
+    pub fn members(&self) -> &[GosValue] {
+        &self.members
+    }
+
+    /// clear_members drops the values of all the members, for tearing down
+    /// the VM
+    pub fn clear_members(&mut self) {
+        self.members.clear();
+    }
 }
 
 // ----------------------------------------------------------------------------
@@ -1625,3 +1782,27 @@ impl FunctionVal {
         et
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_drop_dead_wakers() {
+        let mut chan = ChannelObj::new(GosMetadata::Untyped, 0, GosValue::Int(0));
+        let parked = new_waker();
+        chan.add_receiver(parked.clone());
+        for _ in 0..100 {
+            // woken up by another channel of a 'select'
+            let w = new_waker();
+            chan.add_receiver(w.clone());
+            chan.add_send_waiter(w.clone());
+            w.set(WaitState::Woken);
+        }
+        chan.add_receiver(new_waker());
+        chan.add_send_waiter(new_waker());
+        assert_eq!(chan.recvq.len(), 2);
+        assert_eq!(chan.send_waiters.len(), 1);
+        assert!(chan.can_send());
+    }
+}
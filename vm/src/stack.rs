@@ -9,6 +9,9 @@ use std::fmt::{self, Display};
 use std::rc::Rc;
 
 const DEFAULT_SIZE: usize = 10240;
+/// the number of slots the stack can grow to, the VM stops a fiber whose
+/// stack goes over it with ErrorKind::StackLimit
+pub const MAX_SIZE: usize = DEFAULT_SIZE * 128;
 
 macro_rules! stack_binary_op {
     ($stack:ident, $op:tt, $t:ident) => {{
@@ -59,7 +62,7 @@ impl Stack {
             c: vec![GosValue64::nil(); DEFAULT_SIZE],
             rc: vec![GosValue::new_nil(); DEFAULT_SIZE],
             cursor: 0,
-            max: DEFAULT_SIZE - 1,
+            max: MAX_SIZE - 1,
         }
     }
 
@@ -73,7 +76,7 @@ impl Stack {
             *self.get_rc_mut(self.cursor) = val;
         }
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
@@ -84,35 +87,35 @@ impl Stack {
             *self.get_rc_mut(self.cursor) = self.get_rc(index).clone();
         }
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
     pub fn push_nil(&mut self) {
         *self.get_rc_mut(self.cursor) = GosValue::new_nil();
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
     pub fn push_bool(&mut self, b: bool) {
         *self.get_c_mut(self.cursor) = GosValue64::from_bool(b);
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
     pub fn push_int(&mut self, i: isize) {
         *self.get_c_mut(self.cursor) = GosValue64::from_int(i);
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
     pub fn push_int32_as(&mut self, i: i32, t: ValueType) {
         *self.get_c_mut(self.cursor) = GosValue64::from_int32_as(i, t);
         self.cursor += 1;
-        assert!(self.cursor <= self.max); //todo: expand
+        self.grow_if_full();
     }
 
     #[inline]
@@ -248,6 +251,42 @@ impl Stack {
         }
     }
 
+    /// moves everything above 'index' to the top of another stack,
+    /// the types are not needed because both parts of the slots are moved.
+    /// Nothing is moved if the other stack doesn't have the room
+    pub fn move_to(&mut self, index: usize, other: &mut Stack) -> RuntimeResult {
+        other.check_room(self.cursor - index)?;
+        let nil = GosValue::new_nil();
+        for i in index..self.cursor {
+            *other.get_c_mut(other.cursor) = self.c[i];
+            *other.get_rc_mut(other.cursor) = std::mem::replace(&mut self.rc[i], nil.clone());
+            other.cursor += 1;
+        }
+        self.cursor = index;
+        Ok(())
+    }
+
+    /// moves everything above 'index' out of the stack, along with both parts
+    /// of the slots, they can be put back with 'append_slots'
+    pub fn split_off_slots(&mut self, index: usize) -> Vec<(GosValue64, GosValue)> {
+        let nil = GosValue::new_nil();
+        let slots = (index..self.cursor)
+            .map(|i| (self.c[i], std::mem::replace(&mut self.rc[i], nil.clone())))
+            .collect();
+        self.cursor = index;
+        slots
+    }
+
+    pub fn append_slots(&mut self, slots: Vec<(GosValue64, GosValue)>) -> RuntimeResult {
+        self.check_room(slots.len())?;
+        for (c, rc) in slots.into_iter() {
+            *self.get_c_mut(self.cursor) = c;
+            *self.get_rc_mut(self.cursor) = rc;
+            self.cursor += 1;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn split_off_with_type(&mut self, index: usize, t: ValueType) -> Vec<GosValue> {
         let end = self.cursor;
@@ -270,7 +309,14 @@ impl Stack {
     }
 
     #[inline]
-    pub fn store_with_op(&mut self, li: usize, ri: usize, op: Opcode, t: ValueType) {
+    pub fn store_with_op(
+        &mut self,
+        li: usize,
+        ri: usize,
+        op: Opcode,
+        t: ValueType,
+        gcos: &mut GcObjs,
+    ) {
         if t.copyable() {
             let a = self.get_c(li);
             let b = self.get_c(ri);
@@ -278,7 +324,7 @@ impl Stack {
         } else {
             let a = self.get_rc(li);
             let b = self.get_rc(ri);
-            *self.get_rc_mut(li) = GosValue::add_str(a, b);
+            *self.get_rc_mut(li) = GosValue::add_str(a, b, gcos);
         }
     }
 
@@ -306,7 +352,7 @@ impl Stack {
                 let v = GosValue64::binary_op(&a, b, t, op);
                 v.get_v128(t)
             } else {
-                GosValue::add_str(target, self.get_rc(ri))
+                GosValue::add_str(target, self.get_rc(ri), gcos)
             }
         };
         *target = val;
@@ -332,6 +378,44 @@ impl Stack {
         self.get_c_mut(self.cursor - 1).to_uint32(t)
     }
 
+    /// check_room makes room for 'n' more slots, unless the stack would go
+    /// over MAX_SIZE
+    #[inline]
+    fn check_room(&mut self, n: usize) -> RuntimeResult {
+        if self.cursor + n > self.max {
+            return Err("stack overflow".to_string());
+        }
+        while self.cursor + n >= self.c.len() {
+            self.grow();
+        }
+        Ok(())
+    }
+
+    /// grow_if_full keeps a free slot at the top, the pushes don't check
+    /// MAX_SIZE, the VM checks it when a call is entered
+    #[inline]
+    fn grow_if_full(&mut self) {
+        if self.cursor == self.c.len() {
+            self.grow();
+        }
+    }
+
+    #[cold]
+    fn grow(&mut self) {
+        let size = self.c.len() * 2;
+        self.c.resize(size, GosValue64::nil());
+        self.rc.resize(size, GosValue::new_nil());
+    }
+
+    #[inline]
+    fn check_divisor(&self, t: ValueType) -> RuntimeResult {
+        if self.get_c(self.cursor - 1).is_zero_divisor(t) {
+            Err("integer divide by zero".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
     #[inline]
     pub fn init_pkg_vars(&mut self, pkg: &mut PackageVal, count: usize) {
         for i in 0..count {
@@ -343,13 +427,13 @@ impl Stack {
     }
 
     #[inline]
-    pub fn add(&mut self, t: ValueType) {
+    pub fn add(&mut self, t: ValueType, gcos: &mut GcObjs) {
         if t.copyable() {
             stack_binary_op!(self, binary_op_add, t)
         } else {
             let a = self.get_rc(self.len() - 2);
             let b = self.get_rc(self.len() - 1);
-            *self.get_rc_mut(self.len() - 2) = GosValue::add_str(a, b);
+            *self.get_rc_mut(self.len() - 2) = GosValue::add_str(a, b, gcos);
             self.pop_discard();
         }
     }
@@ -385,13 +469,17 @@ impl Stack {
     }
 
     #[inline]
-    pub fn quo(&mut self, t: ValueType) {
-        stack_binary_op!(self, binary_op_quo, t)
+    pub fn quo(&mut self, t: ValueType) -> RuntimeResult {
+        self.check_divisor(t)?;
+        stack_binary_op!(self, binary_op_quo, t);
+        Ok(())
     }
 
     #[inline]
-    pub fn rem(&mut self, t: ValueType) {
-        stack_binary_op!(self, binary_op_rem, t)
+    pub fn rem(&mut self, t: ValueType) -> RuntimeResult {
+        self.check_divisor(t)?;
+        stack_binary_op!(self, binary_op_rem, t);
+        Ok(())
     }
 
     #[inline]
@@ -531,9 +619,25 @@ impl Stack {
         *self.get_rc_mut(self.cursor - 1) = self.get_c(self.cursor - 1).get_v128(t)
     }
 
+    /// the non-copyable values in the slots in use, the slots holding
+    /// copyable ones may have stale values left
+    #[inline]
+    pub fn rc_values(&self) -> &[GosValue] {
+        &self.rc[..self.cursor]
+    }
+
+    /// clear_rc_garbage drops the values above the top, and the slots the
+    /// stack has grown by
     pub fn clear_rc_garbage(&mut self) {
+        let size = DEFAULT_SIZE.max(self.cursor + 1);
+        if self.c.len() > size {
+            self.c.truncate(size);
+            self.c.shrink_to_fit();
+            self.rc.truncate(size);
+            self.rc.shrink_to_fit();
+        }
         let nil = GosValue::new_nil();
-        for i in self.cursor..self.max {
+        for i in self.cursor..self.rc.len() {
             self.rc[i] = nil.clone();
         }
     }
@@ -553,4 +657,27 @@ mod test {
         s.set(0, v2.clone());
         //assert_eq!(s.get(0, ValueType::Str), v2);
     }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut s = Stack::new();
+        let mut full = Stack::new();
+        s.push(GosValue::Int(1));
+        s.push(GosValue::Int(2));
+        let slots = s.split_off_slots(0);
+        while full.len() < full.max - 1 {
+            full.push_int(0);
+        }
+        let len = full.len();
+        assert!(full.append_slots(slots).is_err());
+        assert_eq!(full.len(), len);
+
+        s.push(GosValue::Int(1));
+        s.push(GosValue::Int(2));
+        assert!(s.move_to(0, &mut full).is_err());
+        assert_eq!((s.len(), full.len()), (2, len));
+        full.pop_discard();
+        assert!(s.move_to(0, &mut full).is_ok());
+        assert_eq!((s.len(), full.len()), (0, len + 1));
+    }
 }
@@ -7,7 +7,6 @@ use super::stack::Stack;
 use ordered_float;
 use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::collections::VecDeque;
 use std::fmt::{self, Display};
 use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
@@ -18,16 +17,6 @@ type F32 = ordered_float::OrderedFloat<f32>;
 type F64 = ordered_float::OrderedFloat<f64>;
 pub type IRC = i32;
 pub type RCount = Cell<IRC>;
-pub type RCQueue = VecDeque<IRC>;
-
-#[inline]
-pub fn rcount_mark_and_queue(rc: &RCount, queue: &mut RCQueue) {
-    let i = rc.get();
-    if i <= 0 {
-        queue.push_back(i);
-        rc.set(1);
-    }
-}
 
 macro_rules! unwrap_gos_val {
     ($name:tt, $self_:ident) => {
@@ -166,7 +155,7 @@ macro_rules! shift_int {
             ValueType::Uint32 => union_shift!($a, $b, uint32, $op),
             ValueType::Uint64 => union_shift!($a, $b, uint64, $op),
             _ => unreachable!(),
-        };
+        }
     };
 }
 
@@ -237,6 +226,7 @@ impl GosValue {
         meta: GosMetadata,
         gcobjs: &mut GcObjs,
     ) -> GosValue {
+        gcobjs.alloc_values(size);
         let arr = Rc::new((ArrayObj::with_size(size, val, meta, gcobjs), Cell::new(0)));
         let v = GosValue::Array(arr);
         gcobjs.push(GcWeak::from_gosv(&v));
@@ -245,6 +235,7 @@ impl GosValue {
 
     #[inline]
     pub fn array_with_val(val: Vec<GosValue>, meta: GosMetadata, gcobjs: &mut GcObjs) -> GosValue {
+        gcobjs.alloc_values(val.len());
         let arr = Rc::new((ArrayObj::with_data(val, meta), Cell::new(0)));
         let v = GosValue::Array(arr);
         gcobjs.push(GcWeak::from_gosv(&v));
@@ -259,6 +250,7 @@ impl GosValue {
         dval: Option<&GosValue>,
         gcobjs: &mut GcObjs,
     ) -> GosValue {
+        gcobjs.alloc_values(cap);
         let s = Rc::new((SliceObj::new(len, cap, meta, dval), Cell::new(0)));
         let v = GosValue::Slice(s);
         gcobjs.push(GcWeak::from_gosv(&v));
@@ -275,6 +267,7 @@ impl GosValue {
 
     #[inline]
     pub fn slice_with_val(val: Vec<GosValue>, meta: GosMetadata, gcobjs: &mut GcObjs) -> GosValue {
+        gcobjs.alloc_values(val.len());
         let s = Rc::new((SliceObj::with_data(val, meta), Cell::new(0)));
         let v = GosValue::Slice(s);
         gcobjs.push(GcWeak::from_gosv(&v));
@@ -305,6 +298,23 @@ impl GosValue {
         v
     }
 
+    #[inline]
+    pub fn new_channel(
+        meta: GosMetadata,
+        cap: usize,
+        zero_val: GosValue,
+        gcobjs: &mut GcObjs,
+    ) -> GosValue {
+        gcobjs.alloc_values(cap);
+        let val = Rc::new((
+            RefCell::new(ChannelObj::new(meta, cap, zero_val)),
+            Cell::new(0),
+        ));
+        let v = GosValue::Channel(val);
+        gcobjs.push(GcWeak::from_gosv(&v));
+        v
+    }
+
     #[inline]
     pub fn new_map_nil(meta: GosMetadata, default_val: GosValue, gcobjs: &mut GcObjs) -> GosValue {
         let val = Rc::new((MapObj::new_nil(meta, default_val), Cell::new(0)));
@@ -571,7 +581,7 @@ impl GosValue {
                         let state: &UpValueState = &uv.inner.borrow();
                         match state {
                             UpValueState::Open(d) => stack
-                                .get_with_type(d.index as usize, d.typ)
+                                .get_with_type(Stack::offset(d.stack_base, d.index), d.typ)
                                 .get_meta(objs, stack),
                             UpValueState::Closed(v) => v.get_meta(objs, stack),
                         }
@@ -610,7 +620,7 @@ impl GosValue {
             GosValue::Map(m) => m.0.meta,
             GosValue::Interface(i) => i.0.borrow().meta,
             GosValue::Struct(s) => s.0.borrow().meta,
-            GosValue::Channel(_) => unimplemented!(),
+            GosValue::Channel(c) => c.0.borrow().meta,
             GosValue::Function(_) => unimplemented!(),
             GosValue::Package(_) => unimplemented!(),
             GosValue::Metadata(_) => unimplemented!(),
@@ -671,69 +681,12 @@ impl GosValue {
     }
 
     #[inline]
-    pub fn add_str(a: &GosValue, b: &GosValue) -> GosValue {
+    pub fn add_str(a: &GosValue, b: &GosValue, gcos: &mut GcObjs) -> GosValue {
         let mut s = a.as_str().as_str().to_string();
         s.push_str(b.as_str().as_str());
+        gcos.alloc(s.capacity());
         GosValue::new_str(s)
     }
-
-    /// for gc
-    pub fn ref_sub_one(&self) {
-        match &self {
-            GosValue::Array(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Pointer(obj) => obj.ref_sub_one(),
-            GosValue::Closure(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Slice(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Map(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Interface(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Struct(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Channel(obj) => obj.1.set(obj.1.get() - 1),
-            GosValue::Named(obj) => obj.0.ref_sub_one(),
-            _ => {}
-        };
-    }
-
-    /// for gc
-    pub fn mark_dirty(&self, queue: &mut RCQueue) {
-        match &self {
-            GosValue::Array(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Pointer(obj) => obj.mark_dirty(queue),
-            GosValue::Closure(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Slice(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Map(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Interface(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Struct(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Channel(obj) => rcount_mark_and_queue(&obj.1, queue),
-            GosValue::Named(obj) => obj.0.mark_dirty(queue),
-            _ => {}
-        };
-    }
-
-    pub fn rc(&self) -> IRC {
-        match &self {
-            GosValue::Array(obj) => obj.1.get(),
-            GosValue::Closure(obj) => obj.1.get(),
-            GosValue::Slice(obj) => obj.1.get(),
-            GosValue::Map(obj) => obj.1.get(),
-            GosValue::Interface(obj) => obj.1.get(),
-            GosValue::Struct(obj) => obj.1.get(),
-            GosValue::Channel(obj) => obj.1.get(),
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn set_rc(&self, rc: IRC) {
-        match &self {
-            GosValue::Array(obj) => obj.1.set(rc),
-            GosValue::Closure(obj) => obj.1.set(rc),
-            GosValue::Slice(obj) => obj.1.set(rc),
-            GosValue::Map(obj) => obj.1.set(rc),
-            GosValue::Interface(obj) => obj.1.set(rc),
-            GosValue::Struct(obj) => obj.1.set(rc),
-            GosValue::Channel(obj) => obj.1.set(rc),
-            _ => unreachable!(),
-        }
-    }
 }
 
 impl Eq for GosValue {}
@@ -885,7 +838,7 @@ impl Display for GosValue {
             GosValue::Map(m) => write!(f, "{}", m.0),
             GosValue::Interface(i) => write!(f, "{}", i.0.borrow()),
             GosValue::Struct(s) => write!(f, "{}", s.0.borrow()),
-            GosValue::Channel(_) => unimplemented!(),
+            GosValue::Channel(c) => write!(f, "{:p}", Rc::as_ptr(c)),
             GosValue::Function(_) => f.write_str("<function>"),
             GosValue::Package(_) => f.write_str("<package>"),
             GosValue::Metadata(_) => f.write_str("<metadata>"),
@@ -1161,6 +1114,26 @@ impl GosValue64 {
         unsafe { self.data.complex64 }
     }
 
+    /// integer division by zero is a runtime error, floats are fine with it
+    #[inline]
+    pub fn is_zero_divisor(&self, t: ValueType) -> bool {
+        unsafe {
+            match t {
+                ValueType::Int => self.data.int == 0,
+                ValueType::Int8 => self.data.int8 == 0,
+                ValueType::Int16 => self.data.int16 == 0,
+                ValueType::Int32 => self.data.int32 == 0,
+                ValueType::Int64 => self.data.int64 == 0,
+                ValueType::Uint => self.data.uint == 0,
+                ValueType::Uint8 => self.data.uint8 == 0,
+                ValueType::Uint16 => self.data.uint16 == 0,
+                ValueType::Uint32 => self.data.uint32 == 0,
+                ValueType::Uint64 => self.data.uint64 == 0,
+                _ => false,
+            }
+        }
+    }
+
     #[inline]
     pub fn to_uint32(&mut self, t: ValueType) -> RuntimeResult {
         unsafe {
@@ -1,25 +1,626 @@
 #![allow(dead_code)]
-use super::ffi::FfiFactory;
-use super::gc;
+use super::ffi::{self, FfiCtx, FfiFactory, FfiResult};
+use super::gc::{self, GcObjs, MemStats};
 use super::instruction::*;
 use super::metadata::*;
-use super::objects::{u64_to_key, ClosureObj, GosHashMap, SliceEnumIter, SliceRef, StringEnumIter};
-use super::stack::Stack;
+use super::objects::{u64_to_key, ClosureObj, FfiClosureObj, GosHashMap, WaitState, Waker};
+use super::stack::{self, Stack};
 use super::value::*;
-use super::vm_util;
-use goscript_parser::FileSet;
+use super::vm_util::{self, RangeIter, SelectResult};
+use goscript_parser::{FileSet, Position};
 use std::cell::{Cell, Ref, RefCell};
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
 use std::pin::Pin;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// the number of instructions a fiber runs before it yields to others,
+/// it only yields at function calls and loop back-edges
+const TIME_SLICE: usize = 1024;
+
+/// the bytes an instruction is taken to allocate for the estimate of the heap
+/// size, besides the arrays, slices, channels and strings counted where they
+/// are allocated
+const INST_ALLOC: usize = 64;
+
+// the maximum number of calls from native code back into the VM nested in each
+// other, each of them takes a run of the VM on the native stack, which is
+// tens of KB in debug builds
+const MAX_NESTED_CALLS: usize = 32;
+
+/// runtime errors are turned into panics carrying the error message
+macro_rules! runtime_error {
+    ($msg:expr, $objs:ident) => {
+        runtime_error!($msg, $objs, ErrorKind::Runtime)
+    };
+    ($msg:expr, $objs:ident, $kind:expr) => {
+        (
+            GosValue::new_iface(
+                $objs.metadata.empty_iface,
+                IfaceUnderlying::Gos(GosValue::new_str($msg), Rc::new(vec![])),
+                &mut $objs.gcobjs,
+            ),
+            $kind,
+        )
+    };
+}
+
+/// xorshift is good enough for choosing among the ready cases of 'select'
+#[inline]
+fn xorshift(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
+}
+
+/// ffi_params pops the parameters of a native method from the stack starting
+/// at 'base', variadic arguments are packed into a slice unless 'ellipsis'
+fn ffi_params(
+    call: &FfiClosureObj,
+    stack: &mut Stack,
+    base: usize,
+    ellipsis: bool,
+    objs: &mut VMObjects,
+) -> Vec<GosValue> {
+    let sig = objs.metas[call.meta.as_non_ptr()].as_signature();
+    if let Some((slice_meta, elem_meta)) = sig.variadic {
+        if !ellipsis {
+            let index = base + sig.params_type.len() - 1;
+            let vt = elem_meta.get_value_type(&objs.metas);
+            stack.pack_variadic(index, slice_meta, vt, &mut objs.gcobjs);
+            if stack.len() == index {
+                // no variadic arguments given
+                stack.push(GosValue::slice_with_val(
+                    vec![],
+                    slice_meta,
+                    &mut objs.gcobjs,
+                ));
+            }
+        }
+    }
+    stack.pop_with_type_n(&sig.params_type)
+}
+
+/// call_ffi calls a native method, the stack of the calling fiber must not be
+/// borrowed, as the native code may call back into the VM
+fn call_ffi(
+    call: &FfiClosureObj,
+    params: Vec<GosValue>,
+    objs: &mut VMObjects,
+    env: Env,
+    spawned: &mut Vec<Fiber>,
+) -> FfiResult<Vec<GosValue>> {
+    let sig = objs.metas[call.meta.as_non_ptr()].as_signature().clone();
+    // an error as the last result is filled in here, the FFI only returns the
+    // results before it
+    let err_meta = sig
+        .results
+        .last()
+        .filter(|m| ffi::is_error_type(m, &objs.metas));
+    let results = match err_meta {
+        Some(_) => &sig.results[..sig.results.len() - 1],
+        None => &sig.results[..],
+    };
+    let mut ctx = FfiCtx {
+        results: results,
+        objs: objs,
+        env: env,
+        spawned: spawned,
+    };
+    // the object is borrowed for the whole call, a callback that calls back
+    // into the same object gets an error instead of aliasing it
+    let ret = match call.ffi.try_borrow_mut() {
+        Ok(mut ffi) => ffi.call(&mut ctx, &call.func_name, params),
+        Err(_) => Err(format!(
+            "ffi: re-entrant call to {} on an object already in use",
+            call.func_name
+        )),
+    };
+    let vals = match err_meta {
+        Some(m) => match ret {
+            Ok(mut vals) => {
+                if vals.len() == results.len() {
+                    vals.push(m.default_val(&objs.metas, &mut objs.gcobjs));
+                }
+                vals
+            }
+            Err(msg) => {
+                let mut vals: Vec<GosValue> = results
+                    .iter()
+                    .map(|x| x.default_val(&objs.metas, &mut objs.gcobjs))
+                    .collect();
+                vals.push(ffi::new_error(msg, m, &objs.metas, &mut objs.gcobjs));
+                vals
+            }
+        },
+        None => ret?,
+    };
+    // the results are left on the stack of the caller, which expects exactly these
+    if vals.len() != sig.results.len() {
+        return Err(format!(
+            "ffi: {} returned {} results, {} expected",
+            call.func_name,
+            vals.len(),
+            sig.results.len()
+        ));
+    }
+    Ok(vals)
+}
+
+/// call_closure calls the Go closure 'cls' with 'args' on behalf of native
+/// code, it runs in new fibers along with the goroutines it starts, which are
+/// put in 'spawned' to keep running after the call returns.
+pub(crate) fn call_closure(
+    cls: &GosValue,
+    args: Vec<GosValue>,
+    objs: &mut VMObjects,
+    env: Env,
+    spawned: &mut Vec<Fiber>,
+) -> Result<Vec<GosValue>, RuntimeError> {
+    let cls = match cls {
+        GosValue::Interface(i) => i.0.borrow().underlying_value().cloned(),
+        GosValue::Named(n) => Some(n.0.clone()),
+        _ => Some(cls.clone()),
+    }
+    .filter(|v| match v {
+        GosValue::Closure(c) => c.0.borrow().func.is_some(),
+        _ => false,
+    })
+    .ok_or_else(|| RuntimeError::new(ErrorKind::Runtime, "not a goscript function".to_string()))?;
+    // the entry frame is charged here, it's not entered by a CALL
+    if let Some(kind) = env.call_limit_reached(1, args.len()) {
+        env.stop.set(Some(kind));
+        return Err(env.limit_error(kind, vec![]));
+    }
+    if env.nested > MAX_NESTED_CALLS {
+        env.stop.set(Some(ErrorKind::StackLimit));
+        return Err(RuntimeError::new(
+            ErrorKind::StackLimit,
+            format!(
+                "stack overflow: {} nested calls from native code",
+                env.nested
+            ),
+        ));
+    }
+    let func = &objs.functions[cls.as_closure().0.borrow().func.unwrap()];
+    if !args_count_ok(func, args.len()) {
+        return Err(RuntimeError::new(
+            ErrorKind::Runtime,
+            format!(
+                "wrong number of arguments in call: have {}, want {}",
+                args.len(),
+                func.param_count()
+            ),
+        ));
+    }
+    let mut sched = Scheduler::new(&cls, args, objs);
+    let result = sched.run_to_end(objs, env);
+    if let Err(e) = &result {
+        // the native code gets the error too, but it can't catch these
+        match e.kind {
+            ErrorKind::Interrupted
+            | ErrorKind::InstructionLimit
+            | ErrorKind::CallDepthLimit
+            | ErrorKind::StackLimit
+            | ErrorKind::Timeout
+            | ErrorKind::OutOfMemory => env.stop.set(Some(e.kind)),
+            _ => {}
+        }
+    }
+    if result.is_ok() {
+        spawned.extend(
+            sched
+                .fibers
+                .drain(1..)
+                .filter_map(|f| Rc::try_unwrap(f).ok())
+                .map(|f| f.into_inner()),
+        );
+    }
+    result
+}
+
+/// args_count_ok checks the number of arguments passed to 'func', a variadic
+/// function takes the variadic arguments one by one
+fn args_count_ok(func: &FunctionVal, count: usize) -> bool {
+    match func.variadic() {
+        Some(_) => count + 1 >= func.param_count(),
+        None => count == func.param_count(),
+    }
+}
+
+/// convert_args checks the arguments passed to 'func' against its parameters,
+/// the ones passed to interface parameters are boxed into interfaces
+fn convert_args(
+    func: FunctionKey,
+    args: Vec<GosValue>,
+    objs: &mut VMObjects,
+    stack: &Stack,
+) -> Result<Vec<GosValue>, String> {
+    let sig = objs.metas[objs.functions[func].meta.as_non_ptr()]
+        .as_signature()
+        .clone();
+    let fixed = sig.params.len() - sig.variadic.map_or(0, |_| 1);
+    let is_iface = |m: &GosMetadata, metas: &MetadataObjs| {
+        m.get_underlying(metas).get_value_type(metas) == ValueType::Interface
+    };
+    let mut result = Vec::with_capacity(args.len());
+    for (i, arg) in args.into_iter().enumerate() {
+        let want = match sig.variadic {
+            Some((_, elem)) if i >= fixed => elem,
+            _ => sig.params[i],
+        };
+        let have = arg.get_meta(objs, stack);
+        let val = if have.semantic_eq(&want, &objs.metas) {
+            arg
+        } else if is_iface(&want, &objs.metas) && !is_iface(&have, &objs.metas) {
+            let under = IfaceUnderlying::Gos(arg, Rc::new(vec![]));
+            vm_util::type_assert(
+                have,
+                under,
+                have,
+                want,
+                &objs.metas,
+                &objs.functions,
+                &mut objs.gcobjs,
+            )?
+        } else {
+            return Err(format!(
+                "cannot use {} as {} in argument {}",
+                have.type_name(&objs.metas),
+                want.type_name(&objs.metas),
+                i + 1
+            ));
+        };
+        result.push(val);
+    }
+    Ok(result)
+}
+
+/// StepResult is what GosVM::step returns
+#[derive(Debug)]
+pub enum StepResult {
+    // the budget is used up, the run goes on with the next step
+    Yielded,
+    // all the goroutines are parked on channels
+    Blocked,
+    // the main function returns, with its results
+    Finished(Vec<GosValue>),
+    // the run is stopped by a panic or a fatal error
+    Panicked(RuntimeError),
+}
+
+/// Scheduler runs the fibers in a round-robin fashion, until the first one
+/// returns or any of them panics. Fibers parked on channels are skipped until
+/// they are woken up, the finished ones are removed.
+/// It can be run for a limited number of instructions, and then be run again
+/// to go on from where it stopped.
+struct Scheduler {
+    fibers: Vec<Rc<RefCell<Fiber>>>,
+    // the types of the results of the first fiber
+    ret_types: Vec<ValueType>,
+    // the fiber to run next
+    next: usize,
+}
+
+impl Scheduler {
+    /// new sets up the first fiber, which calls 'cls' with 'args'
+    fn new(cls: &GosValue, args: Vec<GosValue>, objs: &mut VMObjects) -> Scheduler {
+        let func = &objs.functions[cls.as_closure().0.borrow().func.unwrap()];
+        let types: Vec<ValueType> = func.ret_zeros.iter().map(|v| v.get_type()).collect();
+        let main = Rc::new(RefCell::new(Fiber::new(None)));
+        main.borrow_mut().start(cls, args, objs);
+        Scheduler {
+            fibers: vec![main],
+            ret_types: types,
+            next: 0,
+        }
+    }
+
+    /// run_to_end runs the fibers until the first one returns, and returns
+    /// its results
+    fn run_to_end(
+        &mut self,
+        objs: &mut VMObjects,
+        env: Env,
+    ) -> Result<Vec<GosValue>, RuntimeError> {
+        match self.run(objs, env, None) {
+            StepResult::Finished(results) => Ok(results),
+            StepResult::Panicked(e) => Err(e),
+            StepResult::Blocked => Err(RuntimeError::new(
+                ErrorKind::Deadlock,
+                "all goroutines are asleep - deadlock!".to_string(),
+            )),
+            StepResult::Yielded => unreachable!(),
+        }
+    }
+
+    /// run runs the fibers for about 'budget' instructions, or until the run
+    /// ends if there is no budget. The budget is checked when a fiber yields.
+    fn run(&mut self, objs: &mut VMObjects, env: Env, budget: Option<u64>) -> StepResult {
+        let fibers = &mut self.fibers;
+        let start = env.executed.get();
+        let mut spawned = vec![];
+        loop {
+            let left = budget.map(|b| b.saturating_sub(env.executed.get() - start));
+            if left == Some(0) {
+                return StepResult::Yielded;
+            }
+            let (count, from) = (fibers.len(), self.next);
+            match (0..count)
+                .map(|x| (from + x) % count)
+                .find(|x| !fibers[*x].borrow().parked())
+            {
+                Some(next) => self.next = next,
+                None => return StepResult::Blocked,
+            }
+            if objs.gcobjs.should_collect() || env.heap_full(&objs.gcobjs) {
+                // no fiber is running, so the values on the stacks are all the
+                // roots there are, the slots above the cursors are cleared first
+                for f in fibers.iter() {
+                    f.borrow().stack.borrow_mut().clear_rc_garbage();
+                }
+                gc::gc(&mut objs.gcobjs);
+                if env.limits.max_heap_size.is_some() {
+                    let size = mem_stats(fibers, objs).total();
+                    objs.gcobjs.set_heap_size(size);
+                    if env.heap_full(&objs.gcobjs) {
+                        let err = env.limit_error(ErrorKind::OutOfMemory, vec![]);
+                        return StepResult::Panicked(err);
+                    }
+                }
+            }
+            if let Some(kind) = env.limit_reached() {
+                return StepResult::Panicked(env.limit_error(kind, vec![]));
+            }
+            let time_slice = match left {
+                Some(l) => env.time_slice().min(l as usize),
+                None => env.time_slice(),
+            };
+            let i = self.next;
+            let fiber = fibers[i].clone();
+            let status = fiber
+                .borrow_mut()
+                .main_loop(objs, env, time_slice, &mut spawned);
+            fibers.extend(spawned.drain(..).map(|f| Rc::new(RefCell::new(f))));
+            match status {
+                FiberStatus::Yielded | FiberStatus::Blocked => self.next += 1,
+                FiberStatus::Finished => {
+                    if i == 0 {
+                        // only the results are left on the stack
+                        let fiber = fiber.borrow();
+                        let mut stack = fiber.stack.borrow_mut();
+                        return StepResult::Finished(stack.pop_with_type_n(&self.ret_types));
+                    }
+                    fibers.remove(i);
+                }
+                FiberStatus::Panicked => {
+                    return StepResult::Panicked(fiber.borrow().runtime_error(objs, env.fs));
+                }
+                FiberStatus::Stopped(kind) => {
+                    let fiber = fiber.borrow();
+                    let trace: Vec<(FunctionKey, usize)> = fiber
+                        .frames
+                        .iter()
+                        .rev()
+                        .map(|f| (f.func(), f.pc))
+                        .collect();
+                    let err = env.limit_error(kind, stack_trace(&trace, objs, env.fs));
+                    return StepResult::Panicked(err);
+                }
+            }
+            if self.next >= fibers.len() {
+                self.next = 0;
+            }
+        }
+    }
+}
+
+/// mem_stats measures the objects reachable from the stacks of 'fibers' and
+/// the package members
+fn mem_stats(fibers: &[Rc<RefCell<Fiber>>], objs: &VMObjects) -> MemStats {
+    let stacks: Vec<Rc<RefCell<Stack>>> = fibers.iter().map(|f| f.borrow().stack.clone()).collect();
+    let stacks: Vec<Ref<Stack>> = stacks.iter().map(|s| s.borrow()).collect();
+    let members = objs.packages.iter().flat_map(|(_, p)| p.members().iter());
+    let roots = stacks
+        .iter()
+        .flat_map(|s| s.rc_values().iter())
+        .chain(members);
+    gc::mem_stats(&objs.gcobjs, roots)
+}
+
+/// stack_trace returns the positions of the calls in 'trace', as pairs of the
+/// function and the pc
+fn stack_trace(
+    trace: &[(FunctionKey, usize)],
+    objs: &VMObjects,
+    fs: Option<&FileSet>,
+) -> Vec<Position> {
+    match fs {
+        Some(files) => trace
+            .iter()
+            .filter_map(|(fkey, pc)| objs.functions[*fkey].pos()[pc - 1])
+            .map(|pos| files.position(pos))
+            .collect(),
+        None => vec![],
+    }
+}
 
 #[derive(Debug)]
 pub struct ByteCode {
     pub objects: Pin<Box<VMObjects>>,
     pub packages: Vec<PackageKey>,
     pub ifaces: Vec<(GosMetadata, Rc<Vec<FunctionKey>>)>,
-    pub entry: FunctionKey,
+    // initializes all the packages
+    pub init: FunctionKey,
+    // initializes all the packages and calls main, None if there is no main function
+    pub entry: Option<FunctionKey>,
+}
+
+/// Env is what running the code needs besides the objects, it's shared by all
+/// the fibers, including the ones running calls from native code
+#[derive(Clone, Copy)]
+pub(crate) struct Env<'a> {
+    pkgs: &'a Vec<PackageKey>,
+    ifaces: &'a Vec<(GosMetadata, Rc<Vec<FunctionKey>>)>,
+    pub(crate) ffi_factory: &'a FfiFactory,
+    fs: Option<&'a FileSet>,
+    limits: &'a Limits,
+    // the number of instructions run so far, by all the fibers
+    executed: &'a Cell<u64>,
+    interrupt: &'a AtomicBool,
+    // set when a call from native code is stopped by an interrupt or a limit,
+    // the fiber that made the native call is to be stopped as well
+    stop: &'a Cell<Option<ErrorKind>>,
+    // the frames and the stack slots used by the fibers waiting for the native
+    // calls this run is nested in, they are charged against the limits
+    outer_depth: usize,
+    outer_stack: usize,
+    // the number of native calls this run is nested in
+    nested: usize,
+}
+
+impl<'a> Env<'a> {
+    /// time_slice returns the number of instructions the next fiber can run
+    /// before it yields, which is less than TIME_SLICE near max_instructions
+    fn time_slice(&self) -> usize {
+        match self.limits.max_instructions {
+            Some(max) => {
+                let left = max.saturating_sub(self.executed.get());
+                left.min(TIME_SLICE as u64) as usize
+            }
+            None => TIME_SLICE,
+        }
+    }
+
+    /// interrupted tells if the VM is interrupted, the interrupt is cleared
+    /// once it's seen
+    #[inline]
+    fn interrupted(&self) -> bool {
+        self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed)
+    }
+
+    /// stopped takes the error kind a call from native code back into the VM
+    /// has been stopped with, the calling fiber can't go on after it
+    #[inline]
+    fn stopped(&self) -> Option<ErrorKind> {
+        self.stop.take()
+    }
+
+    /// limit_reached checks the limits that are checked between time slices
+    fn limit_reached(&self) -> Option<ErrorKind> {
+        if let Some(max) = self.limits.max_instructions {
+            if self.executed.get() >= max {
+                return Some(ErrorKind::InstructionLimit);
+            }
+        }
+        match self.limits.deadline {
+            Some(d) if Instant::now() >= d => Some(ErrorKind::Timeout),
+            _ => None,
+        }
+    }
+
+    /// heap_full tells if the estimate of the heap size is over the limit, the
+    /// heap is to be measured to see if it's really full
+    fn heap_full(&self, gcos: &GcObjs) -> bool {
+        self.limits
+            .max_heap_size
+            .map_or(false, |max| gcos.heap_estimate() > max)
+    }
+
+    /// too_large tells if allocating 'bytes' exceeds the heap limit by itself
+    fn too_large(&self, bytes: usize) -> bool {
+        self.limits.max_heap_size.map_or(false, |max| bytes > max)
+    }
+
+    /// call_limit_reached checks the limits on the frames and the stack of a
+    /// fiber that has just entered a call, along with the ones of the fibers
+    /// waiting for the native calls it's nested in
+    fn call_limit_reached(&self, depth: usize, stack_size: usize) -> Option<ErrorKind> {
+        match self.limits.max_call_depth {
+            Some(max) if self.outer_depth + depth > max => Some(ErrorKind::CallDepthLimit),
+            _ if self.outer_stack + stack_size > self.max_stack_size() => {
+                Some(ErrorKind::StackLimit)
+            }
+            _ => None,
+        }
+    }
+
+    /// native_call returns the Env of a native call made by a fiber that has
+    /// 'depth' frames and 'stack_size' stack slots, for the calls it makes
+    /// back into the VM
+    fn native_call(self, depth: usize, stack_size: usize) -> Env<'a> {
+        Env {
+            outer_depth: self.outer_depth + depth,
+            outer_stack: self.outer_stack + stack_size,
+            nested: self.nested + 1,
+            ..self
+        }
+    }
+
+    /// max_stack_size is the limit on the stack size, the stack can't grow
+    /// over stack::MAX_SIZE in any case
+    fn max_stack_size(&self) -> usize {
+        self.limits
+            .max_stack_size
+            .map_or(stack::MAX_SIZE, |max| max.min(stack::MAX_SIZE))
+    }
+
+    fn limit_error(&self, kind: ErrorKind, stack_trace: Vec<Position>) -> RuntimeError {
+        let l = self.limits;
+        let msg = match kind {
+            ErrorKind::InstructionLimit => format!(
+                "instruction limit exceeded: {} instructions",
+                l.max_instructions.unwrap()
+            ),
+            ErrorKind::CallDepthLimit => format!(
+                "call depth limit exceeded: {} calls",
+                l.max_call_depth.unwrap()
+            ),
+            ErrorKind::StackLimit => {
+                format!("stack size limit exceeded: {} slots", self.max_stack_size())
+            }
+            ErrorKind::OutOfMemory => format!(
+                "out of memory: heap size limit exceeded: {} bytes",
+                l.max_heap_size.unwrap()
+            ),
+            _ => "deadline exceeded".to_string(),
+        };
+        let mut err = RuntimeError::new(kind, msg);
+        err.stack_trace = stack_trace;
+        err
+    }
+}
+
+/// Limits bounds what a script can use, the VM stops with an error of the kind
+/// of the limit once one is exceeded, which cannot be recovered by the script.
+/// None means no limit.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    // the number of instructions run by all the goroutines, it's checked
+    // when a goroutine yields, so it can be exceeded by a little
+    pub max_instructions: Option<u64>,
+    // the number of nested calls in a goroutine, the calls made by native
+    // code back into the VM count as nested in the goroutine that called it
+    pub max_call_depth: Option<usize>,
+    // the number of slots in the stack of a goroutine, which can't grow over
+    // 1310720 slots anyway, that is the limit if it's not set. The stacks of
+    // the calls made by native code back into the VM count the same way
+    pub max_stack_size: Option<usize>,
+    // the time to stop the VM, it's checked when a goroutine yields
+    pub deadline: Option<Instant>,
+    // the bytes taken by the objects, roughly. The heap is measured when the
+    // estimate of its size goes over the limit
+    pub max_heap_size: Option<usize>,
 }
 
 #[derive(Clone, Debug)]
@@ -39,6 +640,10 @@ struct CallFrame {
     local_ptrs: Option<Vec<UpValue>>,
     // closures that have upvalues pointing to this frame
     referred_by: Option<HashMap<OpIndex, Referers>>,
+    // the calls deferred by this frame, they run in reverse order on return
+    defer_stack: Option<Vec<DeferredCall>>,
+    // the length of Fiber::next_frames when this frame is entered
+    next_frames_base: usize,
 }
 
 impl CallFrame {
@@ -49,6 +654,8 @@ impl CallFrame {
             stack_base: sbase,
             local_ptrs: None,
             referred_by: None,
+            defer_stack: None,
+            next_frames_base: 0,
         }
     }
 
@@ -92,67 +699,455 @@ impl CallFrame {
     }
 }
 
+/// DeferredCall is a call made by the 'defer' statement, the parameters are
+/// evaluated and moved off the stack, to be put back when it's finally called
+#[derive(Clone)]
+struct DeferredCall {
+    frame: CallFrame,
+    params: Vec<(GosValue64, GosValue)>,
+    ellipsis: bool,
+}
+
+impl fmt::Debug for DeferredCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeferredCall")
+            .field("frame", &self.frame)
+            .field("params", &self.params.len())
+            .field("ellipsis", &self.ellipsis)
+            .finish()
+    }
+}
+
+/// Panic is a panic unwinding the stack of a fiber, there can be more than one
+/// of them when a deferred call panics again
+struct Panic {
+    val: GosValue,
+    kind: ErrorKind,
+    // the index of the frame of the deferred call being run by this panic,
+    // only that call can recover it
+    defer_frame: usize,
+    recovered: bool,
+    // the functions and pcs where it happened, for printing the stack trace
+    trace: Vec<(FunctionKey, usize)>,
+}
+
+/// ErrorKind tells what stopped the VM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    // failed to parse, type check or generate code
+    Compile,
+    // the built-in function 'panic' is called
+    Panic,
+    // runtime errors like index out of range
+    Runtime,
+    // the built-in function 'assert' fails
+    Assert,
+    // all the goroutines are blocked
+    Deadlock,
+    // Limits.max_instructions is exceeded
+    InstructionLimit,
+    // Limits.max_call_depth is exceeded
+    CallDepthLimit,
+    // Limits.max_stack_size is exceeded
+    StackLimit,
+    // Limits.deadline is passed
+    Timeout,
+    // Limits.max_heap_size is exceeded
+    OutOfMemory,
+    // InterruptHandle.interrupt is called
+    Interrupted,
+}
+
+/// RuntimeError is what's returned when a panic is not recovered, or the VM
+/// cannot go on for other reasons
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    // the value passed to 'panic', it's nil if it's not a panic
+    pub value: GosValue,
+    pub message: String,
+    // the positions of the calls where it happened, the innermost first
+    pub stack_trace: Vec<Position>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: String) -> RuntimeError {
+        RuntimeError {
+            kind: kind,
+            value: GosValue::new_nil(),
+            message: message,
+            stack_trace: vec![],
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Compile => write!(f, "{}", self.message)?,
+            ErrorKind::Deadlock
+            | ErrorKind::InstructionLimit
+            | ErrorKind::CallDepthLimit
+            | ErrorKind::StackLimit
+            | ErrorKind::Timeout
+            | ErrorKind::OutOfMemory
+            | ErrorKind::Interrupted => write!(f, "fatal error: {}", self.message)?,
+            _ => write!(f, "panic: {}", self.message)?,
+        }
+        for p in self.stack_trace.iter() {
+            write!(f, "\n\t{}:{}", p.filename, p.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// FiberStatus tells the scheduler why the main loop of a fiber returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FiberStatus {
+    Yielded,
+    Blocked, // parked on a channel
+    Finished,
+    Panicked,
+    Stopped(ErrorKind), // a limit is exceeded
+}
+
 pub struct Fiber {
-    stack: Stack,
+    stack: Rc<RefCell<Stack>>,
     frames: Vec<CallFrame>,
     caller: Option<Rc<RefCell<Fiber>>>,
     next_frames: Vec<CallFrame>,
+    // states of the active for...range loops, along with the stack index of
+    // the mark that refers to them
+    ranges: Vec<Option<(usize, RangeIter)>>,
+    // set when the fiber is parked on channel operations
+    waker: Option<Waker>,
+    rand_state: u64,
+    panics: Vec<Panic>,
+    // set when the fiber is interrupted, the panics cannot be recovered then
+    interrupted: bool,
 }
 
 impl Fiber {
     fn new(caller: Option<Rc<RefCell<Fiber>>>) -> Fiber {
         Fiber {
-            stack: Stack::new(),
+            stack: Rc::new(RefCell::new(Stack::new())),
             frames: Vec::new(),
             caller: caller,
             next_frames: Vec::new(),
+            ranges: Vec::new(),
+            waker: None,
+            rand_state: RandomState::new().build_hasher().finish() | 1,
+            panics: Vec::new(),
+            interrupted: false,
         }
     }
 
-    fn run(&mut self, code: &mut ByteCode, ffi_factory: &FfiFactory, fs: Option<&FileSet>) {
-        let cls = GosValue::new_closure(code.entry, &code.objects.functions);
-        let frame = CallFrame::with_closure(cls.as_closure().clone(), 0);
+    #[inline]
+    fn parked(&self) -> bool {
+        self.waker
+            .as_ref()
+            .map_or(false, |w| w.get() == WaitState::Parked)
+    }
+
+    /// start sets up the first frame of the fiber, which calls 'cls' with 'args'
+    fn start(&mut self, cls: &GosValue, mut args: Vec<GosValue>, objs: &mut VMObjects) {
+        let cls_rc = cls.as_closure();
+        let mut frame = CallFrame::with_closure(cls_rc.clone(), 0);
+        let func = &objs.functions[frame.func()];
+        let mut stack = self.stack.borrow_mut();
+        stack.append(&mut func.ret_zeros.clone());
+        stack.append(&mut args);
+        Fiber::enter_frame(
+            &mut frame,
+            &cls_rc.0.borrow(),
+            func,
+            &mut stack,
+            &Rc::downgrade(&self.stack),
+            false,
+            &mut objs.gcobjs,
+        );
+        drop(stack);
         self.frames.push(frame);
-        self.main_loop(code, ffi_factory, fs);
     }
 
-    fn main_loop(&mut self, code: &mut ByteCode, ffi_factory: &FfiFactory, fs: Option<&FileSet>) {
-        let objs: &mut VMObjects = &mut code.objects;
-        let pkgs = &code.packages;
-        let ifaces = &code.ifaces;
-        let mut frame = self.frames.last_mut().unwrap();
-        let fkey = frame.func();
-        let mut func = &objs.functions[fkey];
-        let stack = &mut self.stack;
+    /// enter_frame sets up the local pointers, the variadic parameter and the
+    /// local variables of the callee, its parameters should be on the stack already
+    fn enter_frame(
+        frame: &mut CallFrame,
+        cls: &ClosureObj,
+        func: &FunctionVal,
+        stack: &mut Stack,
+        stack_weak: &Weak<RefCell<Stack>>,
+        ellipsis: bool,
+        gcos: &mut GcObjs,
+    ) {
+        if let Some(uvs) = &cls.uvs {
+            let mut local_ptrs: Vec<UpValue> = Vec::with_capacity(func.up_ptrs.len());
+            for (i, p) in func.up_ptrs.iter().enumerate() {
+                local_ptrs.push(if p.is_up_value {
+                    uvs[&i].clone()
+                } else {
+                    // local pointers
+                    let uv = UpValue::new(p.clone_with_stack(stack_weak.clone(), frame.stack_base));
+                    frame.add_referred_by(p.index, p.typ, &uv);
+                    uv
+                });
+            }
+            frame.local_ptrs = Some(local_ptrs);
+        }
+
+        if let Some((meta, vt)) = func.variadic() {
+            if !ellipsis {
+                let index = frame.stack_base + func.param_count() + func.ret_count() - 1;
+                stack.pack_variadic(index, meta, vt, gcos);
+            }
+        }
+
+        debug_assert!(func.local_count() == func.local_zeros.len());
         // allocate local variables
         stack.append(&mut func.local_zeros.clone());
-        let mut consts = &func.consts;
-        let mut code = func.code();
-        let mut stack_base = frame.stack_base;
-
-        let mut panic_msg: Option<String> = None;
-
-        // put the loop in a block, so that range_vars expires before GC
-        {
-            let mut range_slot = 0;
-            range_vars!(mr0, mp0, mi0, lr0, lp0, li0, sr0, sp0, si0);
-            range_vars!(mr1, mp1, mi1, lr1, lp1, li1, sr1, sp1, si1);
-            range_vars!(mr2, mp2, mi2, lr2, lp2, li2, sr2, sp2, si2);
-            range_vars!(mr3, mp3, mi3, lr3, lp3, li3, sr3, sp3, si3);
-            range_vars!(mr4, mp4, mi4, lr4, lp4, li4, sr4, sp4, si4);
-            range_vars!(mr5, mp5, mi5, lr5, lp5, li5, sr5, sp5, si5);
-            range_vars!(mr6, mp6, mi6, lr6, lp6, li6, sr6, sp6, si6);
-            range_vars!(mr7, mp7, mi7, lr7, lp7, li7, sr7, sp7, si7);
-            range_vars!(mr8, mp8, mi8, lr8, lp8, li8, sr8, sp8, si8);
-            range_vars!(mr9, mp9, mi9, lr9, lp9, li9, sr9, sp9, si9);
-            range_vars!(mr10, mp10, mi10, lr10, lp10, li10, sr10, sp10, si10);
-            range_vars!(mr11, mp11, mi11, lr11, lp11, li11, sr11, sp11, si11);
-            range_vars!(mr12, mp12, mi12, lr12, lp12, li12, sr12, sp12, si12);
-            range_vars!(mr13, mp13, mi13, lr13, lp13, li13, sr13, sp13, si13);
-            range_vars!(mr14, mp14, mi14, lr14, lp14, li14, sr14, sp14, si14);
-            range_vars!(mr15, mp15, mi15, lr15, lp15, li15, sr15, sp15, si15);
-
-            let mut total_inst = 0;
+    }
+
+    /// prepare_deferred puts the parameters of a deferred call back on the stack
+    /// and returns the frame to enter. Native functions are called right away.
+    fn prepare_deferred(
+        call: DeferredCall,
+        depth: usize,
+        stack_rc: &Rc<RefCell<Stack>>,
+        objs: &mut VMObjects,
+        env: Env,
+        spawned: &mut Vec<Fiber>,
+    ) -> FfiResult<Option<CallFrame>> {
+        let mut stack = stack_rc.borrow_mut();
+        let mut frame = call.frame;
+        frame.stack_base = stack.len();
+        stack.append_slots(call.params)?;
+        let ref_cls = frame.closure().clone();
+        let cls: &ClosureObj = &ref_cls.0.borrow();
+        match cls.func {
+            Some(key) => {
+                Fiber::enter_frame(
+                    &mut frame,
+                    cls,
+                    &objs.functions[key],
+                    &mut stack,
+                    &Rc::downgrade(stack_rc),
+                    call.ellipsis,
+                    &mut objs.gcobjs,
+                );
+                Ok(Some(frame))
+            }
+            None => {
+                let ffi = cls.ffi.as_ref().unwrap();
+                let params = ffi_params(ffi, &mut stack, frame.stack_base, call.ellipsis, objs);
+                let env = env.native_call(depth, stack.len());
+                drop(stack);
+                // the results of deferred calls are discarded
+                call_ffi(ffi, params, objs, env, spawned)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// close any active upvalue the frame contains
+    fn close_upvalues(frame: &CallFrame, stack: &Stack) {
+        if let Some(referred) = &frame.referred_by {
+            for (ind, referrers) in referred {
+                if referrers.weaks.len() == 0 {
+                    continue;
+                }
+                let val = stack.get_with_type(Stack::offset(frame.stack_base, *ind), referrers.typ);
+                for weak in referrers.weaks.iter() {
+                    if let Some(uv) = weak.upgrade() {
+                        uv.close(val.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// release the range loops left by "break", "return" or panics
+    fn release_ranges(ranges: &mut [Option<(usize, RangeIter)>], stack_base: usize) {
+        for r in ranges.iter_mut() {
+            let stale = match r {
+                Some((mark_index, _)) => *mark_index >= stack_base,
+                None => false,
+            };
+            if stale {
+                *r = None;
+            }
+        }
+    }
+
+    /// unwind runs the deferred calls of the frames on the top for the last
+    /// panic, it returns when a deferred call is entered or the panic is
+    /// recovered, false is returned if all the frames are gone.
+    /// After recovering, the frame that deferred the call returns normally.
+    fn unwind(
+        &mut self,
+        stack_rc: &Rc<RefCell<Stack>>,
+        objs: &mut VMObjects,
+        env: Env,
+        spawned: &mut Vec<Fiber>,
+    ) -> bool {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            if let Some(call) = frame.defer_stack.as_mut().and_then(|x| x.pop()) {
+                // a failed native call doesn't stop the panic being unwound
+                if let Ok(Some(mut nframe)) =
+                    Fiber::prepare_deferred(call, self.frames.len(), stack_rc, objs, env, spawned)
+                {
+                    nframe.next_frames_base = self.next_frames.len();
+                    self.frames.push(nframe);
+                    self.panics.last_mut().unwrap().defer_frame = self.frames.len() - 1;
+                    return true;
+                }
+                continue;
+            }
+
+            let mut stack = stack_rc.borrow_mut();
+            let frame = self.frames.pop().unwrap();
+            Fiber::close_upvalues(&frame, &stack);
+            Fiber::release_ranges(&mut self.ranges, frame.stack_base);
+            self.next_frames.truncate(frame.next_frames_base);
+            if self.panics.last().unwrap().recovered {
+                stack.truncate(frame.stack_base + frame.ret_count(objs));
+                self.panics.pop();
+                return true;
+            }
+            stack.truncate(frame.stack_base);
+            // the earlier panics whose deferred calls are aborted are replaced
+            let height = self.frames.len();
+            let last = self.panics.pop().unwrap();
+            self.panics.retain(|p| p.defer_frame < height);
+            self.panics.push(last);
+            if height == 0 {
+                return false;
+            }
+        }
+    }
+
+    /// runtime_error returns the error of the panic that killed the fiber
+    fn runtime_error(&self, objs: &VMObjects, fs: Option<&FileSet>) -> RuntimeError {
+        let p = self.panics.last().unwrap();
+        RuntimeError {
+            kind: if self.interrupted {
+                ErrorKind::Interrupted
+            } else {
+                p.kind
+            },
+            value: p.val.clone(),
+            message: p.val.to_string(),
+            stack_trace: stack_trace(&p.trace, objs, fs),
+        }
+    }
+
+    /// main_loop runs the fiber until it finishes, panics or yields to other
+    /// fibers, which it does after about 'time_slice' instructions.
+    /// New fibers created by the 'go' statement are put in 'spawned'.
+    /// A panic breaks the inner loop to unwind the stack, and the inner loop is
+    /// entered again if a deferred call is to run or the panic is recovered.
+    fn main_loop(
+        &mut self,
+        objs: &mut VMObjects,
+        env: Env,
+        time_slice: usize,
+        spawned: &mut Vec<Fiber>,
+    ) -> FiberStatus {
+        let pkgs = env.pkgs;
+        let ifaces = env.ifaces;
+        let stack_rc = self.stack.clone();
+        let stack_ptr = Rc::as_ptr(&stack_rc);
+        let stack_weak = Rc::downgrade(&stack_rc);
+        let mut stack_ref = stack_rc.borrow_mut();
+        let mut stack: &mut Stack = &mut stack_ref;
+
+        // releases the stack while native code runs, which may call back into
+        // the VM and reach the stack through upvalues
+        macro_rules! without_stack {
+            ($e:expr) => {{
+                drop(stack_ref);
+                let r = $e;
+                stack_ref = stack_rc.borrow_mut();
+                stack = &mut stack_ref;
+                r
+            }};
+        }
+
+        let mut status = FiberStatus::Finished;
+        // the number of instructions run in this time slice
+        let mut total_inst = 0;
+        // a sender that was parked on a channel is woken up by 'close'
+        let mut panic: Option<(GosValue, ErrorKind)> = match self.waker.take() {
+            Some(w) if w.get() == WaitState::Closed => {
+                Some(runtime_error!("send on closed channel".to_string(), objs))
+            }
+            _ => None,
+        };
+        // set when a deferred call run by a panic returns
+        let mut unwinding = false;
+
+        // a call from native code back into the VM that is interrupted or
+        // stopped by a limit stops this fiber too, whatever the native code
+        // has made of the error
+        macro_rules! break_if_stopped {
+            () => {
+                match env.stopped() {
+                    Some(ErrorKind::Interrupted) => {
+                        self.interrupted = true;
+                        let msg = "interrupted".to_string();
+                        panic = Some(runtime_error!(msg, objs, ErrorKind::Interrupted));
+                        break;
+                    }
+                    Some(kind) => {
+                        status = FiberStatus::Stopped(kind);
+                        break;
+                    }
+                    None => {}
+                }
+            };
+        }
+
+        loop {
+            if let Some((val, kind)) = panic.take() {
+                let trace = self.frames.iter().rev().map(|f| (f.func(), f.pc)).collect();
+                self.panics.push(Panic {
+                    val: val,
+                    kind: kind,
+                    defer_frame: 0,
+                    recovered: false,
+                    trace: trace,
+                });
+                unwinding = true;
+            }
+            if unwinding {
+                unwinding = false;
+                if !without_stack!(self.unwind(&stack_rc, objs, env, spawned)) {
+                    status = FiberStatus::Panicked;
+                    break;
+                }
+                match env.stopped() {
+                    // the panic being unwound can't be recovered any more
+                    Some(ErrorKind::Interrupted) => self.interrupted = true,
+                    Some(kind) => {
+                        status = FiberStatus::Stopped(kind);
+                        break;
+                    }
+                    None => {}
+                }
+                if self.frames.is_empty() {
+                    break;
+                }
+            }
+
+            let mut frame = self.frames.last_mut().unwrap();
+            let mut func = &objs.functions[frame.func()];
+            let mut consts = &func.consts;
+            let mut code = func.code();
+            let mut stack_base = frame.stack_base;
+
             //let mut stats: HashMap<Opcode, usize> = HashMap::new();
             loop {
                 let inst = code[frame.pc];
@@ -175,7 +1170,12 @@ impl Fiber {
                     Opcode::PUSH_TRUE => stack.push_bool(true),
                     Opcode::PUSH_IMM => stack.push_int32_as(inst.imm(), inst.t0()),
                     Opcode::POP => {
-                        stack.pop_discard_n(inst.imm() as usize);
+                        let count = inst.imm() as usize;
+                        // the flag is set if break or continue leaves range loops
+                        if inst.t2_as_index() > 0 {
+                            Fiber::release_ranges(&mut self.ranges, stack.len() - count);
+                        }
+                        stack.pop_discard_n(count);
                     }
                     Opcode::LOAD_LOCAL => {
                         let index = Stack::offset(stack_base, inst.imm());
@@ -189,23 +1189,20 @@ impl Fiber {
                     Opcode::LOAD_UPVALUE => {
                         let index = inst.imm();
                         let upvalue = frame.local_ptrs.as_ref().unwrap()[index as usize].clone();
-                        let val = load_up_value!(upvalue, self, stack, self.frames);
+                        let val = load_up_value!(upvalue, stack, stack_ptr);
                         stack.push(val);
-                        frame = self.frames.last_mut().unwrap();
                     }
                     Opcode::STORE_UPVALUE => {
                         let (rhs_index, index) = inst.imm824();
                         let upvalue = frame.local_ptrs.as_ref().unwrap()[index as usize].clone();
                         store_up_value!(
                             upvalue,
-                            self,
                             stack,
-                            self.frames,
+                            stack_ptr,
                             rhs_index,
                             inst.t0(),
                             &mut objs.gcobjs
                         );
-                        frame = self.frames.last_mut().unwrap();
                     }
                     Opcode::LOAD_INDEX => {
                         let ind = stack.pop_with_type(inst.t1());
@@ -214,7 +1211,7 @@ impl Fiber {
                             match vm_util::load_index(val, &ind) {
                                 Ok(v) => stack.push(v),
                                 Err(e) => {
-                                    panic_msg = Some(e);
+                                    panic = Some(runtime_error!(e, objs));
                                     break;
                                 }
                             }
@@ -229,7 +1226,7 @@ impl Fiber {
                             match vm_util::load_index_int(val, index) {
                                 Ok(v) => stack.push(v),
                                 Err(e) => {
-                                    panic_msg = Some(e);
+                                    panic = Some(runtime_error!(e, objs));
                                     break;
                                 }
                             }
@@ -246,14 +1243,17 @@ impl Fiber {
                         let s_index = Stack::offset(stack.len(), index);
                         let key = stack.get_with_type(s_index + 1, inst.t2());
                         let target = &stack.get_with_type(s_index, inst.t1());
-                        vm_util::store_index(
+                        if let Err(e) = vm_util::store_index(
                             stack,
                             target,
                             &key,
                             rhs_index,
                             inst.t0(),
                             &mut objs.gcobjs,
-                        );
+                        ) {
+                            panic = Some(runtime_error!(e, objs));
+                            break;
+                        }
                     }
                     Opcode::STORE_INDEX_IMM => {
                         // the only place we can store the immediate index is t2
@@ -269,7 +1269,7 @@ impl Fiber {
                             inst.t0(),
                             &mut objs.gcobjs,
                         ) {
-                            panic_msg = Some(s);
+                            panic = Some(runtime_error!(s, objs));
                             break;
                         }
                     }
@@ -282,8 +1282,7 @@ impl Fiber {
                         let ind = inst.imm();
                         let mut target = stack.pop_with_type(inst.t0());
                         if let GosValue::Pointer(_) = &target {
-                            target = deref_value!(target, self, stack, self.frames, objs);
-                            frame = self.frames.last_mut().unwrap();
+                            target = deref_value!(target, stack, stack_ptr, objs);
                         }
                         let val = match &target {
                             GosValue::Named(n) => {
@@ -341,7 +1340,8 @@ impl Fiber {
                                 )))
                             }
                             IfaceUnderlying::None => {
-                                panic_msg = Some("access nil interface".to_string());
+                                panic =
+                                    Some(runtime_error!("access nil interface".to_string(), objs));
                                 break;
                             }
                         };
@@ -355,8 +1355,7 @@ impl Fiber {
                         let target = stack.get_with_type(s_index, inst.t1());
                         match target {
                             GosValue::Pointer(_) => {
-                                let unboxed = deref_value!(target, self, stack, self.frames, objs);
-                                frame = self.frames.last_mut().unwrap();
+                                let unboxed = deref_value!(target, stack, stack_ptr, objs);
                                 vm_util::store_field(
                                     stack,
                                     &unboxed,
@@ -384,8 +1383,7 @@ impl Fiber {
                         let s_index = Stack::offset(stack.len(), index);
                         let mut target = stack.get_with_type(s_index, inst.t1());
                         if let GosValue::Pointer(_) = &target {
-                            target = deref_value!(target, self, stack, self.frames, objs);
-                            frame = self.frames.last_mut().unwrap();
+                            target = deref_value!(target, stack, stack_ptr, objs);
                         }
                         match &target {
                             GosValue::Named(n) => {
@@ -429,14 +1427,12 @@ impl Fiber {
                                     PointerObj::UpVal(uv) => {
                                         store_up_value!(
                                             uv,
-                                            self,
                                             stack,
-                                            self.frames,
+                                            stack_ptr,
                                             rhs_index,
                                             inst.t0(),
                                             &mut objs.gcobjs
                                         );
-                                        frame = self.frames.last_mut().unwrap();
                                     }
                                     PointerObj::Struct(r, _) => {
                                         let rhs_s_index = Stack::offset(stack.len(), rhs_index);
@@ -504,7 +1500,7 @@ impl Fiber {
                         let iface = ifaces[mapping as usize].clone();
                         let under = stack.get_with_type(rhs_s_index, inst.t0());
                         let val = match &objs.metas[iface.0.as_non_ptr()] {
-                            MetadataType::Named(_, md) => GosValue::Named(Box::new((
+                            MetadataType::Named(_, md, _) => GosValue::Named(Box::new((
                                 GosValue::new_iface(
                                     *md,
                                     IfaceUnderlying::Gos(under, iface.1),
@@ -521,11 +1517,19 @@ impl Fiber {
                         };
                         stack.set(rhs_s_index, val);
                     }
-                    Opcode::ADD => stack.add(inst.t0()),
+                    Opcode::ADD => stack.add(inst.t0(), &mut objs.gcobjs),
                     Opcode::SUB => stack.sub(inst.t0()),
                     Opcode::MUL => stack.mul(inst.t0()),
-                    Opcode::QUO => stack.quo(inst.t0()),
-                    Opcode::REM => stack.rem(inst.t0()),
+                    Opcode::QUO | Opcode::REM => {
+                        let result = match inst_op {
+                            Opcode::QUO => stack.quo(inst.t0()),
+                            _ => stack.rem(inst.t0()),
+                        };
+                        if let Err(e) = result {
+                            panic = Some(runtime_error!(e, objs));
+                            break;
+                        }
+                    }
                     Opcode::AND => stack.and(inst.t0()),
                     Opcode::OR => stack.or(inst.t0()),
                     Opcode::XOR => stack.xor(inst.t0()),
@@ -542,7 +1546,42 @@ impl Fiber {
                     Opcode::NEQ => stack.compare_neq(inst.t0()),
                     Opcode::LEQ => stack.compare_leq(inst.t0()),
                     Opcode::GEQ => stack.compare_geq(inst.t0()),
-                    Opcode::ARROW => unimplemented!(),
+                    Opcode::ARROW => {
+                        let chan = stack.get_with_type(stack.len() - 1, ValueType::Channel);
+                        match vm_util::recv(&chan) {
+                            Ok((val, ok)) => {
+                                stack.pop_discard();
+                                stack.push(val);
+                                if inst.t2_as_index() > 0 {
+                                    stack.push_bool(ok);
+                                }
+                            }
+                            Err(waker) => {
+                                // park and try again when woken up
+                                self.waker = Some(waker);
+                                frame.pc -= 1;
+                                status = FiberStatus::Blocked;
+                                break;
+                            }
+                        }
+                    }
+                    Opcode::SEND => {
+                        let val = stack.pop_with_type(inst.t1());
+                        let chan = stack.pop_with_type(ValueType::Channel);
+                        match vm_util::send(&chan, val.copy_semantic(&mut objs.gcobjs)) {
+                            Ok(None) => {}
+                            Ok(Some(waker)) => {
+                                // the value is handed over when a receiver wakes us up
+                                self.waker = Some(waker);
+                                status = FiberStatus::Blocked;
+                                break;
+                            }
+                            Err(e) => {
+                                panic = Some(runtime_error!(e, objs));
+                                break;
+                            }
+                        }
+                    }
                     Opcode::REF_UPVALUE => {
                         let index = inst.imm();
                         let upvalue = frame.local_ptrs.as_ref().unwrap()[index as usize].clone();
@@ -599,9 +1638,8 @@ impl Fiber {
                     }
                     Opcode::DEREF => {
                         let boxed = stack.pop_with_type(inst.t0());
-                        let val = deref_value!(boxed, self, stack, self.frames, objs);
+                        let val = deref_value!(boxed, stack, stack_ptr, objs);
                         stack.push(val);
-                        frame = self.frames.last_mut().unwrap();
                     }
                     Opcode::PRE_CALL => {
                         let val = stack.pop_with_type(ValueType::Closure);
@@ -623,29 +1661,33 @@ impl Fiber {
                         self.next_frames.push(next_frame);
                     }
                     Opcode::CALL | Opcode::CALL_ELLIPSIS => {
+                        if env.interrupted() {
+                            self.interrupted = true;
+                            let msg = "interrupted".to_string();
+                            panic = Some(runtime_error!(msg, objs, ErrorKind::Interrupted));
+                            break;
+                        }
                         let mut nframe = self.next_frames.pop().unwrap();
                         let ref_cls = nframe.closure().clone();
                         let cls: &ClosureObj = &ref_cls.0.borrow();
                         match cls.func {
                             Some(key) => {
-                                if let Some(uvs) = &cls.uvs {
-                                    let frame_height = self.frames.len() as OpIndex;
-                                    let func = &objs.functions[key];
-                                    let mut local_ptrs: Vec<UpValue> =
-                                        Vec::with_capacity(func.up_ptrs.len());
-                                    for (i, p) in func.up_ptrs.iter().enumerate() {
-                                        local_ptrs.push(if p.is_up_value {
-                                            uvs[&i].clone()
-                                        } else {
-                                            // local pointers
-                                            let uv = UpValue::new(p.clone_with_frame(frame_height));
-                                            nframe.add_referred_by(p.index, p.typ, &uv);
-                                            uv
-                                        });
-                                    }
-                                    nframe.local_ptrs = Some(local_ptrs);
+                                Fiber::enter_frame(
+                                    &mut nframe,
+                                    cls,
+                                    &objs.functions[key],
+                                    stack,
+                                    &stack_weak,
+                                    inst_op == Opcode::CALL_ELLIPSIS,
+                                    &mut objs.gcobjs,
+                                );
+                                if let Some(kind) =
+                                    env.call_limit_reached(self.frames.len() + 1, stack.len())
+                                {
+                                    status = FiberStatus::Stopped(kind);
+                                    break;
                                 }
-
+                                nframe.next_frames_base = self.next_frames.len();
                                 self.frames.push(nframe);
                                 frame = self.frames.last_mut().unwrap();
 
@@ -657,46 +1699,130 @@ impl Fiber {
                                 // dbg!(&code);
                                 //dbg!(&stack);
 
-                                if let Some((meta, vt)) = func.variadic() {
-                                    if inst_op != Opcode::CALL_ELLIPSIS {
-                                        let index =
-                                            stack_base + func.param_count() + func.ret_count() - 1;
-                                        stack.pack_variadic(index, meta, vt, &mut objs.gcobjs);
+                                if total_inst >= time_slice || env.heap_full(&objs.gcobjs) {
+                                    status = FiberStatus::Yielded;
+                                    break;
+                                }
+                            }
+                            None => {
+                                let ffi = cls.ffi.as_ref().unwrap();
+                                let params = ffi_params(
+                                    ffi,
+                                    stack,
+                                    nframe.stack_base,
+                                    inst_op == Opcode::CALL_ELLIPSIS,
+                                    objs,
+                                );
+                                let env = env.native_call(self.frames.len(), stack.len());
+                                let ret = without_stack!(call_ffi(ffi, params, objs, env, spawned));
+                                break_if_stopped!();
+                                // the native code may have called back into the VM
+                                frame = self.frames.last_mut().unwrap();
+                                func = &objs.functions[frame.func()];
+                                consts = &func.consts;
+                                code = func.code();
+                                match ret {
+                                    Ok(mut returns) => stack.append(&mut returns),
+                                    Err(m) => {
+                                        panic = Some(runtime_error!(m, objs));
+                                        break;
                                     }
                                 }
-
-                                debug_assert!(func.local_count() == func.local_zeros.len());
-                                // allocate local variables
-                                stack.append(&mut func.local_zeros.clone());
+                            }
+                        }
+                    }
+                    Opcode::GO => {
+                        let mut nframe = self.next_frames.pop().unwrap();
+                        let ref_cls = nframe.closure().clone();
+                        let cls: &ClosureObj = &ref_cls.0.borrow();
+                        match cls.func {
+                            Some(key) => {
+                                // move the parameters to the stack of the new fiber
+                                let mut fiber = Fiber::new(None);
+                                let mut new_stack = fiber.stack.borrow_mut();
+                                if let Err(e) = stack.move_to(nframe.stack_base, &mut new_stack) {
+                                    panic = Some(runtime_error!(e, objs));
+                                    break;
+                                }
+                                nframe.stack_base = 0;
+                                Fiber::enter_frame(
+                                    &mut nframe,
+                                    cls,
+                                    &objs.functions[key],
+                                    &mut new_stack,
+                                    &Rc::downgrade(&fiber.stack),
+                                    inst.imm() > 0,
+                                    &mut objs.gcobjs,
+                                );
+                                drop(new_stack);
+                                fiber.frames.push(nframe);
+                                spawned.push(fiber);
                             }
                             None => {
-                                let call = cls.ffi.as_ref().unwrap();
-                                let ptypes = &objs.metas[call.meta.as_non_ptr()]
-                                    .as_signature()
-                                    .params_type;
-                                let params = stack.pop_with_type_n(ptypes);
-                                let mut returns = call.ffi.borrow().call(&call.func_name, params);
-                                stack.append(&mut returns);
+                                // native functions don't run in fibers, just call it
+                                let ffi = cls.ffi.as_ref().unwrap();
+                                let params =
+                                    ffi_params(ffi, stack, nframe.stack_base, inst.imm() > 0, objs);
+                                let env = env.native_call(self.frames.len(), stack.len());
+                                let ret = without_stack!(call_ffi(ffi, params, objs, env, spawned));
+                                break_if_stopped!();
+                                frame = self.frames.last_mut().unwrap();
+                                func = &objs.functions[frame.func()];
+                                consts = &func.consts;
+                                code = func.code();
+                                if let Err(m) = ret {
+                                    panic = Some(runtime_error!(m, objs));
+                                    break;
+                                }
                             }
                         }
                     }
+                    Opcode::DEFER => {
+                        let nframe = self.next_frames.pop().unwrap();
+                        let params = stack.split_off_slots(nframe.stack_base);
+                        let call = DeferredCall {
+                            frame: nframe,
+                            params: params,
+                            ellipsis: inst.imm() > 0,
+                        };
+                        frame.defer_stack.get_or_insert_with(Vec::new).push(call);
+                    }
                     Opcode::RETURN | Opcode::RETURN_INIT_PKG => {
-                        // close any active upvalue this frame contains
-                        if let Some(referred) = &frame.referred_by {
-                            for (ind, referrers) in referred {
-                                if referrers.weaks.len() == 0 {
-                                    continue;
+                        // run the deferred calls first, one at a time,
+                        // this RETURN is executed again after each of them
+                        if let Some(call) = frame.defer_stack.as_mut().and_then(|x| x.pop()) {
+                            frame.pc -= 1;
+                            let depth = self.frames.len();
+                            let ret = without_stack!(Fiber::prepare_deferred(
+                                call, depth, &stack_rc, objs, env, spawned
+                            ));
+                            break_if_stopped!();
+                            match ret {
+                                Ok(Some(mut nframe)) => {
+                                    nframe.next_frames_base = self.next_frames.len();
+                                    self.frames.push(nframe);
+                                    frame = self.frames.last_mut().unwrap();
+                                    func = &objs.functions[frame.func()];
+                                    stack_base = frame.stack_base;
+                                    consts = &func.consts;
+                                    code = func.code();
                                 }
-                                let val = stack
-                                    .get_with_type(Stack::offset(stack_base, *ind), referrers.typ);
-                                for weak in referrers.weaks.iter() {
-                                    if let Some(uv) = weak.upgrade() {
-                                        uv.close(val.clone());
-                                    }
+                                Ok(None) => {
+                                    frame = self.frames.last_mut().unwrap();
+                                    func = &objs.functions[frame.func()];
+                                    consts = &func.consts;
+                                    code = func.code();
+                                }
+                                Err(m) => {
+                                    panic = Some(runtime_error!(m, objs));
+                                    break;
                                 }
                             }
+                            continue;
                         }
 
+                        Fiber::close_upvalues(frame, stack);
+
                         //dbg!(stack.len());
                         //for s in stack.iter() {
                         //    dbg!(GosValueDebug::new(&s, &objs));
@@ -729,9 +1855,9 @@ impl Fiber {
                             _ => unreachable!(),
                         }
 
+                        Fiber::release_ranges(&mut self.ranges, stack_base);
                         self.frames.pop();
                         if self.frames.is_empty() {
-                            dbg!(total_inst);
                             /*let mut s = stats
                                 .iter()
                                 .map(|(&k, &v)| (k, v))
@@ -740,6 +1866,13 @@ impl Fiber {
                             dbg!(s); */
                             break;
                         }
+                        // a deferred call run by a panic is done, go on unwinding
+                        if let Some(p) = self.panics.last() {
+                            if p.defer_frame == self.frames.len() {
+                                unwinding = true;
+                                break;
+                            }
+                        }
                         frame = self.frames.last_mut().unwrap();
                         stack_base = frame.stack_base;
                         // restore func, consts, code
@@ -749,7 +1882,27 @@ impl Fiber {
                     }
 
                     Opcode::JUMP => {
+                        if env.interrupted() {
+                            self.interrupted = true;
+                            let msg = "interrupted".to_string();
+                            panic = Some(runtime_error!(msg, objs, ErrorKind::Interrupted));
+                            break;
+                        }
+                        frame.pc = Stack::offset(frame.pc, inst.imm());
+                    }
+                    // LOOP is a JUMP back to the beginning of a loop
+                    Opcode::LOOP => {
+                        if env.interrupted() {
+                            self.interrupted = true;
+                            let msg = "interrupted".to_string();
+                            panic = Some(runtime_error!(msg, objs, ErrorKind::Interrupted));
+                            break;
+                        }
                         frame.pc = Stack::offset(frame.pc, inst.imm());
+                        if total_inst >= time_slice || env.heap_full(&objs.gcobjs) {
+                            status = FiberStatus::Yielded;
+                            break;
+                        }
                     }
                     Opcode::JUMP_IF => {
                         if stack.pop_bool() {
@@ -771,110 +1924,116 @@ impl Fiber {
                     Opcode::RANGE => {
                         let offset = inst.imm();
                         let len = stack.len();
-                        let t = stack.get_with_type(len - 2, inst.t0());
                         let mut mark = *stack.get_with_type(len - 1, ValueType::Int).as_int();
                         if mark < 0 {
-                            mark = range_slot;
-                            range_slot += 1;
-                            assert!(range_slot < 16);
-                            match mark {
-                                0 => range_init!(
-                                    objs, t, mr0, mp0, mi0, lr0, lp0, li0, sr0, sp0, si0
-                                ),
-                                1 => range_init!(
-                                    objs, t, mr1, mp1, mi1, lr1, lp1, li1, sr1, sp1, si1
-                                ),
-                                2 => range_init!(
-                                    objs, t, mr2, mp2, mi2, lr2, lp2, li2, sr2, sp2, si2
-                                ),
-                                3 => range_init!(
-                                    objs, t, mr3, mp3, mi3, lr3, lp3, li3, sr3, sp3, si3
-                                ),
-                                4 => range_init!(
-                                    objs, t, mr4, mp4, mi4, lr4, lp4, li4, sr4, sp4, si4
-                                ),
-                                5 => range_init!(
-                                    objs, t, mr5, mp5, mi5, lr5, lp5, li5, sr5, sp5, si5
-                                ),
-                                6 => range_init!(
-                                    objs, t, mr6, mp6, mi6, lr6, lp6, li6, sr6, sp6, si6
-                                ),
-                                7 => range_init!(
-                                    objs, t, mr7, mp7, mi7, lr7, lp7, li7, sr7, sp7, si7
-                                ),
-                                8 => range_init!(
-                                    objs, t, mr8, mp8, mi8, lr8, lp8, li8, sr8, sp8, si8
-                                ),
-                                9 => range_init!(
-                                    objs, t, mr9, mp9, mi9, lr9, lp9, li9, sr9, sp9, si9
-                                ),
-                                10 => range_init!(
-                                    objs, t, mr10, mp10, mi10, lr10, lp10, li10, sr10, sp10, si10
-                                ),
-                                11 => range_init!(
-                                    objs, t, mr11, mp11, mi11, lr11, lp11, li11, sr11, sp11, si11
-                                ),
-                                12 => range_init!(
-                                    objs, t, mr12, mp12, mi12, lr12, lp12, li12, sr12, sp12, si12
-                                ),
-                                13 => range_init!(
-                                    objs, t, mr13, mp13, mi13, lr13, lp13, li13, sr13, sp13, si13
-                                ),
-                                14 => range_init!(
-                                    objs, t, mr14, mp14, mi14, lr14, lp14, li14, sr14, sp14, si14
-                                ),
-                                15 => range_init!(
-                                    objs, t, mr15, mp15, mi15, lr15, lp15, li15, sr15, sp15, si15
-                                ),
-                                _ => unreachable!(),
-                            }
+                            let t = stack.get_with_type(len - 2, inst.t0());
+                            let state = Some((len - 1, RangeIter::new(&t)));
+                            mark = match self.ranges.iter().position(|x| x.is_none()) {
+                                Some(i) => {
+                                    self.ranges[i] = state;
+                                    i as isize
+                                }
+                                None => {
+                                    self.ranges.push(state);
+                                    self.ranges.len() as isize - 1
+                                }
+                            };
                             stack.set(len - 1, GosValue::Int(mark));
                         }
-                        let end = match mark {
-                            0 => range_body!(t, stack, inst, mp0, mi0, lp0, li0, sp0, si0),
-                            1 => range_body!(t, stack, inst, mp1, mi1, lp1, li1, sp1, si1),
-                            2 => range_body!(t, stack, inst, mp2, mi2, lp2, li2, sp2, si2),
-                            3 => range_body!(t, stack, inst, mp3, mi3, lp3, li3, sp3, si3),
-                            4 => range_body!(t, stack, inst, mp4, mi4, lp4, li4, sp4, si4),
-                            5 => range_body!(t, stack, inst, mp5, mi5, lp5, li5, sp5, si5),
-                            6 => range_body!(t, stack, inst, mp6, mi6, lp6, li6, sp6, si6),
-                            7 => range_body!(t, stack, inst, mp7, mi7, lp7, li7, sp7, si7),
-                            8 => range_body!(t, stack, inst, mp8, mi8, lp8, li8, sp8, si8),
-                            9 => range_body!(t, stack, inst, mp9, mi9, lp9, li9, sp9, si9),
-                            10 => range_body!(t, stack, inst, mp10, mi10, lp10, li10, sp10, si10),
-                            11 => range_body!(t, stack, inst, mp11, mi11, lp11, li11, sp11, si11),
-                            12 => range_body!(t, stack, inst, mp12, mi12, lp12, li12, sp12, si12),
-                            13 => range_body!(t, stack, inst, mp13, mi13, lp13, li13, sp13, si13),
-                            14 => range_body!(t, stack, inst, mp14, mi14, lp14, li14, sp14, si14),
-                            15 => range_body!(t, stack, inst, mp15, mi15, lp15, li15, sp15, si15),
-                            _ => unreachable!(),
-                        };
-                        if end {
-                            frame.pc = Stack::offset(frame.pc, offset);
-                            range_slot -= 1;
+                        let (_, iter) = self.ranges[mark as usize].as_mut().unwrap();
+                        match iter.next(stack) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                self.ranges[mark as usize] = None;
+                                stack.pop_discard();
+                                stack.pop_discard();
+                                frame.pc = Stack::offset(frame.pc, offset);
+                            }
+                            Err(waker) => {
+                                self.waker = Some(waker);
+                                frame.pc -= 1;
+                                status = FiberStatus::Blocked;
+                                break;
+                            }
+                        }
+                    }
+
+                    Opcode::SELECT => {
+                        let begin = frame.pc;
+                        let end = begin + inst.imm() as usize;
+                        let has_default = inst.t2_as_index() > 0;
+                        let rand = xorshift(&mut self.rand_state) as usize;
+                        match vm_util::select(
+                            stack,
+                            &code[begin..end],
+                            has_default,
+                            rand,
+                            &mut objs.gcobjs,
+                        ) {
+                            // the default case comes right after the descriptions
+                            Ok(SelectResult::Default) => frame.pc = end,
+                            Ok(SelectResult::Case(i)) => {
+                                frame.pc = Stack::offset(end, code[begin + i].imm())
+                            }
+                            Ok(SelectResult::Sent(i, waker)) => {
+                                // go on with the case once a receiver takes the value
+                                frame.pc = Stack::offset(end, code[begin + i].imm());
+                                self.waker = Some(waker);
+                                status = FiberStatus::Blocked;
+                                break;
+                            }
+                            Ok(SelectResult::Blocked(waker)) => {
+                                self.waker = Some(waker);
+                                frame.pc -= 1;
+                                status = FiberStatus::Blocked;
+                                break;
+                            }
+                            Err(e) => {
+                                panic = Some(runtime_error!(e, objs));
+                                break;
+                            }
                         }
                     }
 
                     Opcode::TYPE_ASSERT => {
-                        let val = match stack.pop_interface().0.borrow().underlying() {
-                            IfaceUnderlying::Gos(v, _) => v.copy_semantic(&mut objs.gcobjs),
-                            _ => GosValue::new_nil(),
+                        let val = stack.pop_with_type(ValueType::Interface);
+                        let (static_meta, under) = vm_util::iface_parts(&val);
+                        let dyn_meta = match &under {
+                            IfaceUnderlying::Gos(v, _) => v.get_meta(objs, stack),
+                            _ => GosMetadata::Untyped,
                         };
-                        let meta = GosValue::Metadata(val.get_meta(objs, stack));
-                        stack.push(val);
-                        let ok = &consts[inst.imm() as usize] == &meta;
+                        let target = *consts[inst.imm() as usize].as_meta();
+                        let result = vm_util::type_assert(
+                            static_meta,
+                            under,
+                            dyn_meta,
+                            target,
+                            &objs.metas,
+                            &objs.functions,
+                            &mut objs.gcobjs,
+                        );
                         let do_try = inst.t2_as_index() > 0;
-                        if !do_try {
-                            if !ok {
-                                // todo go_panic
-                                unimplemented!()
+                        match result {
+                            Ok(v) => {
+                                stack.push(v);
+                                if do_try {
+                                    stack.push_bool(true);
+                                }
+                            }
+                            Err(e) => {
+                                if do_try {
+                                    stack.push(zero_val!(target, objs));
+                                    stack.push_bool(false);
+                                } else {
+                                    panic = Some(runtime_error!(e, objs));
+                                    break;
+                                }
                             }
-                        } else {
-                            stack.push_bool(ok);
                         }
                     }
                     Opcode::TYPE => {
-                        let val = match stack.pop_interface().0.borrow().underlying() {
+                        let val = stack.pop_with_type(ValueType::Interface);
+                        let val = match vm_util::iface_parts(&val).1 {
                             IfaceUnderlying::Gos(v, _) => v.copy_semantic(&mut objs.gcobjs),
                             _ => GosValue::new_nil(),
                         };
@@ -885,9 +2044,15 @@ impl Fiber {
                             stack.set(s_index, val);
                         }
                     }
+                    Opcode::CONVERT => {
+                        let val = stack.pop_with_type(inst.t0());
+                        let meta = *consts[inst.imm() as usize].as_meta();
+                        let result = vm_util::convert(val, meta, &objs.metas, &mut objs.gcobjs);
+                        stack.push(result);
+                    }
                     Opcode::TO_UINT32 => {
                         if let Err(e) = stack.top_to_uint(inst.t0()) {
-                            panic_msg = Some(e);
+                            panic = Some(runtime_error!(e, objs));
                             break;
                         }
                     }
@@ -928,21 +2093,44 @@ impl Fiber {
                                     drop(frame);
                                     let frame_height = self.frames.len();
                                     for (_, uv) in uvs.iter_mut() {
-                                        let r: &mut UpValueState = &mut uv.inner.borrow_mut();
-                                        if let UpValueState::Open(d) = r {
+                                        let mut shared = None;
+                                        if let UpValueState::Open(d) = &mut *uv.inner.borrow_mut() {
                                             // get frame index, and add_referred_by
-                                            for i in 1..frame_height {
+                                            let mut found = false;
+                                            for i in 1..frame_height + 1 {
                                                 let index = frame_height - i;
                                                 if self.frames[index].func() == d.func {
-                                                    d.frame = index as OpIndex;
                                                     let upframe = &mut self.frames[index];
+                                                    d.stack = stack_weak.clone();
+                                                    d.stack_base = upframe.stack_base;
                                                     upframe.add_referred_by(d.index, d.typ, uv);
-                                                    // if not found, the upvalue is already closed, nothing to do
+                                                    found = true;
                                                     break;
                                                 }
                                             }
+                                            // the var lives in a frame that has returned or that
+                                            // belongs to another fiber, the enclosing closure
+                                            // has the upvalue already
+                                            if !found {
+                                                let cur = &self.frames[frame_height - 1];
+                                                shared = objs.functions[cur.func()]
+                                                    .up_ptrs
+                                                    .iter()
+                                                    .position(|p| {
+                                                        p.is_up_value
+                                                            && p.func == d.func
+                                                            && p.index == d.index
+                                                    })
+                                                    .and_then(|i| {
+                                                        cur.local_ptrs
+                                                            .as_ref()
+                                                            .map(|x| x[i].clone())
+                                                    });
+                                            }
+                                        }
+                                        if let Some(s) = shared {
+                                            *uv = s;
                                         }
-                                        //dbg!(&desc, &upframe);
                                     }
                                     frame = self.frames.last_mut().unwrap();
                                 }
@@ -1041,6 +2229,12 @@ impl Fiber {
                                     }
                                     _ => unreachable!(),
                                 };
+                                if env.too_large(
+                                    cap.saturating_mul(mem::size_of::<RefCell<GosValue>>()),
+                                ) {
+                                    status = FiberStatus::Stopped(ErrorKind::OutOfMemory);
+                                    break;
+                                }
                                 GosValue::new_slice(
                                     len,
                                     cap,
@@ -1053,7 +2247,32 @@ impl Fiber {
                                 let default = zero_val!(v, objs);
                                 GosValue::new_map(*meta, default, &mut objs.gcobjs)
                             }
-                            MetadataType::Channel => unimplemented!(),
+                            MetadataType::Channel(_, vmeta) => {
+                                let cap = match index {
+                                    -1 => stack.pop_int(),
+                                    0 => 0,
+                                    _ => unreachable!(),
+                                };
+                                if cap < 0 {
+                                    panic = Some(runtime_error!(
+                                        "makechan: size out of range".to_string(),
+                                        objs
+                                    ));
+                                    break;
+                                }
+                                let size = (cap as usize)
+                                    .saturating_mul(mem::size_of::<RefCell<GosValue>>());
+                                if env.too_large(size) {
+                                    status = FiberStatus::Stopped(ErrorKind::OutOfMemory);
+                                    break;
+                                }
+                                GosValue::new_channel(
+                                    *meta,
+                                    cap as usize,
+                                    zero_val!(vmeta, objs),
+                                    &mut objs.gcobjs,
+                                )
+                            }
                             _ => unreachable!(),
                         };
                         stack.pop_discard();
@@ -1069,28 +2288,144 @@ impl Fiber {
                         GosValue::Str(sval) => {
                             stack.push(GosValue::Int(sval.len() as isize));
                         }
+                        GosValue::Channel(chan) => {
+                            stack.push(GosValue::Int(chan.0.borrow().len() as isize));
+                        }
+                        GosValue::Nil(_) => stack.push(GosValue::Int(0)), // nil channel
                         _ => unreachable!(),
                     },
                     Opcode::CAP => match &stack.pop_with_type(inst.t0()) {
                         GosValue::Slice(slice) => {
                             stack.push(GosValue::Int(slice.0.cap() as isize));
                         }
+                        GosValue::Channel(chan) => {
+                            stack.push(GosValue::Int(chan.0.borrow().cap() as isize));
+                        }
+                        GosValue::Nil(_) => stack.push(GosValue::Int(0)), // nil channel
                         _ => unreachable!(),
                     },
                     Opcode::APPEND => {
-                        let index = Stack::offset(stack.len(), inst.imm());
-                        let a = stack.get_with_type(index - 2, ValueType::Slice);
-                        let vala = a.as_slice();
-                        stack.pack_variadic(index, vala.0.meta, inst.t1(), &mut objs.gcobjs);
-                        let b = stack.pop_with_type(ValueType::Slice);
-                        let valb = b.as_slice();
-                        vala.0
-                            .borrow_data_mut()
-                            .append(&mut valb.0.borrow_data().clone());
+                        // the flag is set if there is an ellipsis, otherwise the
+                        // params from 'imm' on are the values to append
+                        let mut vals: GosVec = if inst.t2_as_index() > 0 {
+                            match stack.pop_with_type(ValueType::Slice) {
+                                GosValue::Slice(sl) => {
+                                    sl.0.borrow_data()[sl.0.begin()..sl.0.end()].to_vec()
+                                }
+                                GosValue::Str(s) => s
+                                    .as_str()
+                                    .bytes()
+                                    .map(|b| RefCell::new(GosValue::Uint8(b)))
+                                    .collect(),
+                                _ => unreachable!(),
+                            }
+                        } else {
+                            let index = Stack::offset(stack.len(), inst.imm());
+                            stack
+                                .split_off_with_type(index, inst.t1())
+                                .into_iter()
+                                .map(|x| RefCell::new(x))
+                                .collect()
+                        };
+                        objs.gcobjs.alloc_values(vals.len());
+                        let a = stack.pop_with_type(ValueType::Slice);
+                        // the result shares the underlying vec if the capacity is enough
+                        let mut result = a.as_slice().0.slice(0, -1, -1);
+                        result.append(&mut vals);
+                        stack.push(GosValue::Slice(Rc::new((result, Cell::new(0)))));
+                    }
+                    Opcode::CLOSE => {
+                        let chan = stack.pop_with_type(inst.t0());
+                        if let Err(e) = vm_util::close(&chan) {
+                            panic = Some(runtime_error!(e, objs));
+                            break;
+                        }
+                    }
+                    Opcode::PANIC => {
+                        // the parameter is casted to interface{} already
+                        panic = Some((stack.pop_with_type(ValueType::Interface), ErrorKind::Panic));
+                        break;
+                    }
+                    Opcode::RECOVER => {
+                        // only the deferred call run by the panic can recover it
+                        let height = self.frames.len();
+                        frame = self.frames.last_mut().unwrap();
+                        let val = match self.panics.last_mut() {
+                            Some(p)
+                                if !p.recovered
+                                    && !self.interrupted
+                                    && p.defer_frame == height - 1 =>
+                            {
+                                p.recovered = true;
+                                p.val.clone()
+                            }
+                            _ => GosValue::Nil(objs.metadata.empty_iface),
+                        };
+                        stack.push(val);
+                    }
+                    Opcode::COPY => {
+                        let src = stack.pop_with_type(inst.t1());
+                        let dst = stack.pop_with_type(inst.t0());
+                        let count = vm_util::copy(&dst, &src, &mut objs.gcobjs);
+                        stack.push_int(count as isize);
+                    }
+                    Opcode::DELETE => {
+                        let key = stack.pop_with_type(inst.t1());
+                        let map = stack.pop_with_type(inst.t0());
+                        vm_util::delete(&map, &key);
+                    }
+                    Opcode::COMPLEX => {
+                        let (i, r) = (
+                            stack.pop_with_type(inst.t0()),
+                            stack.pop_with_type(inst.t0()),
+                        );
+                        let val = match (r, i) {
+                            (GosValue::Float32(r), GosValue::Float32(i)) => {
+                                GosValue::Complex64(r, i)
+                            }
+                            (GosValue::Float64(r), GosValue::Float64(i)) => {
+                                GosValue::Complex128(Box::new((r, i)))
+                            }
+                            _ => unreachable!(),
+                        };
+                        stack.push(val);
+                    }
+                    Opcode::REAL | Opcode::IMAG => {
+                        let (r, i) = match stack.pop_with_type(inst.t0()) {
+                            GosValue::Complex64(r, i) => {
+                                (GosValue::Float32(r), GosValue::Float32(i))
+                            }
+                            GosValue::Complex128(c) => {
+                                (GosValue::Float64(c.0), GosValue::Float64(c.1))
+                            }
+                            _ => unreachable!(),
+                        };
+                        stack.push(if inst_op == Opcode::REAL { r } else { i });
+                    }
+                    Opcode::PRINT | Opcode::PRINTLN => {
+                        // like in Go, the built-in print functions write to stderr
+                        let meta = stack.pop_with_type(ValueType::Metadata);
+                        let ptypes = &objs.metas[meta.as_meta().as_non_ptr()]
+                            .as_signature()
+                            .params_type;
+                        let strs: Vec<String> = stack
+                            .pop_with_type_n(ptypes)
+                            .iter()
+                            .map(|x| x.to_string())
+                            .collect();
+                        if inst_op == Opcode::PRINTLN {
+                            eprintln!("{}", strs.join(" "));
+                        } else {
+                            eprint!("{}", strs.concat());
+                        }
                     }
                     Opcode::ASSERT => {
                         if !stack.pop_bool() {
-                            panic_msg = Some("Opcode::ASSERT: not true!".to_string());
+                            panic = Some(runtime_error!(
+                                "Opcode::ASSERT: not true!".to_string(),
+                                objs,
+                                ErrorKind::Assert
+                            ));
                             break;
                         }
                     }
@@ -1105,7 +2440,22 @@ impl Fiber {
                             .as_signature()
                             .params_type[2..];
                         let params = stack.pop_with_type_n(ptypes);
-                        let v = match ffi_factory.create_by_name(name_str, params) {
+                        // the interface type and the name
+                        stack.pop_discard_n(2);
+                        let ret = without_stack!({
+                            let mut ctx = FfiCtx {
+                                results: &[],
+                                objs: objs,
+                                env: env,
+                                spawned: spawned,
+                            };
+                            env.ffi_factory.create_by_name(&mut ctx, name_str, params)
+                        });
+                        // the constructor may have called back into the VM
+                        func = &objs.functions[frame.func()];
+                        consts = &func.consts;
+                        code = func.code();
+                        let v = match ret {
                             Ok(v) => {
                                 let meta = itype.as_meta().get_underlying(&objs.metas).clone();
                                 let info = objs.metas[meta.as_non_ptr()]
@@ -1118,7 +2468,7 @@ impl Fiber {
                                 )
                             }
                             Err(m) => {
-                                panic_msg = Some(m);
+                                panic = Some(runtime_error!(m, objs));
                                 break;
                             }
                         };
@@ -1131,54 +2481,283 @@ impl Fiber {
                 };
                 //dbg!(inst_op, stack.len());
             }
-        }
 
-        if let Some(msg) = panic_msg {
-            // todo: debug message
-            println!("panic: {}", msg);
-            if let Some(files) = fs {
-                for frame in self.frames.iter().rev() {
-                    let func = &objs.functions[frame.func()];
-                    if let Some(p) = func.pos()[frame.pc - 1] {
-                        println!("{}", files.position(p));
-                    } else {
-                        println!("<no debug info available>");
-                    }
-                }
-            }
-            // a hack to make the test case fail
-            if msg.starts_with("Opcode::ASSERT") {
-                panic!("ASSERT");
+            if panic.is_none() && !unwinding {
+                break;
             }
         }
 
-        stack.clear_rc_garbage();
-        gc::gc(&mut objs.gcobjs);
+        env.executed.set(env.executed.get() + total_inst as u64);
+        objs.gcobjs.alloc(total_inst * INST_ALLOC);
+        status
     }
 }
 
-pub struct GosVM {
-    fibers: Vec<Rc<RefCell<Fiber>>>,
-    current_fiber: Option<Rc<RefCell<Fiber>>>,
+pub struct GosVM<'a> {
+    // the fibers of the run going on, or of the last run
+    sched: Option<Scheduler>,
+    // the function run by step, None if no stepped run is going on
+    stepping: Option<FunctionKey>,
     code: ByteCode,
+    ffi: &'a FfiFactory,
+    fs: Option<FileSet>,
+    inited: bool,
+    limits: Limits,
+    executed: Cell<u64>,
+    interrupt: Arc<AtomicBool>,
+    stop: Cell<Option<ErrorKind>>,
 }
 
-impl GosVM {
-    pub fn new(bc: ByteCode) -> GosVM {
-        let mut vm = GosVM {
-            fibers: Vec::new(),
-            current_fiber: None,
+/// InterruptHandle stops the VM it's got from when it's running, from any
+/// thread. The goroutine running is unwound like in a panic that cannot be
+/// recovered, the deferred calls are run.
+#[derive(Clone, Debug)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// interrupt stops the run of the VM going on, the run returns an error
+    /// of the kind Interrupted. It does nothing if the VM is not running.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<'a> GosVM<'a> {
+    pub fn new(bc: ByteCode, ffi: &'a FfiFactory, fs: Option<FileSet>) -> GosVM<'a> {
+        GosVM {
+            sched: None,
+            stepping: None,
             code: bc,
+            ffi: ffi,
+            fs: fs,
+            inited: false,
+            limits: Limits::default(),
+            executed: Cell::new(0),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            stop: Cell::new(None),
+        }
+    }
+
+    /// set_gc_threshold sets the number of tracked objects that triggers a
+    /// collection of the reference cycles, the threshold grows with the number
+    /// of the objects that survive a collection
+    pub fn set_gc_threshold(&mut self, threshold: usize) {
+        self.code.objects.gcobjs.set_threshold(threshold);
+    }
+
+    /// set_limits sets the limits of the runs after it, the count of the
+    /// instructions run is reset to zero
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.executed.set(0);
+    }
+
+    /// executed returns the number of instructions run since the VM is created
+    /// or set_limits is called
+    pub fn executed(&self) -> u64 {
+        self.executed.get()
+    }
+
+    /// interrupt_handle returns a handle for stopping the VM from other threads
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            flag: self.interrupt.clone(),
+        }
+    }
+
+    /// mem_stats measures the objects held by the VM, the unreachable cycles
+    /// not yet collected are counted too
+    pub fn mem_stats(&self) -> MemStats {
+        let fibers = self.sched.as_ref().map_or(&[][..], |s| &s.fibers);
+        mem_stats(fibers, &self.code.objects)
+    }
+
+    /// init runs the initializers of all the packages, only the first call does
+    /// anything. It's called by run and call, so calling it is optional.
+    pub fn init(&mut self) -> Result<(), RuntimeError> {
+        if self.inited {
+            return Ok(());
+        }
+        self.inited = true;
+        let cls = GosValue::new_closure(self.code.init, &self.code.objects.functions);
+        self.run_closure(&cls, vec![]).map(|_| ())
+    }
+
+    /// run calls the main function of the main package.
+    /// The main function has no results, so the values returned are always empty.
+    pub fn run(&mut self) -> Result<Vec<GosValue>, RuntimeError> {
+        self.init()?;
+        match self.code.entry {
+            Some(entry) => {
+                let cls = GosValue::new_closure(entry, &self.code.objects.functions);
+                self.run_closure(&cls, vec![])
+            }
+            None => Err(RuntimeError::new(
+                ErrorKind::Runtime,
+                "function main is undeclared in the main package".to_string(),
+            )),
+        }
+    }
+
+    /// call calls the function 'func' of the package named 'pkg' with 'args',
+    /// and returns the results of the function. Variadic arguments are passed
+    /// one by one, like in a Go call without '...'. The arguments must have the
+    /// types of the parameters, or be assignable to their interface types.
+    pub fn call(
+        &mut self,
+        pkg: &str,
+        func: &str,
+        args: Vec<GosValue>,
+    ) -> Result<Vec<GosValue>, RuntimeError> {
+        self.init()?;
+        let objs = &self.code.objects;
+        let cls = self
+            .code
+            .packages
+            .iter()
+            .map(|k| &objs.packages[*k])
+            .find(|p| p.name() == pkg)
+            .and_then(|p| p.get_member_index(func).map(|i| p.member(*i)))
+            .filter(|v| match v {
+                GosValue::Closure(c) => c.0.borrow().func.is_some(),
+                _ => false,
+            })
+            .ok_or_else(|| {
+                RuntimeError::new(
+                    ErrorKind::Runtime,
+                    format!("{}.{} is not a function", pkg, func),
+                )
+            })?
+            .clone();
+        let key = cls.as_closure().0.borrow().func.unwrap();
+        let f = &objs.functions[key];
+        let count = f.param_count();
+        if !args_count_ok(f, args.len()) {
+            return Err(RuntimeError::new(
+                ErrorKind::Runtime,
+                format!(
+                    "wrong number of arguments in call to {}.{}: have {}, want {}",
+                    pkg,
+                    func,
+                    args.len(),
+                    count
+                ),
+            ));
+        }
+        let stack = self.sched.as_ref().unwrap().fibers[0]
+            .borrow()
+            .stack
+            .clone();
+        let args =
+            convert_args(key, args, &mut self.code.objects, &stack.borrow()).map_err(|e| {
+                RuntimeError::new(
+                    ErrorKind::Runtime,
+                    format!("{} in call to {}.{}", e, pkg, func),
+                )
+            })?;
+        self.run_closure(&cls, args)
+    }
+
+    /// step runs the main function for about 'budget' instructions, it's
+    /// checked when a goroutine yields. The next call goes on from where it
+    /// stopped, until the run finishes or panics, after which the next call
+    /// starts a new run. The packages are initialized by the first run, which
+    /// takes steps too, and Yielded is returned when it's done.
+    /// Calling run or call drops the stepped run going on.
+    pub fn step(&mut self, budget: u64) -> StepResult {
+        if self.stepping.is_none() {
+            let key = match (self.inited, self.code.entry) {
+                (false, _) => self.code.init,
+                (true, Some(entry)) => entry,
+                (true, None) => {
+                    return StepResult::Panicked(RuntimeError::new(
+                        ErrorKind::Runtime,
+                        "function main is undeclared in the main package".to_string(),
+                    ))
+                }
+            };
+            self.inited = true;
+            let cls = GosValue::new_closure(key, &self.code.objects.functions);
+            self.start(&cls, vec![]);
+            self.stepping = Some(key);
+        }
+        let result = self.with_sched(|sched, objs, env| sched.run(objs, env, Some(budget)));
+        match result {
+            StepResult::Finished(_) | StepResult::Panicked(_) => {
+                self.end_run();
+                let init = self.stepping.take() == Some(self.code.init);
+                match result {
+                    StepResult::Finished(_) if init => StepResult::Yielded,
+                    _ => result,
+                }
+            }
+            _ => result,
+        }
+    }
+
+    /// run_closure runs 'cls' in a new main fiber and schedules the fibers
+    /// until the main fiber returns or any of the fibers panics. Like in Go,
+    /// the other goroutines are not waited for when the main one is done.
+    fn run_closure(
+        &mut self,
+        cls: &GosValue,
+        args: Vec<GosValue>,
+    ) -> Result<Vec<GosValue>, RuntimeError> {
+        self.start(cls, args);
+        let result = self.with_sched(|sched, objs, env| sched.run_to_end(objs, env));
+        self.end_run();
+        result
+    }
+
+    /// start sets up a new run of 'cls', the run going on is dropped
+    fn start(&mut self, cls: &GosValue, args: Vec<GosValue>) {
+        self.stepping = None;
+        // the fibers of the last run are dropped first
+        self.sched = None;
+        self.sched = Some(Scheduler::new(cls, args, &mut self.code.objects));
+        // the interrupts made when the VM is not running are dropped
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.stop.set(None);
+    }
+
+    /// with_sched calls 'f' with the scheduler of the run going on
+    fn with_sched<R>(&mut self, f: impl FnOnce(&mut Scheduler, &mut VMObjects, Env) -> R) -> R {
+        let env = Env {
+            pkgs: &self.code.packages,
+            ifaces: &self.code.ifaces,
+            ffi_factory: self.ffi,
+            fs: self.fs.as_ref(),
+            limits: &self.limits,
+            executed: &self.executed,
+            interrupt: &self.interrupt,
+            stop: &self.stop,
+            outer_depth: 0,
+            outer_stack: 0,
+            nested: 0,
         };
-        let fb = Rc::new(RefCell::new(Fiber::new(None)));
-        vm.fibers.push(fb.clone());
-        vm.current_fiber = Some(fb);
-        vm
+        f(self.sched.as_mut().unwrap(), &mut self.code.objects, env)
+    }
+
+    /// end_run drops the fibers but the main one, whose stack is cleared
+    fn end_run(&mut self) {
+        let fibers = &mut self.sched.as_mut().unwrap().fibers;
+        fibers.truncate(1);
+        fibers[0].borrow().stack.borrow_mut().clear_rc_garbage();
     }
+}
 
-    pub fn run(&mut self, ffi: &FfiFactory, fs: Option<&FileSet>) {
-        let mut fb = self.current_fiber.as_ref().unwrap().borrow_mut();
-        fb.run(&mut self.code, ffi, fs);
+impl<'a> Drop for GosVM<'a> {
+    /// the stacks and the package members are dropped first, so that the
+    /// cycles not reachable from the values still held by the host are broken
+    fn drop(&mut self) {
+        self.sched = None;
+        let objs = &mut self.code.objects;
+        for (_, pkg) in objs.packages.iter_mut() {
+            pkg.clear_members();
+        }
+        gc::gc(&mut objs.gcobjs);
     }
 }
 
@@ -1,10 +1,15 @@
 //#![allow(dead_code)]
 //use super::opcode::OpIndex;
 use super::gc::GcObjs;
+use super::metadata::{GosMetadata, MetaCategory, MetadataType};
 use super::instruction::*;
 use super::stack::Stack;
-use super::value::{GosValue, RtValueResult, RuntimeResult, VMObjects};
-use super::objects::MetadataObjs;
+use super::objects::{
+    new_waker, FunctionObjs, IfaceUnderlying, MapObj, MetadataObjs, RecvResult, SliceObj, StringObj,
+    UnderlyingFfi, Waker,
+};
+use super::value::{GosValue, RCount, RtValueResult, RuntimeResult, VMObjects};
+use std::rc::Rc;
 
 macro_rules! read_imm_pkg {
     ($code:ident, $frame:ident, $objs:ident) => {{
@@ -21,19 +26,25 @@ macro_rules! store_local {
             $stack.store_copy_semantic($s_index, rhs_s_index, $typ, $gcos);
         } else {
             let op_ex = Instruction::index2code($rhs_index);
-            $stack.store_with_op($s_index, $stack.len() - 1, op_ex, $typ);
+            $stack.store_with_op($s_index, $stack.len() - 1, op_ex, $typ, $gcos);
         }   
     }};
 }
 
 macro_rules! load_up_value {
-    ($upvalue:expr, $self_:ident, $stack:ident, $frames:expr) => {{
+    ($upvalue:expr, $stack:ident, $stack_ptr:expr) => {{
         let uv: &UpValueState = &$upvalue.inner.borrow();
         match &uv {
             UpValueState::Open(desc) => {
-                let upframe = &$frames[desc.frame as usize];
-                let index = Stack::offset(upframe.stack_base, desc.index);
-                $stack.get_with_type(index, desc.typ)
+                let index = Stack::offset(desc.stack_base, desc.index);
+                if desc.stack.as_ptr() == $stack_ptr {
+                    $stack.get_with_type(index, desc.typ)
+                } else {
+                    // the var lives on the stack of another fiber
+                    let other = desc.stack.upgrade().unwrap();
+                    let val = other.borrow().get_with_type(index, desc.typ);
+                    val
+                }
             }
             UpValueState::Closed(val) => {
                 val.clone()
@@ -43,13 +54,21 @@ macro_rules! load_up_value {
 }
 
 macro_rules! store_up_value {
-    ($upvalue:expr, $self_:ident, $stack:ident, $frames:expr, $rhs_index:ident, $typ:expr, $gcos:expr) => {{
+    ($upvalue:expr, $stack:ident, $stack_ptr:expr, $rhs_index:ident, $typ:expr, $gcos:expr) => {{
         let uv: &mut UpValueState = &mut $upvalue.inner.borrow_mut();
         match uv {
             UpValueState::Open(desc) => {
-                let upframe =&mut $frames[desc.frame as usize];
-                let index = Stack::offset(upframe.stack_base, desc.index);
-                store_local!($stack, index, $rhs_index, $typ, $gcos);
+                let index = Stack::offset(desc.stack_base, desc.index);
+                if desc.stack.as_ptr() == $stack_ptr {
+                    store_local!($stack, index, $rhs_index, $typ, $gcos);
+                } else {
+                    // the var lives on the stack of another fiber
+                    let other = desc.stack.upgrade().unwrap();
+                    let mut other_ref = other.borrow_mut();
+                    let mut val = other_ref.get_with_type(index, desc.typ);
+                    $stack.store_val(&mut val, $rhs_index, $typ, $gcos);
+                    other_ref.set(index, val);
+                }
             }
             UpValueState::Closed(v) => {
                 $stack.store_val(v, $rhs_index, $typ, $gcos);
@@ -59,12 +78,12 @@ macro_rules! store_up_value {
 }
 
 macro_rules! deref_value {
-    ($pointers:expr, $self_:ident, $stack:ident, $frames:expr, $objs:expr) => {{
+    ($pointers:expr, $stack:ident, $stack_ptr:expr, $objs:expr) => {{
         match $pointers {
             GosValue::Pointer(b) => {
                 let r: &PointerObj = &b;
                 match r {
-                    PointerObj::UpVal(uv) => load_up_value!(&uv, $self_, $stack, $frames),
+                    PointerObj::UpVal(uv) => load_up_value!(&uv, $stack, $stack_ptr),
                     PointerObj::Struct(s, md) => match md {
                         GosMetadata::Untyped => GosValue::Struct(s.clone()),
                         _ => GosValue::Named(Box::new((GosValue::Struct(s.clone()), *md))),
@@ -92,127 +111,213 @@ macro_rules! deref_value {
     }};
 }
 
-macro_rules! range_vars {
-    ($m_ref:ident, $m_ptr:ident, $m_iter:ident, $l_ref:ident, $l_ptr:ident, $l_iter:ident,
-        $s_ref:ident, $s_ptr:ident, $s_iter:ident) => {
-        let mut $m_ref: Option<Rc<RefCell<GosHashMap>>> = None;
-        let mut $m_ptr: Option<*mut Ref<GosHashMap>> = None;
-        let mut $m_iter: Option<std::collections::hash_map::Iter<GosValue, RefCell<GosValue>>> = None;
-        let mut $l_ref: Option<Rc<(SliceObj, RCount)>> = None;
-        let mut $l_ptr: Option<*mut SliceRef> = None;
-        let mut $l_iter: Option<SliceEnumIter> = None;
-        let mut $s_ref: Option<Rc<StringObj>> = None;
-        let mut $s_ptr: Option<*mut Rc<StringObj>> = None;
-        let mut $s_iter: Option<StringEnumIter> = None;
-    };
+/// RangeIter keeps the state of a for...range loop. It's owned by the fiber
+/// rather than the main loop, so that the loop can be suspended when the fiber yields.
+pub enum RangeIter {
+    Map(Rc<(MapObj, RCount)>, Vec<GosValue>, usize),
+    Slice(Rc<(SliceObj, RCount)>, usize, usize),
+    Str(Rc<StringObj>, usize),
+    Channel(GosValue),
 }
 
-macro_rules! range_init {
-    ($objs:ident, $target:ident, $map_ref:ident, $map_ptr:ident, $map_iter:ident,
-        $slice_ref:ident, $slice_ptr:ident, $slice_iter:ident, 
-        $str_ref:ident, $str_ptr:ident, $str_iter:ident) => {{
-        match &$target {
+impl RangeIter {
+    pub fn new(target: &GosValue) -> RangeIter {
+        match target {
             GosValue::Map(m) => {
-                let map = m.0.clone_inner();
-                $map_ref.replace(map);
-                let r = $map_ref.as_ref().unwrap().borrow();
-                let p = Box::into_raw(Box::new(r));
-                $map_ptr = Some(p);
-                let mapref = unsafe { p.as_ref().unwrap() };
-                $map_iter = Some(mapref.iter());
-            }
-            GosValue::Slice(sl) => {
-                let slice = sl.clone();
-                $slice_ref.replace(slice);
-                let r = $slice_ref.as_ref().unwrap().0.borrow();
-                let p = Box::into_raw(Box::new(r));
-                $slice_ptr = Some(p);
-                let sliceref = unsafe { p.as_ref().unwrap() };
-                $slice_iter = Some(sliceref.iter().enumerate());
-            }
-            GosValue::Str(s) => {
-                let string = s.clone();
-                $str_ref.replace(string);
-                let r = $str_ref.as_ref().unwrap().clone();
-                let p = Box::into_raw(Box::new(r));
-                $str_ptr = Some(p);
-                let strref = unsafe { p.as_ref().unwrap() };
-                $str_iter = Some(strref.iter().enumerate());
+                let keys = if m.0.is_nil() {
+                    vec![]
+                } else {
+                    m.0.borrow_data().keys().cloned().collect()
+                };
+                RangeIter::Map(m.clone(), keys, 0)
             }
+            GosValue::Slice(sl) => RangeIter::Slice(sl.clone(), 0, sl.0.len()),
+            GosValue::Str(s) => RangeIter::Str(s.clone(), 0),
+            GosValue::Channel(_) | GosValue::Nil(_) => RangeIter::Channel(target.clone()),
             _ => unreachable!(),
         }
-    }};
-}
+    }
 
-macro_rules! range_body {
-    ($target:expr, $stack:ident, $inst:ident,
-        $map_ptr:ident, $map_iter:ident,
-        $slice_ptr:ident, $slice_iter:ident,
-        $str_ptr:ident, $str_iter:ident) => {{
-        match &$target {
-            GosValue::Map(_) => {
-                let v = $map_iter.as_mut().unwrap().next();
-                if let Some((k, v)) = v {
-                    $stack.push(k.clone());
-                    $stack.push(v.clone().into_inner());
-                    false
-                } else {
-                    $map_iter.take();
-                    // release the pointer
-                    if let Some(p) = $map_ptr {
-                        unsafe {
-                            drop(Box::<Ref<GosHashMap>>::from_raw(p));
-                        }
-                        $map_ptr = None
+    /// pushes the next key and value on to the stack, returns false when the
+    /// iteration is over. Err(waker) means the channel is empty for now
+    pub fn next(&mut self, stack: &mut Stack) -> Result<bool, Waker> {
+        let more = match self {
+            RangeIter::Map(m, keys, cur) => {
+                while *cur < keys.len() {
+                    let k = &keys[*cur];
+                    *cur += 1;
+                    // skip the entries removed during the iteration
+                    if let Some(v) = m.0.try_get(k) {
+                        stack.push(k.clone());
+                        stack.push(v);
+                        return Ok(true);
                     }
-                    $stack.pop_discard();
-                    $stack.pop_discard();
-                    true
                 }
+                false
             }
-            GosValue::Slice(_) => {
-                let v = $slice_iter.as_mut().unwrap().next();
-                if let Some((k, v)) = v {
-                    $stack.push_int(k as isize);
-                    $stack.push(v.clone().into_inner());
-                    false
-                } else {
-                    $slice_iter.take();
-                    // release the pointer
-                    if let Some(p) = $slice_ptr {
-                        unsafe {
-                            drop(Box::<SliceRef>::from_raw(p));
-                        }
-                        $slice_ptr = None
+            RangeIter::Slice(sl, cur, len) => {
+                if *cur < *len {
+                    if let Some(v) = sl.0.get(*cur) {
+                        stack.push_int(*cur as isize);
+                        stack.push(v);
+                        *cur += 1;
+                        return Ok(true);
                     }
-                    $stack.pop_discard();
-                    $stack.pop_discard();
+                }
+                false
+            }
+            RangeIter::Str(s, cur) => match s.as_str()[*cur..].chars().next() {
+                Some(c) => {
+                    stack.push_int(*cur as isize);
+                    stack.push_int(c as isize);
+                    *cur += c.len_utf8();
+                    true
+                }
+                None => false,
+            },
+            RangeIter::Channel(chan) => match recv(chan)? {
+                (v, true) => {
+                    // there is only one iteration variable, which takes the key's place
+                    stack.push(v.clone());
+                    stack.push(v);
                     true
                 }
+                (_, false) => false,
+            },
+        };
+        Ok(more)
+    }
+}
+
+/// recv returns the received value and false if the channel is closed and drained.
+/// A receiver that has to wait is registered with the returned waker, it should
+/// park and then try again. Receiving from a nil channel blocks forever
+#[inline]
+pub fn recv(chan: &GosValue) -> Result<(GosValue, bool), Waker> {
+    match chan {
+        GosValue::Channel(c) => {
+            let mut cref = c.0.borrow_mut();
+            match cref.try_recv() {
+                RecvResult::Value(v) => Ok((v, true)),
+                RecvResult::Closed(zero) => Ok((zero, false)),
+                RecvResult::WouldBlock => {
+                    let waker = new_waker();
+                    cref.add_receiver(waker.clone());
+                    Err(waker)
+                }
             }
-            GosValue::Str(_) => {
-                let v = $str_iter.as_mut().unwrap().next();
-                if let Some((k, v)) = v {
-                    $stack.push_int(k as isize);
-                    $stack.push_int(v as isize);
-                    false
-                } else {
-                    $str_iter.take();
-                    // release the pointer
-                    if let Some(p) = $str_ptr {
-                        unsafe {
-                            drop(Box::<Rc<StringObj>>::from_raw(p));
+        }
+        GosValue::Nil(_) => Err(new_waker()),
+        _ => unreachable!(),
+    }
+}
+
+/// send returns a waker if the sender has to park until a receiver takes the value
+#[inline]
+pub fn send(chan: &GosValue, val: GosValue) -> Result<Option<Waker>, String> {
+    match chan {
+        GosValue::Channel(c) => c.0.borrow_mut().send(val),
+        GosValue::Nil(_) => Ok(Some(new_waker())),
+        _ => unreachable!(),
+    }
+}
+
+pub enum SelectResult {
+    Case(usize),
+    // the send case is chosen, the sender waits for the receiver like 'send'
+    Sent(usize, Waker),
+    Default,
+    Blocked(Waker),
+}
+
+/// select carries out one of the ready cases, picked with 'rand'. The operands
+/// of the cases are on the stack in the order of the cases: the channel for a
+/// receive, the channel and the value for a send. They are popped unless the
+/// select blocks, and the results of the receive chosen are pushed.
+/// A fiber that blocks is parked on all the channels with the returned waker.
+pub fn select(
+    stack: &mut Stack,
+    cases: &[Instruction],
+    has_default: bool,
+    rand: usize,
+    gcos: &mut GcObjs,
+) -> Result<SelectResult, String> {
+    let total: usize = cases
+        .iter()
+        .map(|c| if c.op() == Opcode::SEND { 2 } else { 1 })
+        .sum();
+    let mut index = stack.len() - total;
+    let mut operands = Vec::with_capacity(cases.len());
+    for c in cases.iter() {
+        operands.push(index);
+        index += if c.op() == Opcode::SEND { 2 } else { 1 };
+    }
+    let ready: Vec<usize> = cases
+        .iter()
+        .enumerate()
+        .filter(
+            |(i, c)| match stack.get_with_type(operands[*i], ValueType::Channel) {
+                GosValue::Channel(chan) => match c.op() {
+                    Opcode::SEND => chan.0.borrow().can_send(),
+                    _ => chan.0.borrow().can_recv(),
+                },
+                _ => false, // nil channels are never ready
+            },
+        )
+        .map(|(i, _)| i)
+        .collect();
+
+    if ready.len() > 0 {
+        let i = ready[rand % ready.len()];
+        let case = &cases[i];
+        let chan = stack.get_with_type(operands[i], ValueType::Channel);
+        match case.op() {
+            Opcode::SEND => {
+                let val = stack.get_with_type(operands[i] + 1, case.t0());
+                stack.pop_discard_n(total);
+                send(&chan, val.copy_semantic(gcos)).map(|w| match w {
+                    Some(waker) => SelectResult::Sent(i, waker),
+                    None => SelectResult::Case(i),
+                })
+            }
+            _ => {
+                stack.pop_discard_n(total);
+                match recv(&chan) {
+                    Ok((val, ok)) => {
+                        stack.push(val);
+                        if case.t2_as_index() > 0 {
+                            stack.push_bool(ok);
                         }
-                        $str_ptr = None
                     }
-                    $stack.pop_discard();
-                    $stack.pop_discard();
-                    true
+                    Err(_) => unreachable!(),
                 }
+                Ok(SelectResult::Case(i))
             }
-            _ => unreachable!(),
         }
-    }};
+    } else if has_default {
+        stack.pop_discard_n(total);
+        Ok(SelectResult::Default)
+    } else {
+        let waker = new_waker();
+        for (i, c) in cases.iter().enumerate() {
+            if let GosValue::Channel(chan) = stack.get_with_type(operands[i], ValueType::Channel) {
+                match c.op() {
+                    Opcode::SEND => chan.0.borrow_mut().add_send_waiter(waker.clone()),
+                    _ => chan.0.borrow_mut().add_receiver(waker.clone()),
+                }
+            }
+        }
+        Ok(SelectResult::Blocked(waker))
+    }
+}
+
+#[inline]
+pub fn close(chan: &GosValue) -> RuntimeResult {
+    match chan {
+        GosValue::Channel(c) => c.0.borrow_mut().close(),
+        GosValue::Nil(_) => Err("close of nil channel".to_string()),
+        _ => unreachable!(),
+    }
 }
 
 #[inline]
@@ -287,23 +392,20 @@ pub fn store_index(
     r_index: OpIndex,
     t: ValueType,
     gcos: &mut GcObjs,
-) {
+) -> RuntimeResult {
     match target {
-        GosValue::Array(arr) => {
-            let target_cell = &arr.0.borrow_data()[*key.as_int() as usize];
-            stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
-        }
-        GosValue::Slice(s) => {
-            let target_cell = &s.0.borrow_data()[*key.as_int() as usize];
-            stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
-        }
         GosValue::Map(map) => {
+            if map.0.is_nil() {
+                return Err("assignment to entry in nil map".to_string());
+            }
             map.0.touch_key(&key);
             let borrowed = map.0.borrow_data();
             let target_cell = borrowed.get(&key).unwrap();
             stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
+            Ok(())
         }
-        _ => unreachable!(),
+        GosValue::Nil(_) => Err("assignment to entry in nil map".to_string()),
+        _ => store_index_int(stack, target, *key.as_int() as usize, r_index, t, gcos),
     }
 }
 
@@ -316,25 +418,30 @@ pub fn store_index_int(
     t: ValueType,
     gcos: &mut GcObjs,
 ) -> RuntimeResult {
-    let err = Err("assignment to entry in nil map or slice".to_string());
+    let out_of_range = || Err(format!("index {} out of range", i as isize));
     match target {
         GosValue::Array(arr) => {
-            let target_cell = &arr.0.borrow_data()[i];
-            stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
-            Ok(())
+            let borrowed = arr.0.borrow_data();
+            match borrowed.get(i) {
+                Some(target_cell) => {
+                    stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
+                    Ok(())
+                }
+                None => out_of_range(),
+            }
         }
         GosValue::Slice(s) => {
-            if s.0.is_nil() {
-                err
+            if i >= s.0.len() {
+                out_of_range()
             } else {
-                let target_cell = &s.0.borrow_data()[i];
+                let target_cell = &s.0.borrow_data()[s.0.begin() + i];
                 stack.store_val(&mut target_cell.borrow_mut(), r_index, t, gcos);
                 Ok(())
             }
         }
         GosValue::Map(map) => {
             if map.0.is_nil() {
-                err
+                Err("assignment to entry in nil map".to_string())
             } else {
                 let key = GosValue::Int(i as isize);
                 map.0.touch_key(&key);
@@ -344,7 +451,7 @@ pub fn store_index_int(
                 Ok(())
             }
         }
-        GosValue::Nil(_) => err,
+        GosValue::Nil(_) => Err("assignment to entry in nil map or slice".to_string()),
         _ => {
             dbg!(target);
             unreachable!()
@@ -381,6 +488,51 @@ pub fn store_field(
     }
 }
 
+/// copy copies the elements of a slice or the bytes of a string to 'dst' and
+/// returns the number of elements copied. The elements are read out before
+/// writing, so that it works for overlapping slices
+pub fn copy(dst: &GosValue, src: &GosValue, gcos: &mut GcObjs) -> usize {
+    let dst = match dst {
+        GosValue::Named(n) => n.0.as_slice(),
+        _ => dst.as_slice(),
+    };
+    let src = match src {
+        GosValue::Named(n) => &n.0,
+        _ => src,
+    };
+    let vals: Vec<GosValue> = match src {
+        GosValue::Slice(s) => {
+            let count = dst.0.len().min(s.0.len());
+            s.0.borrow_data()[s.0.begin()..(s.0.begin() + count)]
+                .iter()
+                .map(|x| x.borrow().copy_semantic(gcos))
+                .collect()
+        }
+        GosValue::Str(s) => s
+            .as_str()
+            .bytes()
+            .take(dst.0.len())
+            .map(|b| GosValue::Uint8(b))
+            .collect(),
+        _ => unreachable!(),
+    };
+    let count = vals.len();
+    for (i, v) in vals.into_iter().enumerate() {
+        dst.0.set(i, v);
+    }
+    count
+}
+
+#[inline]
+pub fn delete(map: &GosValue, key: &GosValue) {
+    match map {
+        GosValue::Map(m) => m.0.delete(key),
+        GosValue::Named(n) => delete(&n.0, key),
+        GosValue::Nil(_) => {}
+        _ => unreachable!(),
+    }
+}
+
 #[inline]
 pub fn push_index_comma_ok(stack: &mut Stack, map: &GosValue, index: &GosValue) {
     let (v, b) = match map.as_map().0.try_get(index) {
@@ -390,3 +542,288 @@ pub fn push_index_comma_ok(stack: &mut Stack, map: &GosValue, index: &GosValue)
     stack.push(v);
     stack.push_bool(b);
 }
+
+/// iface_parts returns the static type and the underlying of an interface value,
+/// a nil interface is not an InterfaceObj
+pub fn iface_parts(val: &GosValue) -> (GosMetadata, IfaceUnderlying) {
+    match val {
+        GosValue::Interface(i) => {
+            let borrowed = i.0.borrow();
+            (borrowed.meta, borrowed.underlying().clone())
+        }
+        GosValue::Named(n) => {
+            let (_, under) = iface_parts(&n.0);
+            (n.1, under)
+        }
+        GosValue::Nil(m) => (*m, IfaceUnderlying::None),
+        _ => unreachable!(),
+    }
+}
+
+/// type_assert implements x.(T), 'dyn_meta' is the type of the value held by
+/// the interface. Interface targets are checked against the method set of the
+/// value, including the method signatures, and the result is then a new interface value. The error is the message
+/// of the runtime panic.
+pub fn type_assert(
+    static_meta: GosMetadata,
+    under: IfaceUnderlying,
+    dyn_meta: GosMetadata,
+    target: GosMetadata,
+    metas: &MetadataObjs,
+    functions: &FunctionObjs,
+    gcos: &mut GcObjs,
+) -> Result<GosValue, String> {
+    let target_name = target.type_name(metas);
+    let dyn_name = match &under {
+        IfaceUnderlying::None => {
+            return Err(format!(
+                "interface conversion: interface is nil, not {}",
+                target_name
+            ))
+        }
+        IfaceUnderlying::Gos(_, _) => dyn_meta.type_name(metas),
+        IfaceUnderlying::Ffi(_) => static_meta.type_name(metas),
+    };
+    let target_iface = match target {
+        GosMetadata::NonPtr(k, _) => match &metas[k] {
+            MetadataType::Named(_, u, _) => match &metas[u.as_non_ptr()] {
+                MetadataType::Interface(f) => Some((f, *u)),
+                _ => None,
+            },
+            MetadataType::Interface(f) => Some((f, target)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let (fields, iface_meta) = match target_iface {
+        Some(t) => t,
+        None => {
+            return match under {
+                IfaceUnderlying::Gos(v, _) if dyn_meta.semantic_eq(&target, metas) => {
+                    Ok(v.copy_semantic(gcos))
+                }
+                _ => Err(format!(
+                    "interface conversion: {} is {}, not {}",
+                    static_meta.type_name(metas),
+                    dyn_name,
+                    target_name
+                )),
+            };
+        }
+    };
+    let methods = fields.iface_ffi_info();
+    let missing = |name: &str| {
+        Err(format!(
+            "interface conversion: {} is not {}: missing method {}",
+            dyn_name, target_name, name
+        ))
+    };
+    let under = match under {
+        IfaceUnderlying::Gos(v, _) => {
+            let named = match dyn_meta {
+                GosMetadata::NonPtr(k, _) => Some((k, false)),
+                GosMetadata::Ptr1(k, _) => Some((k, true)),
+                _ => None,
+            }
+            .and_then(|(k, is_ptr)| match &metas[k] {
+                MetadataType::Named(m, _, _) => Some((m, is_ptr)),
+                _ => None,
+            });
+            let mut funcs = Vec::with_capacity(methods.len());
+            for (name, sig) in methods.iter() {
+                let desc = named.and_then(|(m, is_ptr)| {
+                    m.mapping
+                        .get(name)
+                        .map(|i| m.members[*i as usize].borrow())
+                        .filter(|d| is_ptr || !d.pointer_recv)
+                        .and_then(|d| d.func)
+                        .filter(|f| {
+                            let fsig = metas[functions[*f].meta.as_non_ptr()].as_signature();
+                            fsig.semantic_eq_no_recv(metas[sig.as_non_ptr()].as_signature(), metas)
+                        })
+                });
+                match desc {
+                    Some(f) => funcs.push(f),
+                    None => return missing(name),
+                }
+            }
+            IfaceUnderlying::Gos(v, Rc::new(funcs))
+        }
+        IfaceUnderlying::Ffi(ffi) => {
+            for (name, _) in methods.iter() {
+                if ffi.methods.iter().find(|(n, _)| n == name).is_none() {
+                    return missing(name);
+                }
+            }
+            IfaceUnderlying::Ffi(UnderlyingFfi::new(ffi.ffi_obj.clone(), methods))
+        }
+        IfaceUnderlying::None => unreachable!(),
+    };
+    let iface = GosValue::new_iface(iface_meta, under, gcos);
+    Ok(if iface_meta == target {
+        iface
+    } else {
+        GosValue::Named(Box::new((iface, target)))
+    })
+}
+
+macro_rules! convert_num {
+    ($v:expr, $to:expr) => {
+        match $to {
+            MetadataType::Int => GosValue::Int($v as isize),
+            MetadataType::Int8 => GosValue::Int8($v as i8),
+            MetadataType::Int16 => GosValue::Int16($v as i16),
+            MetadataType::Int32 => GosValue::Int32($v as i32),
+            MetadataType::Int64 => GosValue::Int64($v as i64),
+            MetadataType::Uint => GosValue::Uint($v as usize),
+            MetadataType::Uint8 => GosValue::Uint8($v as u8),
+            MetadataType::Uint16 => GosValue::Uint16($v as u16),
+            MetadataType::Uint32 => GosValue::Uint32($v as u32),
+            MetadataType::Uint64 => GosValue::Uint64($v as u64),
+            MetadataType::Float32 => GosValue::Float32(($v as f32).into()),
+            MetadataType::Float64 => GosValue::Float64(($v as f64).into()),
+            _ => unreachable!(),
+        }
+    };
+}
+
+macro_rules! convert_complex {
+    ($r:expr, $i:expr, $to:expr) => {
+        match $to {
+            MetadataType::Complex64 => GosValue::Complex64(($r as f32).into(), ($i as f32).into()),
+            MetadataType::Complex128 => {
+                GosValue::Complex128(Box::new((($r as f64).into(), ($i as f64).into())))
+            }
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// convert implements the non-constant conversion of 'val' to the type 'meta'.
+/// Integers are truncated with wraparound, and values of named types are
+/// unwrapped and wrapped again as needed.
+pub fn convert(
+    val: GosValue,
+    meta: GosMetadata,
+    metas: &MetadataObjs,
+    gcos: &mut GcObjs,
+) -> GosValue {
+    let umeta = meta.get_underlying(metas);
+    let val = match val {
+        GosValue::Named(n) => n.0,
+        GosValue::Nil(_) => return meta.zero_val(metas, gcos),
+        _ => val,
+    };
+    let result = match umeta {
+        GosMetadata::NonPtr(k, MetaCategory::Default) => match &metas[k] {
+            MetadataType::Str(_) => match &val {
+                GosValue::Str(_) => val,
+                GosValue::Slice(s) => {
+                    let data = &s.0.borrow_data()[s.0.begin()..s.0.end()];
+                    let mut bytes = Vec::with_capacity(data.len());
+                    let mut runes = String::new();
+                    for v in data.iter() {
+                        match &*v.borrow() {
+                            GosValue::Named(n) => push_char_or_byte(&n.0, &mut bytes, &mut runes),
+                            v => push_char_or_byte(v, &mut bytes, &mut runes),
+                        }
+                    }
+                    if runes.is_empty() {
+                        GosValue::new_str(String::from_utf8_lossy(&bytes).into_owned())
+                    } else {
+                        GosValue::new_str(runes)
+                    }
+                }
+                _ => {
+                    let i = match convert_number(&val, &MetadataType::Int64) {
+                        GosValue::Int64(i) => i,
+                        _ => unreachable!(),
+                    };
+                    GosValue::new_str(rune_to_char(i).to_string())
+                }
+            },
+            MetadataType::SliceOrArray(elem, _) => match &val {
+                GosValue::Str(s) => {
+                    let uelem = elem.get_underlying(metas);
+                    let vals: Vec<GosValue> = if uelem.get_value_type(metas) == ValueType::Uint8 {
+                        s.as_str().bytes().map(|b| GosValue::Uint8(b)).collect()
+                    } else {
+                        s.as_str().chars().map(|c| GosValue::Int32(c as i32)).collect()
+                    };
+                    let vals = if uelem == *elem {
+                        vals
+                    } else {
+                        vals.into_iter()
+                            .map(|v| GosValue::Named(Box::new((v, *elem))))
+                            .collect()
+                    };
+                    GosValue::slice_with_val(vals, umeta, gcos)
+                }
+                _ => val,
+            },
+            MetadataType::Complex64 | MetadataType::Complex128 => match &val {
+                GosValue::Complex64(r, i) => convert_complex!(r.0, i.0, &metas[k]),
+                GosValue::Complex128(c) => convert_complex!((c.0).0, (c.1).0, &metas[k]),
+                _ => unreachable!(),
+            },
+            MetadataType::Int
+            | MetadataType::Int8
+            | MetadataType::Int16
+            | MetadataType::Int32
+            | MetadataType::Int64
+            | MetadataType::Uint
+            | MetadataType::Uint8
+            | MetadataType::Uint16
+            | MetadataType::Uint32
+            | MetadataType::Uint64
+            | MetadataType::Float32
+            | MetadataType::Float64 => convert_number(&val, &metas[k]),
+            // the underlying types are identical
+            _ => val,
+        },
+        _ => val,
+    };
+    if umeta == meta {
+        result
+    } else {
+        GosValue::Named(Box::new((result, meta)))
+    }
+}
+
+/// convert_number converts an integer or a float to the numeric type 'to'
+fn convert_number(val: &GosValue, to: &MetadataType) -> GosValue {
+    match val {
+        GosValue::Int(i) => convert_num!(*i, to),
+        GosValue::Int8(i) => convert_num!(*i, to),
+        GosValue::Int16(i) => convert_num!(*i, to),
+        GosValue::Int32(i) => convert_num!(*i, to),
+        GosValue::Int64(i) => convert_num!(*i, to),
+        GosValue::Uint(i) => convert_num!(*i, to),
+        GosValue::Uint8(i) => convert_num!(*i, to),
+        GosValue::Uint16(i) => convert_num!(*i, to),
+        GosValue::Uint32(i) => convert_num!(*i, to),
+        GosValue::Uint64(i) => convert_num!(*i, to),
+        GosValue::Float32(f) => convert_num!(f.0, to),
+        GosValue::Float64(f) => convert_num!(f.0, to),
+        _ => unreachable!(),
+    }
+}
+
+/// rune_to_char returns "�" for values that are not valid unicode code points
+#[inline]
+fn rune_to_char(i: i64) -> char {
+    if i < 0 || i > u32::MAX as i64 {
+        std::char::REPLACEMENT_CHARACTER
+    } else {
+        std::char::from_u32(i as u32).unwrap_or(std::char::REPLACEMENT_CHARACTER)
+    }
+}
+
+#[inline]
+fn push_char_or_byte(val: &GosValue, bytes: &mut Vec<u8>, runes: &mut String) {
+    match val {
+        GosValue::Uint8(b) => bytes.push(*b),
+        GosValue::Int32(r) => runes.push(rune_to_char(*r as i64)),
+        _ => unreachable!(),
+    }
+}